@@ -1,5 +1,4 @@
 use regex::Regex;
-use std::fmt;
 use anyhow::{Context, Result};
 
 pub fn time_str_to_seconds(time_str: &str) -> Result<f64> {
@@ -2,7 +2,7 @@ use eframe::egui;
 use rfd::FileDialog;
 use std::fs;
 use regex::Regex;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use tokio::runtime::Runtime;
 
 mod video_cutter;
@@ -220,7 +220,7 @@ impl eframe::App for MediaCutterApp {
             });
 
             egui::ScrollArea::vertical()
-                .id_source("segments_scroll")
+                .id_salt("segments_scroll")
                 .max_height(300.0)
                 .show(ui, |ui| {
                 egui::Grid::new("segments_grid").striped(true).show(ui, |ui| {
@@ -468,24 +468,22 @@ impl eframe::App for MediaCutterApp {
                 }
             });
             
-            egui::ScrollArea::vertical().id_source("merge_list").max_height(100.0).show(ui, |ui| {
+            egui::ScrollArea::vertical().id_salt("merge_list").max_height(100.0).show(ui, |ui| {
                 for (i, path) in self.merge_inputs.iter().enumerate() {
                     ui.label(format!("{}. {}", i + 1, Path::new(path).file_name().unwrap_or_default().to_string_lossy()));
                 }
             });
             
-            if !self.merge_inputs.is_empty() {
-                if ui.button("🔗 开始合并 (Merge)").clicked() {
-                    let inputs = self.merge_inputs.clone();
-                    let output_dir = self.output_dir.clone();
-                    self.log(&format!("正在合并 {} 个视频...", inputs.len()));
-                    
-                    let output_path = format!("{}/merged_output_{}.mp4", output_dir, uuid::Uuid::new_v4());
-                    
-                    match VideoCutter::merge_videos(&inputs, &output_path) {
-                         Ok(_) => self.log(&format!("✅ 合并成功: {}", output_path)),
-                         Err(e) => self.log(&format!("❌ 合并失败: {}", e)),
-                    }
+            if !self.merge_inputs.is_empty() && ui.button("🔗 开始合并 (Merge)").clicked() {
+                let inputs = self.merge_inputs.clone();
+                let output_dir = self.output_dir.clone();
+                self.log(&format!("正在合并 {} 个视频...", inputs.len()));
+                
+                let output_path = format!("{}/merged_output_{}.mp4", output_dir, uuid::Uuid::new_v4());
+                
+                match VideoCutter::merge_videos(&inputs, &output_path) {
+                     Ok(_) => self.log(&format!("✅ 合并成功: {}", output_path)),
+                     Err(e) => self.log(&format!("❌ 合并失败: {}", e)),
                 }
             }
             
@@ -744,7 +742,7 @@ impl eframe::App for MediaCutterApp {
             
             ui.separator();
             ui.label("运行日志:");
-            egui::ScrollArea::vertical().id_source("logs_scroll").show(ui, |ui| {
+            egui::ScrollArea::vertical().id_salt("logs_scroll").show(ui, |ui| {
                 ui.monospace(&self.log);
             });
         });
@@ -0,0 +1,57 @@
+//! Times the text decoder with and without the key/value cache on CPU, using
+//! randomly initialised weights shaped like whisper-tiny.
+//!
+//!     cargo run --release -p whisper_app --example decoder_speed [tokens]
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::whisper::{model::Whisper, Config};
+use std::time::Instant;
+use whisper_app::text_decoder::{KvCache, TextDecoder};
+
+fn main() -> Result<()> {
+    let n_tokens: usize = std::env::args().nth(1).map(|n| n.parse()).transpose()?.unwrap_or(200);
+    let config = Config {
+        num_mel_bins: 80,
+        max_source_positions: 1500,
+        d_model: 384,
+        encoder_attention_heads: 6,
+        encoder_layers: 4,
+        vocab_size: 51865,
+        max_target_positions: 448,
+        decoder_attention_heads: 6,
+        decoder_layers: 4,
+        suppress_tokens: vec![],
+    };
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    Whisper::load(&vb, config.clone())?;
+    let mut decoder = TextDecoder::load(vb.pp("model.decoder"), &config)?;
+    let audio_features = Tensor::randn(0f32, 1.0, (1, 1500, 384), &device)?;
+    let tokens: Vec<u32> = (0..n_tokens as u32).map(|i| 50364 + i % 1500).collect();
+
+    let start = Instant::now();
+    for n in 1..=n_tokens {
+        let input = Tensor::new(&tokens[..n], &device)?.unsqueeze(0)?;
+        let hidden = decoder.forward(&input, &audio_features, n == 1)?;
+        decoder.final_linear(&hidden.narrow(1, n - 1, 1)?)?;
+    }
+    let uncached = start.elapsed();
+
+    let start = Instant::now();
+    let mut cache = KvCache::default();
+    for &token in &tokens {
+        let input = Tensor::new(&[token], &device)?.unsqueeze(0)?;
+        let hidden = decoder.forward_cached(&input, &audio_features, &mut cache)?;
+        decoder.final_linear(&hidden)?;
+    }
+    let cached = start.elapsed();
+
+    println!("{n_tokens} tokens");
+    println!("full sequence each step: {:>8.1} ms/token", uncached.as_secs_f64() * 1000.0 / n_tokens as f64);
+    println!("key/value cache:         {:>8.1} ms/token", cached.as_secs_f64() * 1000.0 / n_tokens as f64);
+    println!("speedup: {:.1}x", uncached.as_secs_f64() / cached.as_secs_f64());
+    Ok(())
+}
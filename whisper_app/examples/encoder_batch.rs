@@ -0,0 +1,95 @@
+//! Times the audio encoder on a synthetic multi-minute input, one 30-second
+//! window at a time and in batches, using randomly initialised weights shaped
+//! like whisper-tiny. With a model name, also times the whole engine on the
+//! same audio at both batch sizes.
+//!
+//!     cargo run --release -p whisper_app --example encoder_batch [minutes] [batch size] [model]
+
+use anyhow::Result;
+use candle_core::{DType, Device, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::whisper::{model::Whisper, Config};
+use std::time::{Duration, Instant};
+use whisper_app::audio::{mel_filters, MelExtractor, N_FRAMES, SAMPLE_RATE};
+use whisper_app::audio_encoder::AudioEncoder;
+use whisper_app::decoding::DecodingOptions;
+use whisper_app::engine_config::EngineConfig;
+use whisper_app::whisper_engine::WhisperEngine;
+
+/// Gliding tones over a little noise, so every window differs.
+fn synthetic_audio(minutes: usize) -> Vec<f32> {
+    let mut state = 0x2545_f491u32;
+    (0..minutes * 60 * SAMPLE_RATE)
+        .map(|i| {
+            let t = i as f32 / SAMPLE_RATE as f32;
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            let noise = state as f32 / u32::MAX as f32 - 0.5;
+            0.3 * (2.0 * std::f32::consts::PI * (200.0 + 20.0 * (t % 30.0)) * t).sin() + 0.02 * noise
+        })
+        .collect()
+}
+
+fn encode(encoder: &AudioEncoder, windows: &[Tensor], batch_size: usize) -> Result<Duration> {
+    let start = Instant::now();
+    for batch in windows.chunks(batch_size) {
+        encoder.forward(&Tensor::cat(batch, 0)?)?;
+    }
+    Ok(start.elapsed())
+}
+
+fn main() -> Result<()> {
+    let mut args = std::env::args().skip(1);
+    let minutes: usize = args.next().map(|n| n.parse()).transpose()?.unwrap_or(5);
+    let batch_size: usize = args.next().map(|n| n.parse()).transpose()?.unwrap_or(4);
+    let model = args.next();
+    let config = Config {
+        num_mel_bins: 80,
+        max_source_positions: 1500,
+        d_model: 384,
+        encoder_attention_heads: 6,
+        encoder_layers: 4,
+        vocab_size: 51865,
+        max_target_positions: 448,
+        decoder_attention_heads: 6,
+        decoder_layers: 4,
+        suppress_tokens: vec![],
+    };
+    let device = Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+    Whisper::load(&vb, config.clone())?;
+    let encoder = AudioEncoder::load(vb.pp("model.encoder"), &config)?;
+
+    let pcm = synthetic_audio(minutes);
+    let mel = MelExtractor::new(80, mel_filters(80))?.log_mel(&pcm);
+    let windows = (0..mel.n_frames())
+        .step_by(N_FRAMES)
+        .map(|start| mel.window(start, &device))
+        .collect::<Result<Vec<_>>>()?;
+    // Warm up the allocator and thread pool.
+    encode(&encoder, &windows[..1], 1)?;
+
+    let single = encode(&encoder, &windows, 1)?;
+    let batched = encode(&encoder, &windows, batch_size)?;
+    println!("{minutes} minutes, {} windows", windows.len());
+    println!("one window at a time:  {:>8.1} ms/window", single.as_secs_f64() * 1000.0 / windows.len() as f64);
+    println!("{batch_size:>2} windows per batch: {:>8.1} ms/window", batched.as_secs_f64() * 1000.0 / windows.len() as f64);
+    println!("speedup: {:.2}x", single.as_secs_f64() / batched.as_secs_f64());
+
+    if let Some(model) = model {
+        let options = DecodingOptions::default();
+        let mut times = Vec::new();
+        for encoder_batch_size in [1, batch_size] {
+            let engine_config = EngineConfig { encoder_batch_size, ..Default::default() };
+            let mut engine = WhisperEngine::new_with_config(&model, &engine_config)?;
+            let start = Instant::now();
+            engine.transcribe_pcm(&pcm, &options)?;
+            times.push(start.elapsed());
+            println!("{model}, encoder batch {encoder_batch_size:>2}: {:>8.1} s", start.elapsed().as_secs_f64());
+        }
+        println!("speedup: {:.2}x", times[0].as_secs_f64() / times[1].as_secs_f64());
+    }
+    Ok(())
+}
@@ -0,0 +1,50 @@
+//! Live transcription of 16 kHz mono f32 little-endian PCM read from stdin.
+//!
+//!     ffmpeg -i input -f f32le -ac 1 -ar 16000 - | cargo run --release -p whisper_app --example stream_stdin -- small
+
+use anyhow::Result;
+use std::io::Read;
+use whisper_app::decoding::DecodingOptions;
+use whisper_app::streaming::{StreamEvent, StreamOptions, StreamingTranscriber};
+use whisper_app::whisper_engine::WhisperEngine;
+
+fn print_events(events: Vec<StreamEvent>) {
+    for event in events {
+        match event {
+            StreamEvent::Final(s) => println!("[{:8.2} -> {:8.2}] {}", s.start, s.end, s.text.trim()),
+            StreamEvent::Provisional(segments) => {
+                let text: Vec<&str> = segments.iter().map(|s| s.text.trim()).collect();
+                if !text.is_empty() {
+                    eprintln!("  ... {}", text.join(" "));
+                }
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let model = std::env::args().nth(1).unwrap_or_else(|| "tiny".to_string());
+    let mut engine = WhisperEngine::new(&model)?;
+    let options = DecodingOptions::default();
+    let mut stream = StreamingTranscriber::new(StreamOptions::default());
+
+    let mut stdin = std::io::stdin().lock();
+    let mut bytes = vec![0u8; 16000]; // 250 ms
+    let mut pending = Vec::new();
+    loop {
+        let n = stdin.read(&mut bytes)?;
+        if n == 0 {
+            break;
+        }
+        pending.extend_from_slice(&bytes[..n]);
+        let whole = pending.len() / 4 * 4;
+        let chunk: Vec<f32> = pending[..whole]
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        pending.drain(..whole);
+        print_events(engine.push_stream(&mut stream, &chunk, &options)?);
+    }
+    print_events(engine.finish_stream(&mut stream, &options)?);
+    Ok(())
+}
@@ -0,0 +1,362 @@
+use anyhow::Result;
+use candle_core::{DType, IndexOp, Tensor, D};
+use tokenizers::Tokenizer;
+
+use crate::text_decoder::TextDecoder;
+
+/// Audio context positions per second (each covers two 10 ms mel frames).
+const TOKENS_PER_SECOND: f64 = 50.0;
+/// Width of the median filter smoothing the attention weights over time.
+const MEDFILT_WIDTH: usize = 7;
+/// Languages written without spaces between words; they are split per character.
+const NO_SPACE_LANGUAGES: [&str; 6] = ["zh", "ja", "th", "lo", "my", "yue"];
+const PREPEND_PUNCTUATIONS: &str = "\"'“¿([{-";
+const APPEND_PUNCTUATIONS: &str = "\"'.。,，!！?？:：”)]}、";
+
+/// One word with its timing relative to the start of the window.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WordTiming {
+    pub word: String,
+    pub tokens: Vec<u32>,
+    pub start: f64,
+    pub end: f64,
+    pub probability: f32,
+}
+
+/// Everything `find_alignment` needs to know about the token layout.
+pub struct AlignmentInput<'a> {
+    pub sot_sequence: &'a [u32],
+    pub no_timestamps_token: u32,
+    pub eot_token: u32,
+    pub text_tokens: &'a [u32],
+    /// Mel frames of real audio in the window (at most 3000).
+    pub num_frames: usize,
+    pub language: &'a str,
+}
+
+/// Align `text_tokens` with the audio by dynamic time warping over the
+/// cross-attention of the alignment heads, as reference Whisper does.
+pub fn find_alignment(
+    decoder: &mut TextDecoder,
+    tokenizer: &Tokenizer,
+    audio_features: &Tensor,
+    alignment_heads: &[(usize, usize)],
+    input: &AlignmentInput,
+) -> Result<Vec<WordTiming>> {
+    if input.text_tokens.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut tokens = input.sot_sequence.to_vec();
+    tokens.push(input.no_timestamps_token);
+    tokens.extend_from_slice(input.text_tokens);
+    tokens.push(input.eot_token);
+
+    let device = audio_features.device();
+    let x = Tensor::new(tokens.as_slice(), device)?.unsqueeze(0)?;
+    let (hidden, cross_qks) = decoder.forward_with_cross_attention(&x, audio_features, true)?;
+
+    // Probability the model gave each text token, ignoring special tokens.
+    let sot_len = input.sot_sequence.len();
+    let logits = decoder
+        .final_linear(&hidden)?
+        .i((0, sot_len..sot_len + input.text_tokens.len(), ..input.eot_token as usize))?;
+    let probs = candle_nn::ops::softmax_last_dim(&logits.to_dtype(DType::F32)?)?.to_vec2::<f32>()?;
+    let text_token_probs: Vec<f32> = input
+        .text_tokens
+        .iter()
+        .enumerate()
+        .map(|(i, &t)| probs[i].get(t as usize).copied().unwrap_or(0.0))
+        .collect();
+
+    // (head, token, frame) attention weights of the alignment heads.
+    let n_frames = (input.num_frames / 2).max(1);
+    let mut head_weights = Vec::with_capacity(alignment_heads.len());
+    for &(layer, head) in alignment_heads {
+        let Some(qk) = cross_qks.get(layer) else { continue };
+        let qk = qk.i((0, head))?.narrow(D::Minus1, 0, n_frames.min(qk.dim(D::Minus1)?))?;
+        let weights = candle_nn::ops::softmax_last_dim(&qk.to_dtype(DType::F32)?)?;
+        head_weights.push(weights.to_vec2::<f32>()?);
+    }
+    if head_weights.is_empty() {
+        anyhow::bail!("no usable alignment heads");
+    }
+
+    let n_heads = head_weights.len() as f32;
+    let n_tokens = tokens.len();
+    let n_frames = head_weights[0][0].len();
+    let mut matrix = vec![vec![0f32; n_frames]; n_tokens];
+    for weights in head_weights.iter_mut() {
+        normalize_over_tokens(weights);
+        for row in weights.iter_mut() {
+            median_filter(row, MEDFILT_WIDTH);
+        }
+        for (m_row, w_row) in matrix.iter_mut().zip(weights.iter()) {
+            for (m, w) in m_row.iter_mut().zip(w_row) {
+                *m += w / n_heads;
+            }
+        }
+    }
+
+    // Rows from <|notimestamps|> up to the last text token predict text[0..] and <|endoftext|>.
+    let cost: Vec<Vec<f32>> = matrix[sot_len..n_tokens - 1]
+        .iter()
+        .map(|row| row.iter().map(|v| -v).collect())
+        .collect();
+    let (text_indices, time_indices) = dtw(&cost);
+
+    let mut with_eot = input.text_tokens.to_vec();
+    with_eot.push(input.eot_token);
+    let (words, word_tokens) = split_to_word_tokens(tokenizer, &with_eot, input.eot_token, input.language);
+    if word_tokens.len() <= 1 {
+        return Ok(Vec::new());
+    }
+
+    let mut word_boundaries = vec![0usize];
+    for t in &word_tokens[..word_tokens.len() - 1] {
+        word_boundaries.push(word_boundaries.last().unwrap() + t.len());
+    }
+
+    let jump_times: Vec<f64> = (0..text_indices.len())
+        .filter(|&k| k == 0 || text_indices[k] != text_indices[k - 1])
+        .map(|k| time_indices[k] as f64 / TOKENS_PER_SECOND)
+        .collect();
+
+    let mut timings = Vec::with_capacity(words.len() - 1);
+    for (i, (word, tokens)) in words.into_iter().zip(word_tokens).take(word_boundaries.len() - 1).enumerate() {
+        let (from, to) = (word_boundaries[i], word_boundaries[i + 1]);
+        let start = jump_times.get(from).copied().unwrap_or(0.0);
+        let end = jump_times.get(to).copied().unwrap_or(start);
+        let probability = if to > from {
+            text_token_probs[from..to.min(text_token_probs.len())].iter().sum::<f32>() / (to - from) as f32
+        } else {
+            0.0
+        };
+        timings.push(WordTiming {
+            word,
+            tokens,
+            start,
+            end,
+            probability,
+        });
+    }
+
+    merge_punctuations(&mut timings);
+    Ok(timings)
+}
+
+/// Standardise every frame column to zero mean and unit variance across tokens.
+fn normalize_over_tokens(weights: &mut [Vec<f32>]) {
+    let n_tokens = weights.len() as f32;
+    let n_frames = weights.first().map_or(0, |r| r.len());
+    for f in 0..n_frames {
+        let mean = weights.iter().map(|r| r[f]).sum::<f32>() / n_tokens;
+        let var = weights.iter().map(|r| (r[f] - mean).powi(2)).sum::<f32>() / n_tokens;
+        let std = var.sqrt().max(1e-10);
+        for row in weights.iter_mut() {
+            row[f] = (row[f] - mean) / std;
+        }
+    }
+}
+
+/// Median filter with reflect padding, as `scipy.signal.medfilt` applied by Whisper.
+pub fn median_filter(row: &mut [f32], width: usize) {
+    let pad = width / 2;
+    if row.len() <= pad {
+        return;
+    }
+    let n = row.len() as isize;
+    let reflect = |i: isize| -> usize {
+        let i = if i < 0 { -i } else { i };
+        (if i >= n { 2 * (n - 1) - i } else { i }) as usize
+    };
+    let source = row.to_vec();
+    let mut window = vec![0f32; width];
+    for (i, out) in row.iter_mut().enumerate() {
+        for (k, w) in window.iter_mut().enumerate() {
+            *w = source[reflect(i as isize + k as isize - pad as isize)];
+        }
+        window.sort_by(|a, b| a.total_cmp(b));
+        *out = window[pad];
+    }
+}
+
+/// Minimum-cost monotonic path through `cost` (rows = tokens, columns = frames).
+/// Returns the token and frame index of every step of the path.
+pub fn dtw(cost: &[Vec<f32>]) -> (Vec<usize>, Vec<usize>) {
+    let n = cost.len();
+    let m = cost.first().map_or(0, |r| r.len());
+    if n == 0 || m == 0 {
+        return (Vec::new(), Vec::new());
+    }
+
+    let mut acc = vec![vec![f32::INFINITY; m + 1]; n + 1];
+    let mut trace = vec![vec![0u8; m + 1]; n + 1];
+    acc[0][0] = 0.0;
+    for j in 1..=m {
+        for i in 1..=n {
+            let (c0, c1, c2) = (acc[i - 1][j - 1], acc[i - 1][j], acc[i][j - 1]);
+            let (c, t) = if c0 < c1 && c0 < c2 {
+                (c0, 0)
+            } else if c1 < c0 && c1 < c2 {
+                (c1, 1)
+            } else {
+                (c2, 2)
+            };
+            acc[i][j] = cost[i - 1][j - 1] + c;
+            trace[i][j] = t;
+        }
+    }
+    for t in trace[0].iter_mut() {
+        *t = 2;
+    }
+    for row in trace.iter_mut() {
+        row[0] = 1;
+    }
+
+    let (mut i, mut j) = (n, m);
+    let mut path = Vec::new();
+    while i > 0 || j > 0 {
+        path.push((i - 1, j - 1));
+        match trace[i][j] {
+            0 => {
+                i -= 1;
+                j -= 1;
+            }
+            1 => i -= 1,
+            _ => j -= 1,
+        }
+    }
+    path.reverse();
+    path.into_iter().unzip()
+}
+
+/// Group tokens into words: on unicode characters for scripts without spaces,
+/// otherwise on leading spaces and punctuation.
+pub fn split_to_word_tokens(tokenizer: &Tokenizer, tokens: &[u32], eot_token: u32, language: &str) -> (Vec<String>, Vec<Vec<u32>>) {
+    let (subwords, subword_tokens) = split_tokens_on_unicode(tokenizer, tokens);
+    if NO_SPACE_LANGUAGES.contains(&language) {
+        return (subwords, subword_tokens);
+    }
+
+    let mut words: Vec<String> = Vec::new();
+    let mut word_tokens: Vec<Vec<u32>> = Vec::new();
+    for (subword, tokens) in subwords.into_iter().zip(subword_tokens) {
+        let special = tokens[0] >= eot_token;
+        let with_space = subword.starts_with(' ');
+        let trimmed = subword.trim();
+        let punctuation = !trimmed.is_empty() && trimmed.chars().all(|c| c.is_ascii_punctuation());
+        if special || with_space || punctuation || words.is_empty() {
+            words.push(subword);
+            word_tokens.push(tokens);
+        } else {
+            words.last_mut().unwrap().push_str(&subword);
+            word_tokens.last_mut().unwrap().extend(tokens);
+        }
+    }
+    (words, word_tokens)
+}
+
+/// Emit a piece each time the accumulated tokens decode to complete UTF-8.
+fn split_tokens_on_unicode(tokenizer: &Tokenizer, tokens: &[u32]) -> (Vec<String>, Vec<Vec<u32>>) {
+    let mut words = Vec::new();
+    let mut word_tokens = Vec::new();
+    let mut current = Vec::new();
+    for &token in tokens {
+        current.push(token);
+        let decoded = tokenizer.decode(&current, false).unwrap_or_default();
+        if !decoded.contains('\u{fffd}') {
+            words.push(decoded);
+            word_tokens.push(std::mem::take(&mut current));
+        }
+    }
+    if !current.is_empty() {
+        words.push(tokenizer.decode(&current, false).unwrap_or_default());
+        word_tokens.push(current);
+    }
+    (words, word_tokens)
+}
+
+/// Attach leading punctuation to the next word and trailing punctuation to the
+/// previous one, keeping the timing of the word they join.
+pub fn merge_punctuations(words: &mut Vec<WordTiming>) {
+    // Prepend: walk backwards so runs of opening punctuation collapse forwards.
+    let mut i = words.len().saturating_sub(1);
+    while i > 0 {
+        let previous = &words[i - 1];
+        let trimmed = previous.word.trim();
+        if previous.word.starts_with(' ') && !trimmed.is_empty() && trimmed.chars().all(|c| PREPEND_PUNCTUATIONS.contains(c)) {
+            let previous = words.remove(i - 1);
+            let following = &mut words[i - 1];
+            following.word = previous.word + &following.word;
+            let mut tokens = previous.tokens;
+            tokens.append(&mut following.tokens);
+            following.tokens = tokens;
+        }
+        i -= 1;
+    }
+
+    // Append: walk forwards so closing punctuation joins the word before it.
+    let mut i = 1;
+    while i < words.len() {
+        let current = &words[i];
+        let is_punct = !current.word.is_empty() && current.word.chars().all(|c| APPEND_PUNCTUATIONS.contains(c));
+        if !words[i - 1].word.ends_with(' ') && is_punct {
+            let current = words.remove(i);
+            let previous = &mut words[i - 1];
+            previous.word.push_str(&current.word);
+            previous.tokens.extend(current.tokens);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn word(text: &str, start: f64, end: f64) -> WordTiming {
+        WordTiming {
+            word: text.to_string(),
+            tokens: vec![0],
+            start,
+            end,
+            probability: 1.0,
+        }
+    }
+
+    #[test]
+    fn test_dtw_follows_diagonal_of_low_cost() {
+        // Three tokens over six frames: token k is cheapest on frames 2k and 2k+1.
+        let cost: Vec<Vec<f32>> = (0..3)
+            .map(|k| (0..6).map(|f| if f / 2 == k { -1.0 } else { 1.0 }).collect())
+            .collect();
+        let (text, time) = dtw(&cost);
+        assert_eq!(text, vec![0, 0, 1, 1, 2, 2]);
+        assert_eq!(time, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn test_median_filter_removes_spikes() {
+        let mut row = vec![0.0, 0.0, 0.0, 9.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0];
+        median_filter(&mut row, 3);
+        assert_eq!(row, vec![0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_merge_punctuations() {
+        let mut words = vec![
+            word(" \"", 0.0, 0.1),
+            word("Hello", 0.1, 0.5),
+            word(",", 0.5, 0.6),
+            word(" world", 0.6, 1.0),
+            word(".", 1.0, 1.1),
+        ];
+        merge_punctuations(&mut words);
+        let texts: Vec<&str> = words.iter().map(|w| w.word.as_str()).collect();
+        assert_eq!(texts, vec![" \"Hello,", " world."]);
+        assert_eq!(words[0].start, 0.1);
+        assert_eq!(words[0].tokens.len(), 3);
+    }
+}
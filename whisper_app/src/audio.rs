@@ -1,7 +1,9 @@
-use anyhow::{Error, Result};
+use anyhow::Result;
 use candle_core::{Device, Tensor};
-use rustfft::{FftPlanner, num_complex::Complex};
+use rayon::prelude::*;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
 use std::f32::consts::PI;
+use std::sync::Arc;
 
 pub const SAMPLE_RATE: usize = 16000;
 pub const N_FFT: usize = 400;
@@ -9,94 +11,282 @@ pub const HOP_LENGTH: usize = 160;
 pub const CHUNK_LENGTH: usize = 30;
 pub const N_SAMPLES: usize = CHUNK_LENGTH * SAMPLE_RATE; // 480000
 pub const N_FRAMES: usize = N_SAMPLES / HOP_LENGTH; // 3000
+/// Frequency bins of one frame's spectrum.
+const N_BINS: usize = N_FFT / 2 + 1;
 
-pub fn pcm_to_mel(
-    config: &candle_transformers::models::whisper::Config,
-    pcm: &[f32],
-    mel_filters: &[f32],
-    device: &Device
-) -> Result<Tensor> {
-    let mut pcm = pcm.to_vec();
-    // 1. Pad or trim to 30 seconds
-    if pcm.len() < N_SAMPLES {
-        pcm.resize(N_SAMPLES, 0.0);
-    } else {
-        pcm.truncate(N_SAMPLES);
+/// Slaney-style mel filterbank of shape `(n_mels, N_FFT / 2 + 1)`, flattened row-major.
+/// Matches `librosa.filters.mel(sr=16000, n_fft=400, n_mels=n_mels)`, which is what
+/// Whisper ships as `mel_filters`, so checkpoints without that file still work.
+pub fn mel_filters(n_mels: usize) -> Vec<f32> {
+    // Linear below 1 kHz, logarithmic above.
+    const F_SP: f64 = 200.0 / 3.0;
+    const MIN_LOG_HZ: f64 = 1000.0;
+    const MIN_LOG_MEL: f64 = MIN_LOG_HZ / F_SP;
+    let logstep = 6.4f64.ln() / 27.0;
+    let hz_to_mel = |f: f64| {
+        if f < MIN_LOG_HZ {
+            f / F_SP
+        } else {
+            MIN_LOG_MEL + (f / MIN_LOG_HZ).ln() / logstep
+        }
+    };
+    let mel_to_hz = |m: f64| {
+        if m < MIN_LOG_MEL {
+            m * F_SP
+        } else {
+            MIN_LOG_HZ * (logstep * (m - MIN_LOG_MEL)).exp()
+        }
+    };
+
+    let n_bins = N_FFT / 2 + 1;
+    let nyquist = SAMPLE_RATE as f64 / 2.0;
+    let fft_freqs: Vec<f64> = (0..n_bins).map(|i| i as f64 * nyquist / (n_bins - 1) as f64).collect();
+    let max_mel = hz_to_mel(nyquist);
+    let mel_f: Vec<f64> = (0..n_mels + 2)
+        .map(|i| mel_to_hz(i as f64 * max_mel / (n_mels + 1) as f64))
+        .collect();
+
+    let mut weights = vec![0f32; n_mels * n_bins];
+    for m in 0..n_mels {
+        let (lo, center, hi) = (mel_f[m], mel_f[m + 1], mel_f[m + 2]);
+        // Normalise each triangle to unit area so the filters have equal energy.
+        let enorm = 2.0 / (hi - lo);
+        for (k, &f) in fft_freqs.iter().enumerate() {
+            let lower = (f - lo) / (center - lo);
+            let upper = (hi - f) / (hi - center);
+            weights[m * n_bins + k] = (lower.min(upper).max(0.0) * enorm) as f32;
+        }
+    }
+    weights
+}
+
+/// Whisper's log-mel front end: 25 ms Hann frames every 10 ms, power spectrum,
+/// mel filterbank, log10. The window and FFT plan are made once; frames are
+/// computed in parallel (on the current rayon pool).
+#[derive(Clone)]
+pub struct MelExtractor {
+    n_mels: usize,
+    /// `(n_mels, N_FFT / 2 + 1)`, row-major.
+    filters: Vec<f32>,
+    window: Vec<f32>,
+    fft: Arc<dyn Fft<f32>>,
+}
+
+impl MelExtractor {
+    /// `filters` as returned by `mel_filters` or read from `mel_filters.bytes`.
+    pub fn new(n_mels: usize, filters: Vec<f32>) -> Result<Self> {
+        if filters.len() != n_mels * N_BINS {
+            anyhow::bail!("mel filterbank has {} weights, expected {} mel bins x {}", filters.len(), n_mels, N_BINS);
+        }
+        Ok(Self {
+            n_mels,
+            filters,
+            window: (0..N_FFT).map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / N_FFT as f32).cos())).collect(),
+            fft: FftPlanner::new().plan_fft_forward(N_FFT),
+        })
+    }
+
+    pub fn n_mels(&self) -> usize {
+        self.n_mels
+    }
+
+    /// Log-mel spectrogram of audio of any length, framed like reference
+    /// `whisper.audio.log_mel_spectrogram(audio, padding=N_SAMPLES)`: frame `i`
+    /// is centred on sample `i * HOP_LENGTH` (`torch.stft(center=True)`), the
+    /// start is reflect-padded and the end is followed by the zeros Whisper
+    /// appends. There are `pcm.len() / HOP_LENGTH` content frames; the few frames
+    /// after them that still overlap the audio are kept for `LogMel::window`.
+    pub fn log_mel(&self, pcm: &[f32]) -> LogMel {
+        let n_frames = pcm.len() / HOP_LENGTH;
+        let computed = (pcm.len() + N_FFT / 2).div_ceil(HOP_LENGTH);
+        let sample = |k: isize| pcm.get(k.unsigned_abs()).copied().unwrap_or(0.0);
+        let mut data = vec![0f32; computed * self.n_mels];
+        data.par_chunks_mut(self.n_mels).enumerate().for_each_init(
+            || (vec![Complex::new(0f32, 0f32); N_FFT], vec![Complex::new(0f32, 0f32); self.fft.get_inplace_scratch_len()]),
+            |(buffer, scratch), (i, mel)| {
+                // Negative indices mirror the audio around sample 0 (reflect padding).
+                let start = (i * HOP_LENGTH) as isize - (N_FFT / 2) as isize;
+                for (j, b) in buffer.iter_mut().enumerate() {
+                    *b = Complex::new(sample(start + j as isize) * self.window[j], 0.0);
+                }
+                self.fft.process_with_scratch(buffer, scratch);
+                let power: Vec<f32> = buffer[..N_BINS].iter().map(|c| c.norm_sqr()).collect();
+                for (m, value) in mel.iter_mut().enumerate() {
+                    let filter = &self.filters[m * N_BINS..(m + 1) * N_BINS];
+                    let energy: f32 = filter.iter().zip(&power).map(|(w, p)| w * p).sum();
+                    *value = energy.max(1e-10).log10();
+                }
+            },
+        );
+        // The padding always adds silent frames, so silence bounds the maximum from below.
+        let max = data.iter().copied().fold(1e-10f32.log10(), f32::max);
+        LogMel { n_mels: self.n_mels, n_frames, max, data }
+    }
+}
+
+/// Output of `MelExtractor::log_mel`: log10 mel energies, frame-major.
+#[derive(Debug, Clone)]
+pub struct LogMel {
+    n_mels: usize,
+    n_frames: usize,
+    /// Largest value over the whole input, for the clamp in `window`.
+    max: f32,
+    /// `(frames, n_mels)`, row-major, including the frames after the content.
+    data: Vec<f32>,
+}
+
+impl LogMel {
+    /// Frames of audio content, one per `HOP_LENGTH` samples.
+    pub fn n_frames(&self) -> usize {
+        self.n_frames
+    }
+
+    /// Log10 energy of mel bin `mel` in frame `frame`.
+    pub fn get(&self, frame: usize, mel: usize) -> f32 {
+        self.data[frame * self.n_mels + mel]
     }
 
-    // 2. Hann Window
-    let window = (0..N_FFT)
-        .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / N_FFT as f32).cos()))
-        .collect::<Vec<_>>();
-
-    // 3. STFT (Short-Time Fourier Transform)
-    // We computed magnitudes: |FFT(window * frame)|^2
-    // We ignore the DC component and keep first (N_FFT / 2 + 1) bins.
-    
-    let mut planner = FftPlanner::new();
-    let fft = planner.plan_fft_forward(N_FFT);
-    
-    let mut magnitudes = Vec::with_capacity(N_FRAMES * (N_FFT / 2 + 1));
-    
-    for i in 0..N_FRAMES {
-        let start = i * HOP_LENGTH;
-        let end = start + N_FFT;
-        let mut frame = vec![Complex { re: 0.0, im: 0.0 }; N_FFT];
-        
-        for j in 0..N_FFT {
-            if start + j < pcm.len() {
-                frame[j].re = pcm[start + j] * window[j];
+    /// Encoder input for the 30 s window starting at frame `start`: `N_FRAMES`
+    /// frames (silence past the end), clamped to 8 below the maximum of the
+    /// whole input and scaled like reference Whisper, as a `(1, n_mels, N_FRAMES)` tensor.
+    pub fn window(&self, start: usize, device: &Device) -> Result<Tensor> {
+        let computed = self.data.len() / self.n_mels;
+        let frames = computed.saturating_sub(start).min(N_FRAMES);
+        let data = &self.data[start.min(computed) * self.n_mels..][..frames * self.n_mels];
+        let floor = self.max - 8.0;
+        let scale = |x: f32| (x.max(floor) + 4.0) / 4.0;
+
+        let mut mel = vec![scale(1e-10f32.log10()); self.n_mels * N_FRAMES];
+        for (f, frame) in data.chunks_exact(self.n_mels).enumerate() {
+            for (m, &x) in frame.iter().enumerate() {
+                mel[m * N_FRAMES + f] = scale(x);
             }
         }
-        
-        fft.process(&mut frame);
-        
-        // Take squared magnitude of the first (N_FFT / 2 + 1) coefficients
-        for j in 0..=(N_FFT / 2) {
-            let s = frame[j].re * frame[j].re + frame[j].im * frame[j].im;
-            magnitudes.push(s);
+        Ok(Tensor::from_vec(mel, (1, self.n_mels, N_FRAMES), device)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mel_filters_shape_and_peaks() {
+        let n_bins = N_FFT / 2 + 1;
+        for n_mels in [80, 128] {
+            let filters = mel_filters(n_mels);
+            assert_eq!(filters.len(), n_mels * n_bins);
+            assert!(filters.iter().all(|w| *w >= 0.0));
+            // Every filter picks up some bin, and the peaks move up in frequency.
+            let peaks: Vec<usize> = filters
+                .chunks(n_bins)
+                .map(|row| {
+                    assert!(row.iter().any(|w| *w > 0.0));
+                    row.iter().enumerate().max_by(|a, b| a.1.total_cmp(b.1)).unwrap().0
+                })
+                .collect();
+            assert!(peaks.windows(2).all(|w| w[0] <= w[1]));
+        }
+    }
+
+    /// A 1 kHz tone sits exactly on FFT bin 25 (40 Hz bins), so a full Hann
+    /// frame has power (A·N/4)² there, (A·N/8)² in the two neighbours and none
+    /// elsewhere. Expected values computed from that and the librosa filterbank.
+    #[test]
+    fn test_log_mel_of_a_tone_matches_reference() {
+        let extractor = MelExtractor::new(80, mel_filters(80)).unwrap();
+        let pcm: Vec<f32> = (0..N_SAMPLES).map(|i| 0.5 * (2.0 * PI * 1000.0 * i as f32 / SAMPLE_RATE as f32).sin()).collect();
+        let mel = extractor.log_mel(&pcm);
+        assert_eq!(mel.n_frames(), N_FRAMES);
+        for (m, expected) in [(24, 0.56872), (25, 1.3645), (26, 1.75861), (27, 1.13307)] {
+            assert!((mel.get(100, m) - expected).abs() < 1e-3, "mel {m}: {}", mel.get(100, m));
+        }
+
+        let window = extractor.log_mel(&pcm).window(0, &Device::Cpu).unwrap();
+        assert_eq!(window.dims(), [1, 80, N_FRAMES]);
+        let window = window.squeeze(0).unwrap().to_vec2::<f32>().unwrap();
+        for (m, expected) in [(0, -0.56035), (24, 1.14218), (25, 1.34113), (26, 1.43965), (27, 1.28327)] {
+            assert!((window[m][100] - expected).abs() < 1e-3, "mel {m}: {}", window[m][100]);
+        }
+    }
+
+    /// Reference Whisper frames with `torch.stft(center=True, pad_mode="reflect")`
+    /// after appending `N_SAMPLES` zeros. An impulse at sample 100 therefore
+    /// shows up twice in frame 0 (at 100 and mirrored at -100, where the Hann
+    /// window is 0.5): the two add up to power 1 in even bins and cancel in odd
+    /// ones. Frame 1 sees it once at offset 140 and frame 2 not at all. An
+    /// impulse on the last sample reaches into the first frame after the content.
+    #[test]
+    fn test_frames_are_centred_with_reflect_padding() {
+        let filters = mel_filters(80);
+        let extractor = MelExtractor::new(80, filters.clone()).unwrap();
+        let mut pcm = vec![0f32; SAMPLE_RATE];
+        pcm[100] = 1.0;
+        pcm[SAMPLE_RATE - 1] = 1.0;
+        let mel = extractor.log_mel(&pcm);
+        assert_eq!(mel.n_frames(), 100);
+
+        let hann = |j: usize| 0.5 * (1.0 - (2.0 * PI * j as f32 / N_FFT as f32).cos());
+        let row = |m: usize| &filters[m * N_BINS..(m + 1) * N_BINS];
+        for m in [0, 10, 40, 79] {
+            let even: f32 = row(m).iter().step_by(2).sum();
+            let all: f32 = row(m).iter().sum();
+            assert!((mel.get(0, m) - even.max(1e-10).log10()).abs() < 1e-4, "frame 0, mel {m}: {}", mel.get(0, m));
+            assert!((mel.get(1, m) - (hann(140).powi(2) * all).log10()).abs() < 1e-4, "frame 1, mel {m}: {}", mel.get(1, m));
+            assert_eq!(mel.get(2, m), -10.0);
+            assert!((mel.get(100, m) - (hann(199).powi(2) * all).log10()).abs() < 1e-4, "frame 100, mel {m}: {}", mel.get(100, m));
         }
+
+        // The frame after the content is part of the first window, not silence.
+        let window = mel.window(0, &Device::Cpu).unwrap().squeeze(0).unwrap().to_vec2::<f32>().unwrap();
+        let floor = (mel.max - 8.0 + 4.0) / 4.0;
+        assert!(window[40][100] > floor);
+        assert_eq!(window[40][102], floor);
+    }
+
+    #[test]
+    fn test_windows_of_long_and_short_audio() {
+        let extractor = MelExtractor::new(80, mel_filters(80)).unwrap();
+        let mut rng = 1u32;
+        let mut pcm: Vec<f32> = (0..N_SAMPLES * 3 / 2)
+            .map(|_| {
+                rng = rng.wrapping_mul(1664525).wrapping_add(1013904223);
+                (rng >> 8) as f32 / (1 << 24) as f32 - 0.5
+            })
+            .collect();
+        // The second window is 100 dB quieter than the first.
+        pcm[N_SAMPLES..].iter_mut().for_each(|x| *x *= 1e-5);
+        let mel = extractor.log_mel(&pcm);
+        assert_eq!(mel.n_frames(), N_FRAMES * 3 / 2);
+
+        // Like reference Whisper, the clamp uses the maximum of the whole input,
+        // so the quiet window sits on the floor instead of being stretched to its own range.
+        let second = mel.window(N_FRAMES, &Device::Cpu).unwrap().squeeze(0).unwrap().to_vec2::<f32>().unwrap();
+        let floor = (mel.max - 8.0 + 4.0) / 4.0;
+        for m in [0, 40, 79] {
+            assert_eq!(second[m][100], floor, "mel {m}");
+            assert_eq!(second[m][N_FRAMES - 1], floor, "mel {m}");
+        }
+
+        // 1.5 s of audio: 150 frames, then the floor.
+        let short = extractor.log_mel(&pcm[..SAMPLE_RATE * 3 / 2]);
+        assert_eq!(short.n_frames(), 150);
+        let window = short.window(0, &Device::Cpu).unwrap().squeeze(0).unwrap().to_vec2::<f32>().unwrap();
+        let max = window.iter().flatten().copied().fold(f32::NEG_INFINITY, f32::max);
+        assert!((window[40][152] - (max - 2.0)).abs() < 1e-5, "floor is 8 below the maximum, scaled by 1/4");
+        assert!(MelExtractor::new(80, mel_filters(128)).is_err());
+    }
+
+    #[test]
+    fn test_mel_filters_match_librosa() {
+        // Reference values from librosa.filters.mel(sr=16000, n_fft=400, n_mels=80).
+        let filters = mel_filters(80);
+        let n_bins = N_FFT / 2 + 1;
+        // Filter 0 spans 0..~74 Hz with its peak near bin 1 (40 Hz).
+        assert_eq!(filters[0], 0.0);
+        assert!((filters[1] - 0.024_862_59).abs() < 1e-6, "{}", filters[1]);
+        assert_eq!(filters[2], 0.0);
+        // The last filter ends at Nyquist.
+        assert_eq!(filters[80 * n_bins - 1], 0.0);
     }
-    
-    // 4. Mel Filterbank Application
-    // mel_filters size: (n_mels * (N_FFT / 2 + 1)) flattened row-major?
-    // Actually Whisper mel_filters.bytes are likely stored as (n_mels, n_fft_half)
-    let n_mels = config.num_mel_bins;
-    let n_fft_half = N_FFT / 2 + 1;
-    
-    let mag_tensor = Tensor::from_vec(magnitudes, (N_FRAMES, n_fft_half), device)?;
-    let mel_filters_tensor = Tensor::from_vec(mel_filters.to_vec(), (n_mels, n_fft_half), device)?;
-    
-    // Result = Mel (n_mels, n_fft_half) @ Mag.T (n_fft_half, N_FRAMES) -> (n_mels, N_FRAMES)
-    // Candle matmul: (m, k) @ (k, n) -> (m, n)
-    // We need Mag.t()
-    let mel_spec = mel_filters_tensor.matmul(&mag_tensor.t()?)?;
-    
-    // 5. Log10(max(x, 1e-10))
-    let mel_spec = mel_spec
-        .clamp(1e-10f64, f64::MAX)?
-        .log()?
-        .div(&Tensor::new(&[10.0f64.ln()], device)?)?
-        // 6. Scale: (x + 4.0) / 4.0 ? No, standard Log10 is usually (log10(x) + 8.0) / 4.0? 
-        // Wait, regular Whisper prep is log10(x) then clamp max=8?
-        // Reference Python: log_spec = torch.clamp(log_spec, min=max(log_spec.max() - 8.0, -4.0)) -> (log_spec + 4.0) / 4.0
-        // Actually Candle examples simplified this often to just log10?
-        // Let's check `candle-transformers` whisper source code logic if possible or follow standard:
-        // Standard Whisper behavior: 
-        // log_spec = torch.log10(mel_spec)
-        // log_spec = torch.maximum(log_spec, log_spec.max() - 8.0)
-        // log_spec = (log_spec + 4.0) / 4.0
-        // But often just log10 is returned if the model handles normalization?
-        // Let's apply valid normalization:
-        ;
-
-    let max_val = mel_spec.max_keepdim(0)?.max_keepdim(1)?;
-    let min_val = max_val.sub(&Tensor::new(&[8.0f64], device)?)?;
-    let mel_spec = mel_spec.broadcast_maximum(&min_val)?;
-    let mel_spec = mel_spec.add(&Tensor::new(&[4.0f64], device)?)?.div(&Tensor::new(&[4.0f64], device)?)?;
-    
-    // Add batch dimension: (1, n_mels, N_FRAMES)
-    let mel_spec = mel_spec.unsqueeze(0)?;
-    
-    Ok(mel_spec)
 }
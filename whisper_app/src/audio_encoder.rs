@@ -0,0 +1,179 @@
+use candle_core::{DType, Device, Result, Tensor};
+use candle_nn::{Conv1d, Conv1dConfig, LayerNorm, Module, VarBuilder};
+use candle_transformers::models::whisper::Config;
+use candle_transformers::quantized_var_builder;
+
+use crate::text_decoder::{Linear, MultiHeadAttention, Weights};
+
+// Weight-compatible with candle's `whisper::model::AudioEncoder`, but runs in the
+// dtype of its weights: candle's builds its positional embedding in f32, which
+// rules out f16 and bf16. Also loads GGUF checkpoints, like the decoder.
+
+#[derive(Debug, Clone)]
+struct EncoderBlock {
+    attn: MultiHeadAttention,
+    attn_ln: LayerNorm,
+    mlp_linear1: Linear,
+    mlp_linear2: Linear,
+    mlp_ln: LayerNorm,
+}
+
+impl EncoderBlock {
+    fn load(n_state: usize, n_head: usize, vb: Weights) -> Result<Self> {
+        let n_mlp = n_state * 4;
+        Ok(Self {
+            attn: MultiHeadAttention::load(n_state, n_head, vb.pp("self_attn"))?,
+            attn_ln: vb.pp("self_attn_layer_norm").layer_norm(n_state)?,
+            mlp_linear1: vb.pp("fc1").linear(n_state, n_mlp, true)?,
+            mlp_linear2: vb.pp("fc2").linear(n_mlp, n_state, true)?,
+            mlp_ln: vb.pp("final_layer_norm").layer_norm(n_state)?,
+        })
+    }
+
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        let (attn, _) = self.attn.forward_self(&self.attn_ln.forward(x)?, None, None)?;
+        let x = (x + attn)?;
+        let mlp = self
+            .mlp_linear2
+            .forward(&self.mlp_linear1.forward(&self.mlp_ln.forward(&x)?)?.gelu()?)?;
+        x + mlp
+    }
+}
+
+/// Sinusoidal positions, `(length, channels)`, computed in f32.
+fn sinusoids(length: usize, channels: usize, device: &Device) -> Result<Tensor> {
+    let log_timescale_increment = 10000f32.ln() / (channels / 2 - 1) as f32;
+    let inv_timescales: Vec<f32> = (0..channels / 2)
+        .map(|i| (i as f32 * -log_timescale_increment).exp())
+        .collect();
+    let inv_timescales = Tensor::new(inv_timescales.as_slice(), device)?.unsqueeze(0)?;
+    let positions = Tensor::arange(0, length as u32, device)?.to_dtype(DType::F32)?.unsqueeze(1)?;
+    let scaled_time = positions.broadcast_mul(&inv_timescales)?;
+    Tensor::cat(&[scaled_time.sin()?, scaled_time.cos()?], 1)
+}
+
+/// Whisper audio encoder: two convolutions, then transformer blocks over the
+/// 1500 audio positions of a window.
+#[derive(Debug, Clone)]
+pub struct AudioEncoder {
+    conv1: Conv1d,
+    conv2: Conv1d,
+    positional_embedding: Tensor,
+    blocks: Vec<EncoderBlock>,
+    ln_post: LayerNorm,
+    dtype: DType,
+}
+
+impl AudioEncoder {
+    /// Load from the `model.encoder` prefix of a Hugging Face Whisper checkpoint,
+    /// in the dtype of `vb`.
+    pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        Self::load_weights(Weights::Full(vb), cfg)
+    }
+
+    /// Load from the `model.encoder` prefix of a GGUF checkpoint.
+    pub fn load_quantized(vb: quantized_var_builder::VarBuilder, cfg: &Config) -> Result<Self> {
+        Self::load_weights(Weights::Quantized(vb), cfg)
+    }
+
+    fn load_weights(vb: Weights, cfg: &Config) -> Result<Self> {
+        let n_state = cfg.d_model;
+        let conv = |name: &str, in_channels: usize, stride: usize| -> Result<Conv1d> {
+            let weight = vb.pp(name).tensor((n_state, in_channels, 3), "weight")?;
+            let bias = vb.pp(name).tensor(n_state, "bias")?;
+            let config = Conv1dConfig { padding: 1, stride, ..Default::default() };
+            Ok(Conv1d::new(weight, Some(bias), config))
+        };
+        let blocks = (0..cfg.encoder_layers)
+            .map(|i| EncoderBlock::load(n_state, cfg.encoder_attention_heads, vb.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            conv1: conv("conv1", cfg.num_mel_bins, 1)?,
+            conv2: conv("conv2", n_state, 2)?,
+            positional_embedding: sinusoids(cfg.max_source_positions, n_state, vb.device())?.to_dtype(vb.dtype())?,
+            blocks,
+            ln_post: vb.pp("layer_norm").layer_norm(n_state)?,
+            dtype: vb.dtype(),
+        })
+    }
+
+    /// Encode a `(batch, mel bins, frames)` spectrogram of any float dtype.
+    pub fn forward(&self, mel: &Tensor) -> Result<Tensor> {
+        let x = self.conv1.forward(&mel.to_dtype(self.dtype)?)?.gelu()?;
+        let x = self.conv2.forward(&x)?.gelu()?.transpose(1, 2)?;
+        let positional_embedding = self.positional_embedding.narrow(0, 0, x.dim(1)?)?;
+        let mut x = x.broadcast_add(&positional_embedding)?;
+        for block in &self.blocks {
+            x = block.forward(&x)?;
+        }
+        self.ln_post.forward(&x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+    use candle_transformers::models::whisper::model::Whisper;
+
+    fn tiny_config() -> Config {
+        Config {
+            num_mel_bins: 8,
+            max_source_positions: 12,
+            d_model: 16,
+            encoder_attention_heads: 2,
+            encoder_layers: 2,
+            vocab_size: 40,
+            max_target_positions: 24,
+            decoder_attention_heads: 2,
+            decoder_layers: 1,
+            suppress_tokens: vec![],
+        }
+    }
+
+    #[test]
+    fn test_matches_candle_encoder_and_runs_in_half_precision() -> anyhow::Result<()> {
+        let device = Device::Cpu;
+        let cfg = tiny_config();
+        // Random weights under the Hugging Face names.
+        let varmap = VarMap::new();
+        let mut candle = Whisper::load(&VarBuilder::from_varmap(&varmap, DType::F32, &device), cfg.clone())?;
+        let mel = Tensor::randn(0f32, 1.0, (1, 8, 24), &device)?;
+        let expected = candle.encoder.forward(&mel, true)?;
+
+        let tensors = varmap.data().lock().unwrap().iter().map(|(k, v)| (k.clone(), v.as_tensor().clone())).collect();
+        let ours = AudioEncoder::load(VarBuilder::from_tensors(tensors, DType::F32, &device).pp("model.encoder"), &cfg)?;
+        let actual = ours.forward(&mel)?;
+        let diff = (actual - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 1e-5, "max difference {diff}");
+
+        // The CPU backend has f16 but no bf16 matmul.
+        let tensors = varmap.data().lock().unwrap().iter().map(|(k, v)| (k.clone(), v.as_tensor().clone())).collect();
+        let half = AudioEncoder::load(VarBuilder::from_tensors(tensors, DType::F16, &device).pp("model.encoder"), &cfg)?;
+        let actual = half.forward(&mel)?;
+        assert_eq!(actual.dtype(), DType::F16);
+        let diff = (actual.to_dtype(DType::F32)? - &expected)?.abs()?.max_all()?.to_scalar::<f32>()?;
+        assert!(diff < 0.1, "max difference {diff}");
+        Ok(())
+    }
+
+    #[test]
+    fn test_batched_windows_match_single_windows() -> anyhow::Result<()> {
+        let device = Device::Cpu;
+        let cfg = tiny_config();
+        let varmap = VarMap::new();
+        Whisper::load(&VarBuilder::from_varmap(&varmap, DType::F32, &device), cfg.clone())?;
+        let tensors = varmap.data().lock().unwrap().iter().map(|(k, v)| (k.clone(), v.as_tensor().clone())).collect();
+        let encoder = AudioEncoder::load(VarBuilder::from_tensors(tensors, DType::F32, &device).pp("model.encoder"), &cfg)?;
+
+        let mel = Tensor::randn(0f32, 1.0, (3, 8, 24), &device)?;
+        let batched = encoder.forward(&mel)?;
+        assert_eq!(batched.dims(), &[3, 12, 16]);
+        for i in 0..3 {
+            let single = encoder.forward(&mel.narrow(0, i, 1)?)?;
+            let diff = (batched.narrow(0, i, 1)? - single)?.abs()?.max_all()?.to_scalar::<f32>()?;
+            assert!(diff < 1e-5, "window {i}: max difference {diff}");
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,342 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use crate::decoding::DecodingOptions;
+use crate::export::{self, ConfidenceThresholds};
+use crate::job::{CancellationToken, Cancelled, Job, Progress};
+use crate::tracks::AudioTrack;
+use crate::transcriber::Transcriber;
+use crate::whisper_engine::{Segment, Transcription};
+
+/// Which audio tracks of each file `run_batch` transcribes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackSelection {
+    /// The file's default track.
+    Default,
+    All,
+    /// These tracks, counted among the file's audio tracks from 0.
+    Only(Vec<usize>),
+}
+
+/// Where and what `run_batch` writes for every file.
+#[derive(Debug, Clone)]
+pub struct BatchOptions {
+    pub output_dir: PathBuf,
+    /// Also write `<stem>.confidence.json` and `<stem>.ass`.
+    pub export_confidence: bool,
+    pub confidence: ConfidenceThresholds,
+    pub tracks: TrackSelection,
+    /// With several tracks, write one merged file with every cue labelled by
+    /// its track instead of `<stem>.track<N>.srt` per track.
+    pub merge_tracks: bool,
+}
+
+impl BatchOptions {
+    pub fn new(output_dir: impl Into<PathBuf>) -> Self {
+        Self {
+            output_dir: output_dir.into(),
+            export_confidence: false,
+            confidence: ConfidenceThresholds::default(),
+            tracks: TrackSelection::Default,
+            merge_tracks: false,
+        }
+    }
+}
+
+/// What `run_batch` is doing; `index` is the position in the file list.
+#[derive(Debug)]
+pub enum BatchEvent<'a> {
+    Started { index: usize, file: &'a str },
+    Progress { index: usize, progress: Progress },
+    /// `track` is `None` for the default track.
+    Transcribed { index: usize, track: Option<&'a AudioTrack>, transcription: &'a Transcription },
+    /// Sent for each output once all of the file's outputs are in place.
+    Written { index: usize, path: &'a Path },
+    /// An output could not be written; none of the file's outputs are kept.
+    WriteFailed { index: usize, path: &'a Path, error: &'a anyhow::Error },
+    /// Transcription failed; the file gets no output and the batch goes on.
+    Failed { index: usize, error: &'a anyhow::Error },
+    /// Every output of the file was written.
+    Completed { index: usize },
+    /// The batch was cancelled while the file was running; it got no output.
+    Cancelled { index: usize },
+}
+
+impl BatchEvent<'_> {
+    pub fn index(&self) -> usize {
+        match *self {
+            BatchEvent::Started { index, .. }
+            | BatchEvent::Progress { index, .. }
+            | BatchEvent::Transcribed { index, .. }
+            | BatchEvent::Written { index, .. }
+            | BatchEvent::WriteFailed { index, .. }
+            | BatchEvent::Failed { index, .. }
+            | BatchEvent::Completed { index }
+            | BatchEvent::Cancelled { index } => index,
+        }
+    }
+
+    /// Status of the file at `index()` after this event; `None` when the
+    /// event does not change it.
+    pub fn status(&self) -> Option<FileStatus> {
+        Some(match self {
+            BatchEvent::Started { .. } => FileStatus::Running(None),
+            BatchEvent::Progress { progress, .. } => FileStatus::Running(Some(*progress)),
+            BatchEvent::WriteFailed { path, error, .. } => FileStatus::Failed(format!("{}: {}", path.display(), error)),
+            BatchEvent::Failed { error, .. } => FileStatus::Failed(error.to_string()),
+            BatchEvent::Completed { .. } => FileStatus::Done,
+            BatchEvent::Cancelled { .. } => FileStatus::Queued,
+            BatchEvent::Transcribed { .. } | BatchEvent::Written { .. } => return None,
+        })
+    }
+}
+
+/// Where a file of a batch is, for status tables; follows `BatchEvent::status`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FileStatus {
+    Queued,
+    /// Being transcribed, with the progress of its current track once known.
+    Running(Option<Progress>),
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug, Default)]
+pub struct BatchSummary {
+    /// Files transcribed with all their outputs written.
+    pub completed: Vec<String>,
+    /// Files that failed to transcribe or to be written.
+    pub failed: Vec<String>,
+    /// Set when the batch stopped early; the files in progress got no output.
+    pub cancelled: bool,
+}
+
+/// What happened to one file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileOutcome {
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+/// Transcribe `files` in order, writing `<stem>.srt` to the output directory for
+/// each, plus `<stem>.words.json` with word timestamps and the confidence files
+/// when requested. Outputs are written atomically, so a cancelled or failed
+/// file leaves nothing behind. Files with several selected tracks get outputs
+/// per track or merged ones, see `BatchOptions::merge_tracks`.
+pub fn run_batch(
+    transcriber: &mut dyn Transcriber,
+    files: &[String],
+    options: &DecodingOptions,
+    batch: &BatchOptions,
+    cancel: &CancellationToken,
+    on_event: impl FnMut(BatchEvent) + Send,
+) -> BatchSummary {
+    run_batch_parallel(&mut [transcriber], files, options, batch, cancel, on_event)
+}
+
+/// `run_batch` with one file running on each of `transcribers` at a time;
+/// each takes the next file in the list as soon as it is free. Events of
+/// different files interleave, and the summary keeps the order of `files`.
+/// The first transcriber runs on the calling thread.
+pub fn run_batch_parallel(
+    transcribers: &mut [&mut dyn Transcriber],
+    files: &[String],
+    options: &DecodingOptions,
+    batch: &BatchOptions,
+    cancel: &CancellationToken,
+    on_event: impl FnMut(BatchEvent) + Send,
+) -> BatchSummary {
+    let on_event = Mutex::new(on_event);
+    let next = AtomicUsize::new(0);
+    let stopped = AtomicBool::new(false);
+    let outcomes: Mutex<Vec<Option<FileOutcome>>> = Mutex::new(vec![None; files.len()]);
+    let work = |transcriber: &mut dyn Transcriber| loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        let Some(file) = files.get(index) else {
+            break;
+        };
+        if cancel.is_cancelled() {
+            stopped.store(true, Ordering::Relaxed);
+            break;
+        }
+        let outcome = run_file(transcriber, index, file, options, batch, cancel, &on_event);
+        if outcome == FileOutcome::Cancelled {
+            stopped.store(true, Ordering::Relaxed);
+        }
+        outcomes.lock().unwrap()[index] = Some(outcome);
+    };
+
+    std::thread::scope(|scope| {
+        let (first, rest) = transcribers.split_first_mut().expect("at least one transcriber");
+        let work = &work;
+        for transcriber in rest {
+            scope.spawn(move || work(&mut **transcriber));
+        }
+        work(&mut **first);
+    });
+
+    let mut summary = BatchSummary { cancelled: stopped.into_inner(), ..Default::default() };
+    for (file, outcome) in files.iter().zip(outcomes.into_inner().unwrap()) {
+        match outcome {
+            Some(FileOutcome::Completed) => summary.completed.push(file.clone()),
+            Some(FileOutcome::Failed) => summary.failed.push(file.clone()),
+            Some(FileOutcome::Cancelled) | None => {}
+        }
+    }
+    summary
+}
+
+/// Transcribe and write one file of a batch.
+fn run_file(
+    transcriber: &mut dyn Transcriber,
+    index: usize,
+    file: &str,
+    options: &DecodingOptions,
+    batch: &BatchOptions,
+    cancel: &CancellationToken,
+    on_event: &Mutex<impl FnMut(BatchEvent) + Send>,
+) -> FileOutcome {
+    let emit = |event: BatchEvent| (on_event.lock().unwrap())(event);
+    emit(BatchEvent::Started { index, file });
+    let tracks = match select_tracks(transcriber, file, &batch.tracks) {
+        Ok(tracks) => tracks,
+        Err(error) => {
+            emit(BatchEvent::Failed { index, error: &error });
+            return FileOutcome::Failed;
+        }
+    };
+
+    let mut transcriptions = Vec::with_capacity(tracks.len());
+    for track in tracks {
+        let options = DecodingOptions { audio_track: track.as_ref().map(|t| t.index), ..options.clone() };
+        let result = {
+            let mut job = Job::new(cancel.clone()).on_progress(|progress| emit(BatchEvent::Progress { index, progress }));
+            transcriber.transcribe_with(file, &options, &mut job)
+        };
+        let transcription = match result {
+            Ok(transcription) => transcription,
+            Err(e) if e.is::<Cancelled>() => {
+                emit(BatchEvent::Cancelled { index });
+                return FileOutcome::Cancelled;
+            }
+            Err(error) => {
+                emit(BatchEvent::Failed { index, error: &error });
+                return FileOutcome::Failed;
+            }
+        };
+        emit(BatchEvent::Transcribed { index, track: track.as_ref(), transcription: &transcription });
+        transcriptions.push((track, transcription));
+    }
+
+    let outputs: Vec<(String, Transcription)> = if transcriptions.len() == 1 {
+        transcriptions.into_iter().map(|(_, t)| (String::new(), t)).collect()
+    } else if batch.merge_tracks {
+        vec![(String::new(), merge_tracks(&transcriptions))]
+    } else {
+        transcriptions
+            .into_iter()
+            .map(|(track, t)| (format!(".track{}", track.map_or(1, |t| t.index + 1)), t))
+            .collect()
+    };
+    // Either every output is written or none is, so a failed file leaves no partial set behind.
+    let mut files = Vec::new();
+    let mut failed = false;
+    let rendered = outputs.iter().flat_map(|(prefix, transcription)| render_outputs(file, prefix, transcription, options, batch));
+    for (path, contents) in rendered {
+        match contents {
+            Ok(contents) => files.push((path, contents)),
+            Err(error) => {
+                emit(BatchEvent::WriteFailed { index, path: &path, error: &error });
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        return FileOutcome::Failed;
+    }
+    if let Err((path, error)) = export::write_all_atomic(&files) {
+        emit(BatchEvent::WriteFailed { index, path: &path, error: &error });
+        return FileOutcome::Failed;
+    }
+    for (path, _) in &files {
+        emit(BatchEvent::Written { index, path });
+    }
+    emit(BatchEvent::Completed { index });
+    FileOutcome::Completed
+}
+
+/// Output path for `file` with `suffix` (e.g. `".srt"`) in `output_dir`.
+pub fn output_path(output_dir: &Path, file: &str, suffix: &str) -> PathBuf {
+    let stem = Path::new(file).file_stem().map_or_else(|| "output".into(), |s| s.to_string_lossy());
+    output_dir.join(format!("{}{}", stem, suffix))
+}
+
+/// The tracks to transcribe; `None` is the default track.
+fn select_tracks(transcriber: &dyn Transcriber, file: &str, selection: &TrackSelection) -> Result<Vec<Option<AudioTrack>>> {
+    let available = match selection {
+        TrackSelection::Default => return Ok(vec![None]),
+        _ => transcriber.audio_tracks(file)?,
+    };
+    match selection {
+        TrackSelection::Only(indices) => indices
+            .iter()
+            .map(|&i| {
+                available.get(i).cloned().map(Some).ok_or_else(|| {
+                    anyhow::anyhow!("no audio track {} (the file has {})", i + 1, available.len())
+                })
+            })
+            .collect(),
+        _ => Ok(available.into_iter().map(Some).collect()),
+    }
+}
+
+/// One transcription of several tracks, in time order, with every cue
+/// starting with its track's label, e.g. `[eng] Hello.`
+pub fn merge_tracks(transcriptions: &[(Option<AudioTrack>, Transcription)]) -> Transcription {
+    let mut detailed: Vec<Segment> = transcriptions
+        .iter()
+        .flat_map(|(track, transcription)| {
+            let label = track.as_ref().map_or_else(|| "Track 1".to_string(), AudioTrack::label);
+            transcription.detailed.iter().map(move |segment| Segment {
+                text: format!("[{}] {}", label, segment.text.trim()),
+                ..segment.clone()
+            })
+        })
+        .collect();
+    detailed.sort_by(|a, b| a.start.total_cmp(&b.start));
+    let first = &transcriptions[0].1;
+    Transcription {
+        segments: detailed.iter().map(|s| (s.start, s.end, s.text.clone())).collect(),
+        detailed,
+        language: first.language.clone(),
+        language_probability: first.language_probability,
+        flagged: transcriptions.iter().flat_map(|(_, t)| t.flagged.iter().cloned()).collect(),
+        audio_decoder: first.audio_decoder,
+    }
+}
+
+/// The output files of one transcription with their contents.
+fn render_outputs(
+    file: &str,
+    prefix: &str,
+    transcription: &Transcription,
+    options: &DecodingOptions,
+    batch: &BatchOptions,
+) -> Vec<(PathBuf, Result<String>)> {
+    let segments = &transcription.detailed;
+    let mut outputs = vec![(".srt", Ok(export::to_srt(segments)))];
+    if options.word_timestamps {
+        outputs.push((".words.json", serde_json::to_string_pretty(segments).map_err(Into::into)));
+    }
+    if batch.export_confidence {
+        outputs.push((".confidence.json", export::to_confidence_json(segments, &batch.confidence)));
+        outputs.push((".ass", Ok(export::to_ass(segments, &batch.confidence))));
+    }
+    outputs
+        .into_iter()
+        .map(|(suffix, contents)| (output_path(&batch.output_dir, file, &format!("{}{}", prefix, suffix)), contents))
+        .collect()
+}
@@ -0,0 +1,449 @@
+use anyhow::Result;
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use std::io::Write;
+
+use crate::guard::GuardOptions;
+use crate::preprocess::PreprocessOptions;
+use crate::vad::VadOptions;
+
+/// What the decoder is asked to produce.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Task {
+    /// Text in the spoken language (`<|transcribe|>`).
+    #[default]
+    Transcribe,
+    /// English text whatever the spoken language (`<|translate|>`).
+    Translate,
+}
+
+impl Task {
+    pub fn token(&self) -> &'static str {
+        match self {
+            Task::Transcribe => "<|transcribe|>",
+            Task::Translate => "<|translate|>",
+        }
+    }
+}
+
+/// Options controlling how `WhisperEngine` decodes each window.
+///
+/// Each window is first decoded at `temperatures[0]`. If the result looks like a
+/// repetition loop (compression ratio above the threshold) or the model is unsure
+/// (average log-probability below the threshold), it is decoded again at the next
+/// temperature in the schedule.
+#[derive(Debug, Clone)]
+pub struct DecodingOptions {
+    /// Language code such as `"zh"`; `None` detects it from the first window.
+    pub language: Option<String>,
+    pub task: Task,
+    /// Temperature schedule tried in order; `[0.0]` disables fallback.
+    pub temperatures: Vec<f64>,
+    /// Beam width used at temperature 0; `None` decodes greedily.
+    pub beam_size: Option<usize>,
+    /// Beam search keeps going until `beam_size * patience` hypotheses have finished.
+    pub patience: f64,
+    /// Number of independent samples drawn at non-zero temperatures.
+    pub best_of: usize,
+    /// Alpha of the Google NMT length penalty; `None` ranks by log-probability / length.
+    pub length_penalty: Option<f64>,
+    pub compression_ratio_threshold: Option<f64>,
+    pub logprob_threshold: Option<f64>,
+    /// Seed for sampling, so runs are reproducible.
+    pub seed: u64,
+    /// Align every word with the audio (one extra decoder pass per window).
+    pub word_timestamps: bool,
+    /// Decode only the speech regions found by the VAD; `None` decodes everything.
+    pub vad: Option<VadOptions>,
+    /// Context for the first window, e.g. a sentence using the right spellings.
+    pub initial_prompt: Option<String>,
+    /// Terms such as product names, given as context to every window.
+    pub hotwords: Vec<String>,
+    /// Give each window the text of the previous ones as context. Keeps names
+    /// and style consistent but can carry a repetition loop forward.
+    pub condition_on_previous_text: bool,
+    /// Windows whose `<|nospeech|>` probability is above this, and whose average
+    /// log-probability is below `logprob_threshold`, are treated as silence.
+    pub no_speech_threshold: Option<f64>,
+    /// Keep a window from starting with a blank or end-of-text.
+    pub suppress_blank: bool,
+    /// Token ids never to produce; `None` uses the model's list of non-speech
+    /// symbols (`suppress_tokens` in config.json). Special tokens are always suppressed.
+    pub suppress_tokens: Option<Vec<u32>>,
+    /// Latest allowed start of a window's first segment, in seconds.
+    pub max_initial_timestamp: Option<f64>,
+    /// Check every segment for repetition loops and implausible speech rates.
+    pub guard: Option<GuardOptions>,
+    /// Clean-up of the decoded audio file before anything else looks at it.
+    pub preprocess: PreprocessOptions,
+    /// Audio track of the file to transcribe, counted among its audio tracks
+    /// from 0 (see `tracks::list_audio_tracks`); `None` takes the default one.
+    pub audio_track: Option<usize>,
+}
+
+impl Default for DecodingOptions {
+    fn default() -> Self {
+        Self {
+            language: None,
+            task: Task::Transcribe,
+            temperatures: vec![0.0, 0.2, 0.4, 0.6, 0.8, 1.0],
+            beam_size: None,
+            patience: 1.0,
+            best_of: 5,
+            length_penalty: None,
+            compression_ratio_threshold: Some(2.4),
+            logprob_threshold: Some(-1.0),
+            seed: 299792458,
+            word_timestamps: false,
+            vad: None,
+            initial_prompt: None,
+            hotwords: Vec::new(),
+            condition_on_previous_text: true,
+            no_speech_threshold: Some(0.6),
+            suppress_blank: true,
+            suppress_tokens: None,
+            max_initial_timestamp: Some(1.0),
+            guard: Some(GuardOptions::default()),
+            preprocess: PreprocessOptions::default(),
+            audio_track: None,
+        }
+    }
+}
+
+/// Something that produces next-token logits for a growing token sequence.
+///
+/// `State` captures everything needed to continue one hypothesis, so beams and
+/// samples can branch by cloning it.
+pub trait TokenDecoder {
+    type State: Clone;
+
+    /// Feed the prompt, returning the state after it and the logits for the first sampled token.
+    fn start(&mut self, prompt: &[u32]) -> Result<(Self::State, Vec<f32>)>;
+
+    /// Append `token` to `state` and return the logits for the token after it.
+    fn step(&mut self, state: &mut Self::State, token: u32) -> Result<Vec<f32>>;
+}
+
+/// Sampled tokens for one window (without prompt and end-of-text) and their score.
+#[derive(Debug, Clone)]
+pub struct DecodeResult {
+    pub tokens: Vec<u32>,
+    /// Log-probability of each of `tokens` at temperature 1.
+    pub token_logprobs: Vec<f32>,
+    pub sum_logprob: f64,
+    pub avg_logprob: f64,
+    pub temperature: f64,
+    /// Probability of `<|nospeech|>` right after `<|startoftranscript|>`, when measured.
+    pub no_speech_prob: f32,
+}
+
+impl DecodeResult {
+    fn new(tokens: Vec<u32>, token_logprobs: Vec<f32>, sum_logprob: f64, temperature: f64) -> Self {
+        // Reference Whisper counts the end-of-text token in the average.
+        let avg_logprob = sum_logprob / (tokens.len() + 1) as f64;
+        Self {
+            tokens,
+            token_logprobs,
+            sum_logprob,
+            avg_logprob,
+            temperature,
+            no_speech_prob: 0.0,
+        }
+    }
+}
+
+/// Decode one window at `temperature`: beam search or greedy at 0, best-of-N sampling above.
+pub fn decode<D: TokenDecoder>(
+    decoder: &mut D,
+    prompt: &[u32],
+    eot_token: u32,
+    sample_len: usize,
+    temperature: f64,
+    options: &DecodingOptions,
+    rng: &mut StdRng,
+) -> Result<DecodeResult> {
+    if temperature > 0.0 {
+        let mut candidates = Vec::with_capacity(options.best_of.max(1));
+        for _ in 0..options.best_of.max(1) {
+            candidates.push(sample(decoder, prompt, eot_token, sample_len, temperature, rng)?);
+        }
+        Ok(select_best(candidates, options.length_penalty))
+    } else if let Some(beam_size) = options.beam_size.filter(|&b| b > 1) {
+        beam_search(decoder, prompt, eot_token, sample_len, beam_size, options)
+    } else {
+        greedy(decoder, prompt, eot_token, sample_len)
+    }
+}
+
+fn greedy<D: TokenDecoder>(decoder: &mut D, prompt: &[u32], eot_token: u32, sample_len: usize) -> Result<DecodeResult> {
+    let (mut state, mut logits) = decoder.start(prompt)?;
+    let mut tokens = Vec::new();
+    let mut token_logprobs = Vec::new();
+    let mut sum_logprob = 0.0;
+    for _ in 0..sample_len {
+        let logprobs = log_softmax(&logits);
+        let next_token = argmax(&logits);
+        sum_logprob += logprobs[next_token as usize] as f64;
+        if next_token == eot_token {
+            break;
+        }
+        tokens.push(next_token);
+        token_logprobs.push(logprobs[next_token as usize]);
+        logits = decoder.step(&mut state, next_token)?;
+    }
+    Ok(DecodeResult::new(tokens, token_logprobs, sum_logprob, 0.0))
+}
+
+fn sample<D: TokenDecoder>(
+    decoder: &mut D,
+    prompt: &[u32],
+    eot_token: u32,
+    sample_len: usize,
+    temperature: f64,
+    rng: &mut StdRng,
+) -> Result<DecodeResult> {
+    let (mut state, mut logits) = decoder.start(prompt)?;
+    let mut tokens = Vec::new();
+    let mut token_logprobs = Vec::new();
+    let mut sum_logprob = 0.0;
+    for _ in 0..sample_len {
+        let logprobs = log_softmax(&logits);
+        let scaled: Vec<f32> = logits.iter().map(|l| l / temperature as f32).collect();
+        let probs: Vec<f32> = log_softmax(&scaled).iter().map(|l| l.exp()).collect();
+        let next_token = match WeightedIndex::new(&probs) {
+            Ok(dist) => dist.sample(rng) as u32,
+            Err(_) => argmax(&logits),
+        };
+        sum_logprob += logprobs[next_token as usize] as f64;
+        if next_token == eot_token {
+            break;
+        }
+        tokens.push(next_token);
+        token_logprobs.push(logprobs[next_token as usize]);
+        logits = decoder.step(&mut state, next_token)?;
+    }
+    Ok(DecodeResult::new(tokens, token_logprobs, sum_logprob, temperature))
+}
+
+struct Beam<S> {
+    state: S,
+    logits: Vec<f32>,
+    tokens: Vec<u32>,
+    token_logprobs: Vec<f32>,
+    sum_logprob: f64,
+}
+
+fn beam_search<D: TokenDecoder>(
+    decoder: &mut D,
+    prompt: &[u32],
+    eot_token: u32,
+    sample_len: usize,
+    beam_size: usize,
+    options: &DecodingOptions,
+) -> Result<DecodeResult> {
+    let max_finished = ((beam_size as f64 * options.patience).round() as usize).max(1);
+    let (state, logits) = decoder.start(prompt)?;
+    let mut beams = vec![Beam {
+        state,
+        logits,
+        tokens: Vec::new(),
+        token_logprobs: Vec::new(),
+        sum_logprob: 0.0,
+    }];
+    let mut finished: Vec<DecodeResult> = Vec::new();
+
+    for _ in 0..sample_len {
+        // (beam index, token, token log-probability, cumulative log-probability)
+        let mut candidates: Vec<(usize, u32, f32, f64)> = Vec::new();
+        for (i, beam) in beams.iter().enumerate() {
+            let logprobs = log_softmax(&beam.logits);
+            for token in top_k(&logprobs, beam_size + 1) {
+                let logprob = logprobs[token as usize];
+                candidates.push((i, token, logprob, beam.sum_logprob + logprob as f64));
+            }
+        }
+        candidates.sort_by(|a, b| b.3.total_cmp(&a.3));
+
+        let mut next_beams = Vec::with_capacity(beam_size);
+        for (i, token, logprob, sum_logprob) in candidates {
+            if token == eot_token {
+                if finished.len() < max_finished {
+                    let beam = &beams[i];
+                    finished.push(DecodeResult::new(beam.tokens.clone(), beam.token_logprobs.clone(), sum_logprob, 0.0));
+                }
+                continue;
+            }
+            let mut state = beams[i].state.clone();
+            let logits = decoder.step(&mut state, token)?;
+            let mut tokens = beams[i].tokens.clone();
+            tokens.push(token);
+            let mut token_logprobs = beams[i].token_logprobs.clone();
+            token_logprobs.push(logprob);
+            next_beams.push(Beam {
+                state,
+                logits,
+                tokens,
+                token_logprobs,
+                sum_logprob,
+            });
+            if next_beams.len() == beam_size {
+                break;
+            }
+        }
+        beams = next_beams;
+        if finished.len() >= max_finished || beams.is_empty() {
+            break;
+        }
+    }
+
+    // Hypotheses that ran out of room still count when nothing finished.
+    if finished.is_empty() {
+        finished = beams
+            .into_iter()
+            .map(|b| DecodeResult::new(b.tokens, b.token_logprobs, b.sum_logprob, 0.0))
+            .collect();
+    }
+    Ok(select_best(finished, options.length_penalty))
+}
+
+/// Pick the candidate with the best length-normalised log-probability.
+fn select_best(candidates: Vec<DecodeResult>, length_penalty: Option<f64>) -> DecodeResult {
+    let score = |c: &DecodeResult| {
+        let length = c.tokens.len() as f64;
+        let penalty = match length_penalty {
+            None => length.max(1.0),
+            Some(alpha) => ((5.0 + length) / 6.0).powf(alpha),
+        };
+        c.sum_logprob / penalty
+    };
+    candidates
+        .into_iter()
+        .max_by(|a, b| score(a).total_cmp(&score(b)))
+        .expect("at least one candidate")
+}
+
+/// Ratio of UTF-8 size to zlib-compressed size; high values mean repetitive text.
+pub fn compression_ratio(text: &str) -> f64 {
+    if text.is_empty() {
+        return 0.0;
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    let _ = encoder.write_all(text.as_bytes());
+    let compressed = encoder.finish().unwrap_or_default();
+    text.len() as f64 / compressed.len().max(1) as f64
+}
+
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.iter().map(|l| l - log_sum).collect()
+}
+
+fn argmax(values: &[f32]) -> u32 {
+    values
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, _)| i as u32)
+        .unwrap_or(0)
+}
+
+fn top_k(values: &[f32], k: usize) -> Vec<u32> {
+    let mut indices: Vec<u32> = (0..values.len() as u32).collect();
+    let k = k.min(indices.len());
+    if k == 0 {
+        return indices;
+    }
+    indices.select_nth_unstable_by(k - 1, |&a, &b| values[b as usize].total_cmp(&values[a as usize]));
+    indices.truncate(k);
+    indices
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    const EOT: u32 = 0;
+
+    /// Toy model with a fixed next-token distribution per prefix.
+    struct TableDecoder {
+        table: fn(&[u32]) -> Vec<f32>,
+    }
+
+    impl TokenDecoder for TableDecoder {
+        type State = Vec<u32>;
+
+        fn start(&mut self, _prompt: &[u32]) -> Result<(Vec<u32>, Vec<f32>)> {
+            Ok((vec![], (self.table)(&[])))
+        }
+
+        fn step(&mut self, state: &mut Vec<u32>, token: u32) -> Result<Vec<f32>> {
+            state.push(token);
+            Ok((self.table)(state))
+        }
+    }
+
+    fn probs(p: [f32; 4]) -> Vec<f32> {
+        p.iter().map(|x| x.max(1e-9).ln()).collect()
+    }
+
+    /// Greedy picks token 1 first (0.6) but every continuation is poor, while
+    /// the 2 -> 3 path is far more likely overall.
+    fn garden_path(prefix: &[u32]) -> Vec<f32> {
+        match prefix {
+            [] => probs([0.0, 0.6, 0.4, 0.0]),
+            [1] => probs([0.3, 0.4, 0.0, 0.3]),
+            [2] => probs([0.0, 0.0, 0.0, 1.0]),
+            _ => probs([1.0, 0.0, 0.0, 0.0]),
+        }
+    }
+
+    fn options() -> DecodingOptions {
+        DecodingOptions::default()
+    }
+
+    #[test]
+    fn test_greedy_follows_argmax() {
+        let mut d = TableDecoder { table: garden_path };
+        let r = greedy(&mut d, &[], EOT, 10).unwrap();
+        assert_eq!(r.tokens, vec![1, 1]);
+    }
+
+    #[test]
+    fn test_beam_search_finds_more_likely_sequence() {
+        let mut d = TableDecoder { table: garden_path };
+        let opts = DecodingOptions {
+            beam_size: Some(2),
+            ..options()
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let r = decode(&mut d, &[], EOT, 10, 0.0, &opts, &mut rng).unwrap();
+        assert_eq!(r.tokens, vec![2, 3]);
+        assert!((r.sum_logprob - 0.4f64.ln()).abs() < 1e-4);
+        assert_eq!(r.token_logprobs.len(), 2);
+        assert!((r.token_logprobs[0] - 0.4f32.ln()).abs() < 1e-4 && r.token_logprobs[1].abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_sampling_is_seeded_and_respects_zero_probabilities() {
+        let mut d = TableDecoder { table: garden_path };
+        let opts = options();
+        let mut a = StdRng::seed_from_u64(7);
+        let mut b = StdRng::seed_from_u64(7);
+        let ra = decode(&mut d, &[], EOT, 10, 1.0, &opts, &mut a).unwrap();
+        let rb = decode(&mut d, &[], EOT, 10, 1.0, &opts, &mut b).unwrap();
+        assert_eq!(ra.tokens, rb.tokens);
+        assert!(ra.tokens.first().is_some_and(|t| *t == 1 || *t == 2));
+    }
+
+    #[test]
+    fn test_compression_ratio_flags_repetition() {
+        let normal = "The quick brown fox jumps over the lazy dog near the river bank.";
+        let looped = "Thank you for watching. ".repeat(20);
+        assert!(compression_ratio(normal) < 2.4);
+        assert!(compression_ratio(&looped) > 2.4);
+    }
+}
@@ -0,0 +1,133 @@
+use anyhow::Result;
+use candle_core::{DType, Device};
+
+/// Which device runs the model.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeviceChoice {
+    /// CUDA, then Metal, then the CPU, whichever is compiled in and present.
+    Auto,
+    Cpu,
+    /// CUDA GPU with this ordinal; needs the `cuda` feature.
+    Cuda(usize),
+    /// Metal GPU with this ordinal; needs the `metal` feature.
+    Metal(usize),
+}
+
+/// Precision of the full-precision (safetensors) weights and activations.
+/// GGUF models always compute in f32.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    F32,
+    F16,
+    BF16,
+}
+
+impl Precision {
+    pub const ALL: [Precision; 3] = [Precision::F32, Precision::F16, Precision::BF16];
+
+    pub fn dtype(self) -> DType {
+        match self {
+            Precision::F32 => DType::F32,
+            Precision::F16 => DType::F16,
+            Precision::BF16 => DType::BF16,
+        }
+    }
+
+    /// Whether `device` can run the model at this precision; candle's CPU
+    /// backend has no bf16 matmul.
+    pub fn is_supported_on(self, device: &Device) -> bool {
+        !(self == Precision::BF16 && device.is_cpu())
+    }
+}
+
+impl std::fmt::Display for Precision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Precision::F32 => "f32",
+            Precision::F16 => "f16",
+            Precision::BF16 => "bf16",
+        })
+    }
+}
+
+/// How `WhisperEngine` runs its model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct EngineConfig {
+    pub device: DeviceChoice,
+    /// Falls back to f32 where the device does not support it.
+    pub precision: Precision,
+    /// CPU threads for inference; `None` uses every core.
+    pub threads: Option<usize>,
+    /// 30-second windows run through the encoder at once: the window being
+    /// decoded and the ones following it every 30 seconds. When decoding resumes
+    /// at the last complete segment instead, the windows encoded ahead are
+    /// dropped, so the transcript is the same for every batch size.
+    pub encoder_batch_size: usize,
+}
+
+impl Default for EngineConfig {
+    fn default() -> Self {
+        Self {
+            device: DeviceChoice::Auto,
+            precision: Precision::F32,
+            threads: None,
+            encoder_batch_size: 1,
+        }
+    }
+}
+
+impl EngineConfig {
+    /// Open the configured device. An explicit GPU that is missing or not
+    /// compiled in is an error; `Auto` falls back to the CPU.
+    pub fn device(&self) -> Result<Device> {
+        Ok(match self.device {
+            DeviceChoice::Auto if candle_core::utils::cuda_is_available() => Device::new_cuda(0)?,
+            DeviceChoice::Auto if candle_core::utils::metal_is_available() => Device::new_metal(0)?,
+            DeviceChoice::Auto | DeviceChoice::Cpu => Device::Cpu,
+            DeviceChoice::Cuda(ordinal) => Device::new_cuda(ordinal)?,
+            DeviceChoice::Metal(ordinal) => Device::new_metal(ordinal)?,
+        })
+    }
+
+    /// The configured precision if `device` supports it, f32 otherwise.
+    pub fn precision_on(&self, device: &Device) -> Precision {
+        if self.precision.is_supported_on(device) {
+            self.precision
+        } else {
+            Precision::F32
+        }
+    }
+}
+
+/// Short name of a device for logs, e.g. `CPU` or `Metal 0`.
+pub fn device_name(device: &Device) -> String {
+    match device.location() {
+        candle_core::DeviceLocation::Cpu => "CPU".to_string(),
+        candle_core::DeviceLocation::Cuda { gpu_id } => format!("CUDA {}", gpu_id),
+        candle_core::DeviceLocation::Metal { gpu_id } => format!("Metal {}", gpu_id),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cpu_falls_back_from_bf16() {
+        let config = EngineConfig { device: DeviceChoice::Cpu, precision: Precision::BF16, threads: Some(2), encoder_batch_size: 1 };
+        let device = config.device().unwrap();
+        assert!(device.is_cpu());
+        assert_eq!(device_name(&device), "CPU");
+        assert_eq!(config.precision_on(&device), Precision::F32);
+        let f16 = EngineConfig { precision: Precision::F16, ..config };
+        assert_eq!(f16.precision_on(&device), Precision::F16);
+    }
+
+    #[test]
+    fn test_missing_gpu_is_an_error() {
+        if !candle_core::utils::cuda_is_available() {
+            let config = EngineConfig { device: DeviceChoice::Cuda(0), ..Default::default() };
+            assert!(config.device().is_err());
+        }
+    }
+}
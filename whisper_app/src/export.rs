@@ -0,0 +1,256 @@
+use anyhow::Result;
+use common::time_utils::seconds_to_time_str;
+use serde::Serialize;
+use std::path::{Path, PathBuf};
+
+use crate::whisper_engine::Segment;
+
+/// When a cue counts as uncertain and is marked in the confidence exports.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfidenceThresholds {
+    /// Below this mean token log-probability.
+    pub avg_logprob: f64,
+    /// Any text token below this probability.
+    pub token_probability: f32,
+    /// Above this no-speech probability.
+    pub no_speech_prob: f32,
+    /// Above this compression ratio.
+    pub compression_ratio: f64,
+}
+
+impl Default for ConfidenceThresholds {
+    fn default() -> Self {
+        Self {
+            avg_logprob: -0.7,
+            token_probability: 0.15,
+            no_speech_prob: 0.5,
+            compression_ratio: 2.2,
+        }
+    }
+}
+
+impl ConfidenceThresholds {
+    pub fn is_low_confidence(&self, segment: &Segment) -> bool {
+        segment.avg_logprob < self.avg_logprob
+            || segment.min_probability() < self.token_probability
+            || segment.no_speech_prob > self.no_speech_prob
+            || segment.compression_ratio > self.compression_ratio
+    }
+}
+
+/// Write `contents` to a `.part` file next to `path` and rename it into place,
+/// so `path` never holds a half-written file.
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    write_all_atomic(&[(path.to_path_buf(), contents.to_string())]).map_err(|(_, e)| e)
+}
+
+/// `write_atomic` for several files at once, all or nothing: they are renamed
+/// into place only once every `.part` file is written. Files they replace are
+/// moved aside to `.bak` first, so when one fails the others are removed and
+/// the earlier files put back. The error comes with the path that failed.
+pub fn write_all_atomic(files: &[(PathBuf, String)]) -> std::result::Result<(), (PathBuf, anyhow::Error)> {
+    let with_suffix = |path: &Path, suffix: &str| {
+        let mut name = path.as_os_str().to_owned();
+        name.push(suffix);
+        PathBuf::from(name)
+    };
+    for (i, (path, contents)) in files.iter().enumerate() {
+        if let Err(e) = std::fs::write(with_suffix(path, ".part"), contents) {
+            for (written, _) in &files[..=i] {
+                let _ = std::fs::remove_file(with_suffix(written, ".part"));
+            }
+            return Err((path.clone(), e.into()));
+        }
+    }
+
+    // Whether each file already replaced had an earlier version, now in `.bak`.
+    let mut backed_up = Vec::with_capacity(files.len());
+    for (path, _) in files {
+        let had_file = path.is_file();
+        let moved_aside = if had_file { std::fs::rename(path, with_suffix(path, ".bak")) } else { Ok(()) };
+        let replaced = moved_aside.and_then(|()| {
+            std::fs::rename(with_suffix(path, ".part"), path).inspect_err(|_| {
+                if had_file {
+                    let _ = std::fs::rename(with_suffix(path, ".bak"), path);
+                }
+            })
+        });
+        let Err(error) = replaced else {
+            backed_up.push(had_file);
+            continue;
+        };
+        for ((renamed, _), &had_file) in files.iter().zip(&backed_up) {
+            let _ = std::fs::remove_file(renamed);
+            if had_file {
+                let _ = std::fs::rename(with_suffix(renamed, ".bak"), renamed);
+            }
+        }
+        for (pending, _) in &files[backed_up.len()..] {
+            let _ = std::fs::remove_file(with_suffix(pending, ".part"));
+        }
+        return Err((path.clone(), error.into()));
+    }
+    for ((path, _), &had_file) in files.iter().zip(&backed_up) {
+        if had_file {
+            let _ = std::fs::remove_file(with_suffix(path, ".bak"));
+        }
+    }
+    Ok(())
+}
+
+pub fn to_srt(segments: &[Segment]) -> String {
+    let mut srt = String::new();
+    for (i, segment) in segments.iter().enumerate() {
+        srt.push_str(&format!(
+            "{}\n{} --> {}\n{}\n\n",
+            i + 1,
+            seconds_to_time_str(segment.start),
+            seconds_to_time_str(segment.end),
+            segment.text.trim()
+        ));
+    }
+    srt
+}
+
+#[derive(Serialize)]
+struct Cue<'a> {
+    index: usize,
+    low_confidence: bool,
+    #[serde(flatten)]
+    segment: &'a Segment,
+}
+
+/// Every cue with its scores and token probabilities, numbered like the SRT.
+pub fn to_confidence_json(segments: &[Segment], thresholds: &ConfidenceThresholds) -> Result<String> {
+    let cues: Vec<Cue> = segments
+        .iter()
+        .enumerate()
+        .map(|(i, segment)| Cue {
+            index: i + 1,
+            low_confidence: thresholds.is_low_confidence(segment),
+            segment,
+        })
+        .collect();
+    Ok(serde_json::to_string_pretty(&cues)?)
+}
+
+/// `H:MM:SS.cc`, the ASS timestamp format.
+fn ass_time(seconds: f64) -> String {
+    let cs = (seconds.max(0.0) * 100.0).round() as u64;
+    format!("{}:{:02}:{:02}.{:02}", cs / 360_000, cs / 6000 % 60, cs / 100 % 60, cs % 100)
+}
+
+/// ASS subtitles where low-confidence cues use the yellow `Uncertain` style.
+pub fn to_ass(segments: &[Segment], thresholds: &ConfidenceThresholds) -> String {
+    let mut ass = String::from(
+        "[Script Info]\n\
+         ScriptType: v4.00+\n\
+         PlayResX: 1920\n\
+         PlayResY: 1080\n\
+         \n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: Default,Arial,60,&H00FFFFFF,&H000000FF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,3,0,2,60,60,50,1\n\
+         Style: Uncertain,Arial,60,&H0000FFFF,&H000000FF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,3,0,2,60,60,50,1\n\
+         \n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+    );
+    for segment in segments {
+        let style = if thresholds.is_low_confidence(segment) { "Uncertain" } else { "Default" };
+        let text = segment.text.trim().replace('\n', "\\N").replace('{', "(").replace('}', ")");
+        ass.push_str(&format!(
+            "Dialogue: 0,{},{},{},,0,0,0,,{}\n",
+            ass_time(segment.start),
+            ass_time(segment.end),
+            style,
+            text
+        ));
+    }
+    ass
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::whisper_engine::TokenProbability;
+
+    fn segment(start: f64, end: f64, text: &str, probabilities: &[f32]) -> Segment {
+        let tokens: Vec<TokenProbability> = probabilities
+            .iter()
+            .enumerate()
+            .map(|(i, &probability)| TokenProbability { id: i as u32, text: format!("t{i}"), probability })
+            .collect();
+        Segment {
+            start,
+            end,
+            text: text.to_string(),
+            words: Vec::new(),
+            avg_logprob: probabilities.iter().map(|p| p.ln() as f64).sum::<f64>() / probabilities.len() as f64,
+            no_speech_prob: 0.01,
+            compression_ratio: 1.0,
+            tokens,
+        }
+    }
+
+    #[test]
+    fn test_low_confidence_cues_are_marked() {
+        let segments = [
+            segment(0.0, 2.5, " Sure thing.", &[0.95, 0.9, 0.97]),
+            segment(2.5, 3661.255, " Mumble {grumble}", &[0.9, 0.05]),
+        ];
+        let thresholds = ConfidenceThresholds::default();
+
+        let ass = to_ass(&segments, &thresholds);
+        assert!(ass.contains("Dialogue: 0,0:00:00.00,0:00:02.50,Default,,0,0,0,,Sure thing.\n"), "{ass}");
+        assert!(ass.contains("Dialogue: 0,0:00:02.50,1:01:01.26,Uncertain,,0,0,0,,Mumble (grumble)\n"), "{ass}");
+
+        let json: serde_json::Value = serde_json::from_str(&to_confidence_json(&segments, &thresholds).unwrap()).unwrap();
+        assert_eq!(json[0]["low_confidence"], false);
+        assert_eq!(json[1]["index"], 2);
+        assert_eq!(json[1]["low_confidence"], true);
+        assert_eq!(json[1]["tokens"][1]["text"], "t1");
+    }
+
+    #[test]
+    fn test_srt() {
+        let srt = to_srt(&[segment(1.0, 2.0, " Hello", &[0.9])]);
+        assert_eq!(srt, "1\n00:00:01,000 --> 00:00:02,000\nHello\n\n");
+    }
+
+    #[test]
+    fn test_write_atomic_leaves_no_part_file() {
+        let dir = std::env::temp_dir().join(format!("whisper-export-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("out.srt");
+        write_atomic(&path, "first").unwrap();
+        write_atomic(&path, "second").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "second");
+        assert!(!dir.join("out.srt.part").exists());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_all_atomic_removes_the_others_on_failure() {
+        let dir = std::env::temp_dir().join(format!("whisper-export-all-test-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("b.ass")).unwrap();
+        // Output of an earlier run, which a failed write must not lose.
+        std::fs::write(dir.join("a.srt"), "earlier").unwrap();
+        let files = ["a.srt", "b.ass", "c.json"].map(|name| (dir.join(name), name.to_string()));
+        let list = || {
+            let mut names: Vec<String> = std::fs::read_dir(&dir).unwrap().map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+            names.sort();
+            names
+        };
+        let (path, _) = write_all_atomic(&files).unwrap_err();
+        assert_eq!(path, dir.join("b.ass"));
+        assert_eq!(list(), ["a.srt", "b.ass"]);
+        assert_eq!(std::fs::read_to_string(dir.join("a.srt")).unwrap(), "earlier");
+
+        std::fs::remove_dir(dir.join("b.ass")).unwrap();
+        write_all_atomic(&files).unwrap();
+        assert_eq!(list(), ["a.srt", "b.ass", "c.json"]);
+        assert_eq!(std::fs::read_to_string(dir.join("a.srt")).unwrap(), "a.srt");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,200 @@
+use anyhow::{Context, Result};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::audio::SAMPLE_RATE;
+use crate::tracks::AudioTrack;
+
+// Fallback decoder for containers and codecs symphonia does not handle
+// (MKV/WebM with Opus, AVI, some MOV). Needs `ffmpeg` on the PATH, like media_cutter.
+
+/// Whether an `ffmpeg` binary can be run.
+pub fn is_available() -> bool {
+    Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+/// Decode audio track `track` of `path` (counted among its audio tracks; the
+/// stream ffmpeg picks when `None`) to 16 kHz mono f32 PCM.
+pub fn decode(path: &Path, track: Option<usize>) -> Result<Vec<f32>> {
+    let mut command = Command::new("ffmpeg");
+    command.args(["-nostdin", "-v", "error", "-i"]).arg(path);
+    if let Some(track) = track {
+        command.args(["-map", &format!("0:a:{}", track)]);
+    }
+    let output = command
+        .args(["-vn", "-ac", "1", "-ar", &SAMPLE_RATE.to_string(), "-f", "f32le", "-"])
+        .stdin(Stdio::null())
+        .output()
+        .context("could not run ffmpeg; install it and make sure it is on the PATH")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("ffmpeg failed: {}", stderr.lines().last().unwrap_or("unknown error").trim());
+    }
+    if output.stdout.is_empty() {
+        anyhow::bail!("ffmpeg found no audio");
+    }
+    Ok(output
+        .stdout
+        .chunks_exact(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect())
+}
+
+/// The audio streams of `path` as ffprobe reports them.
+pub fn probe_audio_tracks(path: &Path) -> Result<Vec<AudioTrack>> {
+    let output = Command::new("ffprobe")
+        .args(["-v", "error", "-select_streams", "a", "-show_streams", "-of", "json"])
+        .arg(path)
+        .stdin(Stdio::null())
+        .output()
+        .context("could not run ffprobe")?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        anyhow::bail!("ffprobe failed: {}", stderr.lines().last().unwrap_or("unknown error").trim());
+    }
+    let json: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let streams = json["streams"].as_array().cloned().unwrap_or_default();
+    if streams.is_empty() {
+        anyhow::bail!("ffprobe found no audio");
+    }
+    let tag = |stream: &serde_json::Value, name: &str| stream["tags"][name].as_str().map(str::to_string);
+    Ok(streams
+        .iter()
+        .enumerate()
+        .map(|(index, stream)| AudioTrack {
+            index,
+            codec: stream["codec_name"].as_str().unwrap_or("unknown").to_string(),
+            language: tag(stream, "language").filter(|language| language != "und"),
+            title: tag(stream, "title"),
+            channels: stream["channels"].as_u64().map(|c| c as usize),
+            // ffprobe prints the rate as a string.
+            sample_rate: stream["sample_rate"].as_str().and_then(|rate| rate.parse().ok()),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// Render `args` (inputs and codecs) to `name` in the temp folder, or `None`
+    /// when ffmpeg is not installed and the test should be skipped.
+    pub(crate) fn synthesize(name: &str, args: &[&str]) -> Option<PathBuf> {
+        if !is_available() {
+            eprintln!("ffmpeg not found, skipping");
+            return None;
+        }
+        let path = std::env::temp_dir().join(format!("whisper-ffmpeg-{}-{}", std::process::id(), name));
+        let status = Command::new("ffmpeg")
+            .args(["-nostdin", "-v", "error", "-y"])
+            .args(args)
+            .arg(&path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "ffmpeg could not write {}", name);
+        Some(path)
+    }
+
+    /// Zero crossings per second of `pcm` at 16 kHz, twice the frequency of a tone.
+    pub(crate) fn crossings_per_second(pcm: &[f32]) -> f32 {
+        let crossings = pcm.windows(2).filter(|w| (w[0] < 0.0) != (w[1] < 0.0)).count();
+        crossings as f32 * SAMPLE_RATE as f32 / pcm.len() as f32
+    }
+
+    #[test]
+    fn test_decodes_avi_to_16khz_mono() {
+        let Some(path) = synthesize(
+            "tone.avi",
+            &["-f", "lavfi", "-i", "sine=frequency=440:sample_rate=44100:duration=2", "-ac", "2", "-c:a", "pcm_s16le"],
+        ) else {
+            return;
+        };
+        let pcm = decode(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!((pcm.len() as i64 - 2 * SAMPLE_RATE as i64).abs() < 1600, "{} samples", pcm.len());
+        let hz = crossings_per_second(&pcm) / 2.0;
+        assert!((hz - 440.0).abs() < 10.0, "{} Hz", hz);
+    }
+
+    #[test]
+    fn test_load_audio_falls_back_to_ffmpeg() {
+        use crate::whisper_engine::{load_audio, AudioDecoder};
+        let Some(wav) = synthesize("tone.wav", &["-f", "lavfi", "-i", "sine=frequency=440:duration=1"]) else {
+            return;
+        };
+        let avi = synthesize("tone-mono.avi", &["-f", "lavfi", "-i", "sine=frequency=440:duration=1", "-c:a", "pcm_s16le"]).unwrap();
+        let (wav_pcm, wav_decoder) = load_audio(&wav, None).unwrap();
+        let (avi_pcm, avi_decoder) = load_audio(&avi, None).unwrap();
+        std::fs::remove_file(&wav).unwrap();
+        std::fs::remove_file(&avi).unwrap();
+        assert_eq!(wav_decoder, AudioDecoder::Symphonia);
+        assert_eq!(avi_decoder, AudioDecoder::Ffmpeg);
+        // Both decoders resample to the same 16 kHz tone.
+        assert!((crossings_per_second(&wav_pcm) - crossings_per_second(&avi_pcm)).abs() < 20.0);
+    }
+
+    #[test]
+    fn test_selects_audio_track() {
+        use crate::whisper_engine::load_audio;
+        let Some(path) = synthesize(
+            "two-tracks.mkv",
+            &[
+                "-f", "lavfi", "-i", "sine=frequency=300:duration=1",
+                "-f", "lavfi", "-i", "sine=frequency=900:duration=1",
+                "-map", "0:a", "-map", "1:a", "-c:a", "pcm_s16le",
+            ],
+        ) else {
+            return;
+        };
+        let first = decode(&path, Some(0)).unwrap();
+        let second = decode(&path, Some(1)).unwrap();
+        let (loaded, _) = load_audio(&path, Some(1)).unwrap();
+        assert!(decode(&path, Some(2)).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert!((crossings_per_second(&first) / 2.0 - 300.0).abs() < 10.0);
+        assert!((crossings_per_second(&second) / 2.0 - 900.0).abs() < 10.0);
+        assert!((crossings_per_second(&loaded) / 2.0 - 900.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_track_numbering_counts_codecs_symphonia_lacks() {
+        use crate::tracks::list_audio_tracks;
+        use crate::whisper_engine::{load_audio, AudioDecoder};
+        // Symphonia has no AC-3 decoder, so it would call the PCM track its first.
+        let Some(path) = synthesize(
+            "ac3-then-pcm.mkv",
+            &[
+                "-f", "lavfi", "-i", "sine=frequency=300:duration=1",
+                "-f", "lavfi", "-i", "sine=frequency=900:duration=1",
+                "-map", "0:a", "-map", "1:a", "-c:a:0", "ac3", "-c:a:1", "pcm_s16le",
+            ],
+        ) else {
+            return;
+        };
+        let tracks = list_audio_tracks(&path).unwrap();
+        let (first, first_decoder) = load_audio(&path, Some(0)).unwrap();
+        let (second, _) = load_audio(&path, Some(1)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].codec, "ac3");
+        assert_eq!(first_decoder, AudioDecoder::Ffmpeg);
+        assert!((crossings_per_second(&first) / 2.0 - 300.0).abs() < 10.0);
+        assert!((crossings_per_second(&second) / 2.0 - 900.0).abs() < 10.0);
+    }
+
+    #[test]
+    fn test_video_without_audio_is_an_error() {
+        let Some(path) = synthesize("silent.avi", &["-f", "lavfi", "-i", "color=c=black:s=32x32:d=1", "-c:v", "mjpeg"]) else {
+            return;
+        };
+        let error = decode(&path, None).unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().starts_with("ffmpeg"), "{}", error);
+    }
+}
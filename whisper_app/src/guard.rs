@@ -0,0 +1,183 @@
+use serde::Serialize;
+
+use crate::decoding::compression_ratio;
+
+/// What to do with a segment that looks hallucinated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GuardAction {
+    Drop,
+    /// Decode the segment's audio again at fallback temperatures without
+    /// context; drop it if the new text is flagged too.
+    Redecode,
+}
+
+/// Thresholds for `check_segment`. Rates are in units per second, where a unit
+/// is a word, or a single character for Chinese, Japanese and Korean.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GuardOptions {
+    /// Flag an n-gram repeated back to back more often than this.
+    pub max_repeats: usize,
+    /// Longest n-gram checked for repetition.
+    pub max_ngram: usize,
+    pub compression_ratio_threshold: f64,
+    pub max_units_per_sec: f64,
+    /// Segments at least `slow_min_duration` seconds long need this rate.
+    pub min_units_per_sec: f64,
+    pub slow_min_duration: f64,
+    pub action: GuardAction,
+}
+
+impl Default for GuardOptions {
+    fn default() -> Self {
+        Self {
+            max_repeats: 4,
+            max_ngram: 8,
+            compression_ratio_threshold: 2.4,
+            max_units_per_sec: 10.0,
+            min_units_per_sec: 0.15,
+            slow_min_duration: 10.0,
+            action: GuardAction::Redecode,
+        }
+    }
+}
+
+/// Why a segment was flagged.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Issue {
+    RepeatedNgram { ngram: String, repeats: usize },
+    HighCompressionRatio(f64),
+    TooFast(f64),
+    TooSlow(f64),
+}
+
+impl std::fmt::Display for Issue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Issue::RepeatedNgram { ngram, repeats } => write!(f, "\"{}\" repeated {} times", ngram, repeats),
+            Issue::HighCompressionRatio(ratio) => write!(f, "compression ratio {:.2}", ratio),
+            Issue::TooFast(rate) => write!(f, "{:.1} units/s is too fast", rate),
+            Issue::TooSlow(rate) => write!(f, "{:.2} units/s is too slow", rate),
+        }
+    }
+}
+
+/// A segment the guard flagged, kept so it can be reviewed.
+#[derive(Debug, Clone, Serialize)]
+pub struct FlaggedSegment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    pub issues: Vec<Issue>,
+    /// Text that replaced it after re-decoding; `None` when it was dropped.
+    pub replacement: Option<String>,
+}
+
+fn is_cjk(c: char) -> bool {
+    matches!(c,
+        '\u{3040}'..='\u{30ff}' // kana
+        | '\u{3400}'..='\u{4dbf}'
+        | '\u{4e00}'..='\u{9fff}'
+        | '\u{ac00}'..='\u{d7af}' // hangul
+        | '\u{f900}'..='\u{faff}')
+}
+
+/// Words, with every CJK character counted as a word of its own.
+fn units(text: &str) -> Vec<String> {
+    let mut units = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        if is_cjk(c) || !(c.is_alphanumeric() || c == '\'') {
+            if !word.is_empty() {
+                units.push(std::mem::take(&mut word).to_lowercase());
+            }
+            if is_cjk(c) {
+                units.push(c.to_string());
+            }
+        } else {
+            word.push(c);
+        }
+    }
+    if !word.is_empty() {
+        units.push(word.to_lowercase());
+    }
+    units
+}
+
+/// The n-gram repeated back to back the most times, with its count.
+fn most_repeated_ngram(units: &[String], max_ngram: usize) -> Option<(String, usize)> {
+    let mut best: Option<(String, usize)> = None;
+    for n in 1..=max_ngram.min(units.len() / 2) {
+        for i in 0..=units.len() - n {
+            let mut repeats = 1;
+            while i + (repeats + 1) * n <= units.len() && units[i + repeats * n..i + (repeats + 1) * n] == units[i..i + n] {
+                repeats += 1;
+            }
+            if repeats > 1 && best.as_ref().is_none_or(|(_, r)| repeats > *r) {
+                let separator = if units[i..i + n].iter().all(|u| u.chars().all(is_cjk)) { "" } else { " " };
+                best = Some((units[i..i + n].join(separator), repeats));
+            }
+        }
+    }
+    best
+}
+
+/// Everything suspicious about a segment of `duration` seconds; empty when it looks fine.
+pub fn check_segment(text: &str, duration: f64, options: &GuardOptions) -> Vec<Issue> {
+    let mut issues = Vec::new();
+    let units = units(text);
+    if let Some((ngram, repeats)) = most_repeated_ngram(&units, options.max_ngram) {
+        if repeats > options.max_repeats {
+            issues.push(Issue::RepeatedNgram { ngram, repeats });
+        }
+    }
+    let ratio = compression_ratio(text.trim());
+    if ratio > options.compression_ratio_threshold {
+        issues.push(Issue::HighCompressionRatio(ratio));
+    }
+    let rate = units.len() as f64 / duration.max(0.1);
+    if rate > options.max_units_per_sec {
+        issues.push(Issue::TooFast(rate));
+    } else if duration >= options.slow_min_duration && rate < options.min_units_per_sec {
+        issues.push(Issue::TooSlow(rate));
+    }
+    issues
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normal_speech_passes() {
+        let options = GuardOptions::default();
+        assert!(check_segment(" We are going to look at the new timeline editor today.", 3.5, &options).is_empty());
+        assert!(check_segment("今天我们来看一下新的时间线编辑器。", 3.0, &options).is_empty());
+        assert!(check_segment(" No, no, no.", 1.5, &options).is_empty());
+    }
+
+    #[test]
+    fn test_repeated_sentence() {
+        let text = " Thank you for watching. ".repeat(6);
+        let issues = check_segment(&text, 20.0, &GuardOptions::default());
+        assert!(issues.contains(&Issue::RepeatedNgram { ngram: "thank you for watching".to_string(), repeats: 6 }), "{issues:?}");
+        assert!(issues.iter().any(|i| matches!(i, Issue::HighCompressionRatio(_))));
+    }
+
+    #[test]
+    fn test_repeated_cjk() {
+        let text = "字幕由志愿者提供".repeat(5);
+        let issues = check_segment(&text, 25.0, &GuardOptions::default());
+        assert!(matches!(&issues[0], Issue::RepeatedNgram { ngram, repeats: 5 } if ngram == "字幕由志愿者提供"), "{issues:?}");
+    }
+
+    #[test]
+    fn test_speech_rate() {
+        let options = GuardOptions::default();
+        let fast = check_segment(" one two three four five six seven eight nine ten eleven twelve", 0.5, &options);
+        assert!(matches!(fast[..], [Issue::TooFast(_)]), "{fast:?}");
+        let slow = check_segment(" Thanks.", 28.0, &options);
+        assert!(matches!(slow[..], [Issue::TooSlow(_)]), "{slow:?}");
+        // A short pause around one word is fine.
+        assert!(check_segment(" Okay.", 4.0, &options).is_empty());
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::Result;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// How far a transcription job has got, reported after every 30 s window.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Progress {
+    /// Windows decoded so far.
+    pub window: usize,
+    /// Seconds of audio decoded so far; with VAD only speech is counted.
+    pub processed_secs: f64,
+    pub total_secs: f64,
+}
+
+impl Progress {
+    pub fn percent(&self) -> f32 {
+        if self.total_secs <= 0.0 {
+            return 100.0;
+        }
+        (self.processed_secs / self.total_secs * 100.0).clamp(0.0, 100.0) as f32
+    }
+}
+
+/// Asks a running job to stop; it does so before its next window. Clones share
+/// the same flag, so one can be kept by the UI and one handed to the job.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+/// Error of a job stopped through its `CancellationToken`; check with
+/// `err.is::<Cancelled>()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl std::fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "transcription cancelled")
+    }
+}
+
+impl std::error::Error for Cancelled {}
+
+/// Progress callback and cancellation token of one transcription job.
+#[derive(Default)]
+pub struct Job<'a> {
+    cancel: CancellationToken,
+    on_progress: Option<Box<dyn FnMut(Progress) + Send + 'a>>,
+}
+
+impl<'a> Job<'a> {
+    pub fn new(cancel: CancellationToken) -> Self {
+        Self { cancel, on_progress: None }
+    }
+
+    pub fn on_progress(mut self, callback: impl FnMut(Progress) + Send + 'a) -> Self {
+        self.on_progress = Some(Box::new(callback));
+        self
+    }
+
+    /// `Err(Cancelled)` once the job has been cancelled.
+    pub fn check(&self) -> Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Pass `progress` to the callback, if any; called by `Transcriber`s.
+    pub fn report(&mut self, progress: Progress) {
+        if let Some(callback) = &mut self.on_progress {
+            callback(progress);
+        }
+    }
+
+    /// A job for nested decoding passes: same cancellation, no progress reports.
+    pub(crate) fn quiet(&self) -> Job<'static> {
+        Job::new(self.cancel.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cancel_is_shared_between_clones() {
+        let token = CancellationToken::default();
+        let job = Job::new(token.clone());
+        assert!(job.check().is_ok());
+        token.cancel();
+        assert!(job.check().unwrap_err().is::<Cancelled>());
+        assert!(job.quiet().check().is_err());
+    }
+
+    #[test]
+    fn test_progress_reports() {
+        let mut seen = Vec::new();
+        {
+            let mut job = Job::default().on_progress(|p| seen.push(p.percent()));
+            job.report(Progress { window: 1, processed_secs: 30.0, total_secs: 120.0 });
+            job.report(Progress { window: 2, processed_secs: 130.0, total_secs: 120.0 });
+            job.quiet().report(Progress { window: 3, processed_secs: 60.0, total_secs: 120.0 });
+        }
+        assert_eq!(seen, [25.0, 100.0]);
+    }
+}
@@ -0,0 +1,24 @@
+pub mod alignment;
+pub mod audio;
+pub mod audio_encoder;
+pub mod batch;
+pub mod decoding;
+pub mod engine_config;
+pub mod export;
+pub mod ffmpeg;
+pub mod guard;
+pub mod job;
+pub mod language;
+pub mod logit_filters;
+pub mod models;
+pub mod parallel;
+pub mod preprocess;
+pub mod quantize;
+pub mod resample;
+pub mod settings;
+pub mod streaming;
+pub mod text_decoder;
+pub mod tracks;
+pub mod transcriber;
+pub mod vad;
+pub mod whisper_engine;
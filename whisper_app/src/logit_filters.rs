@@ -0,0 +1,258 @@
+use anyhow::Result;
+
+use crate::decoding::{log_softmax, TokenDecoder};
+
+/// Rewrites next-token logits before a token is picked, like reference Whisper's
+/// `LogitFilter`s. `tokens` are the tokens sampled so far in this window, without
+/// the prompt.
+pub trait LogitFilter {
+    fn apply(&self, logits: &mut [f32], tokens: &[u32]);
+}
+
+fn suppress(logits: &mut [f32], tokens: impl IntoIterator<Item = u32>) {
+    for token in tokens {
+        if let Some(logit) = logits.get_mut(token as usize) {
+            *logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Do not start a window with a blank or with end-of-text.
+pub struct SuppressBlank {
+    /// The encoding of `" "` and `<|endoftext|>`.
+    pub blank_tokens: Vec<u32>,
+}
+
+impl LogitFilter for SuppressBlank {
+    fn apply(&self, logits: &mut [f32], tokens: &[u32]) {
+        if tokens.is_empty() {
+            suppress(logits, self.blank_tokens.iter().copied());
+        }
+    }
+}
+
+/// Never produce these tokens (non-speech symbols and special tokens).
+pub struct SuppressTokens {
+    pub tokens: Vec<u32>,
+}
+
+impl LogitFilter for SuppressTokens {
+    fn apply(&self, logits: &mut [f32], _tokens: &[u32]) {
+        suppress(logits, self.tokens.iter().copied());
+    }
+}
+
+/// Keeps timestamp tokens well-formed: they come in pairs around text, never go
+/// backwards, the window starts with one, and the first is at most
+/// `max_initial_timestamp_index` steps (of 20 ms) in.
+pub struct ApplyTimestampRules {
+    pub eot_token: u32,
+    pub no_timestamps_token: u32,
+    pub timestamp_begin: u32,
+    pub max_initial_timestamp_index: Option<u32>,
+}
+
+impl LogitFilter for ApplyTimestampRules {
+    fn apply(&self, logits: &mut [f32], tokens: &[u32]) {
+        let begin = self.timestamp_begin as usize;
+        let eot = self.eot_token as usize;
+        suppress(logits, [self.no_timestamps_token]);
+
+        let is_timestamp = |t: u32| t >= self.timestamp_begin;
+        let last_was_timestamp = tokens.last().is_some_and(|&t| is_timestamp(t));
+        let penultimate_was_timestamp = tokens.len() < 2 || is_timestamp(tokens[tokens.len() - 2]);
+        if last_was_timestamp {
+            if penultimate_was_timestamp {
+                // A pair just closed: text (or end-of-text) must follow.
+                logits[begin..].fill(f32::NEG_INFINITY);
+            } else {
+                // A segment's text just ended: only a timestamp or end-of-text.
+                logits[..eot].fill(f32::NEG_INFINITY);
+            }
+        }
+
+        if let Some(&last) = tokens.iter().rev().find(|&&t| is_timestamp(t)) {
+            // Timestamps must not decrease; a new segment cannot reuse its start time.
+            let last = last as usize;
+            let min_allowed = if last_was_timestamp && !penultimate_was_timestamp { last } else { last + 1 };
+            let end = min_allowed.min(logits.len());
+            logits[begin..end].fill(f32::NEG_INFINITY);
+        }
+
+        if tokens.is_empty() {
+            logits[..begin].fill(f32::NEG_INFINITY);
+            if let Some(max_index) = self.max_initial_timestamp_index {
+                let last_allowed = begin + max_index as usize;
+                if last_allowed + 1 < logits.len() {
+                    logits[last_allowed + 1..].fill(f32::NEG_INFINITY);
+                }
+            }
+        }
+
+        // When the timestamps together outweigh every single text token, emit one.
+        let logprobs = log_softmax(logits);
+        let timestamp_logprob = log_sum_exp(&logprobs[begin..]);
+        let max_text_logprob = logprobs[..begin].iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if timestamp_logprob > max_text_logprob {
+            logits[..begin].fill(f32::NEG_INFINITY);
+        }
+    }
+}
+
+fn log_sum_exp(values: &[f32]) -> f32 {
+    let max = values.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    if max == f32::NEG_INFINITY {
+        return max;
+    }
+    values.iter().map(|v| (v - max).exp()).sum::<f32>().ln() + max
+}
+
+/// Runs `filters` over every set of logits `inner` produces.
+pub struct FilteredDecoder<'a, D> {
+    pub inner: D,
+    filters: &'a [Box<dyn LogitFilter>],
+}
+
+impl<'a, D> FilteredDecoder<'a, D> {
+    pub fn new(inner: D, filters: &'a [Box<dyn LogitFilter>]) -> Self {
+        Self { inner, filters }
+    }
+
+    fn filter(&self, mut logits: Vec<f32>, tokens: &[u32]) -> Vec<f32> {
+        for filter in self.filters {
+            filter.apply(&mut logits, tokens);
+        }
+        logits
+    }
+}
+
+impl<D: TokenDecoder> TokenDecoder for FilteredDecoder<'_, D> {
+    /// The inner state and the tokens sampled so far.
+    type State = (D::State, Vec<u32>);
+
+    fn start(&mut self, prompt: &[u32]) -> Result<(Self::State, Vec<f32>)> {
+        let (state, logits) = self.inner.start(prompt)?;
+        Ok(((state, Vec::new()), self.filter(logits, &[])))
+    }
+
+    fn step(&mut self, (state, tokens): &mut Self::State, token: u32) -> Result<Vec<f32>> {
+        tokens.push(token);
+        let logits = self.inner.step(state, token)?;
+        Ok(self.filter(logits, tokens))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Toy vocabulary: 0..5 text, 5 eot, 6 no-timestamps, 7.. timestamps.
+    const EOT: u32 = 5;
+    const NO_TIMESTAMPS: u32 = 6;
+    const TS: u32 = 7;
+    const VOCAB: usize = 15;
+
+    fn rules() -> ApplyTimestampRules {
+        ApplyTimestampRules {
+            eot_token: EOT,
+            no_timestamps_token: NO_TIMESTAMPS,
+            timestamp_begin: TS,
+            max_initial_timestamp_index: Some(2),
+        }
+    }
+
+    fn allowed(logits: &[f32]) -> Vec<u32> {
+        (0..logits.len() as u32).filter(|&t| logits[t as usize].is_finite()).collect()
+    }
+
+    #[test]
+    fn test_suppress_blank_only_at_start() {
+        let filter = SuppressBlank { blank_tokens: vec![1, EOT] };
+        let mut logits = vec![0.0; VOCAB];
+        filter.apply(&mut logits, &[]);
+        assert!(!allowed(&logits).contains(&1) && !allowed(&logits).contains(&EOT));
+        let mut logits = vec![0.0; VOCAB];
+        filter.apply(&mut logits, &[TS, 2]);
+        assert_eq!(allowed(&logits).len(), VOCAB);
+    }
+
+    #[test]
+    fn test_suppress_tokens() {
+        let filter = SuppressTokens { tokens: vec![0, 3, 99] };
+        let mut logits = vec![0.0; VOCAB];
+        filter.apply(&mut logits, &[2]);
+        assert_eq!(allowed(&logits).len(), VOCAB - 2);
+        assert!(!allowed(&logits).contains(&3));
+    }
+
+    #[test]
+    fn test_window_starts_with_early_timestamp() {
+        let mut logits = vec![0.0; VOCAB];
+        logits[2] = 10.0; // text strongly preferred by the model
+        rules().apply(&mut logits, &[]);
+        assert_eq!(allowed(&logits), [TS, TS + 1, TS + 2]);
+    }
+
+    #[test]
+    fn test_timestamps_are_paired() {
+        // After "<|0.00|> text <|0.40|>": a timestamp or end-of-text, no text.
+        let mut logits = vec![0.0; VOCAB];
+        rules().apply(&mut logits, &[TS, 2, TS + 4]);
+        assert!(allowed(&logits).iter().all(|&t| t >= EOT));
+        assert!(!allowed(&logits).contains(&NO_TIMESTAMPS));
+
+        // After a closed pair: text or end-of-text, no timestamp.
+        let mut logits = vec![0.0; VOCAB];
+        rules().apply(&mut logits, &[TS, 2, TS + 4, TS + 4]);
+        assert!(allowed(&logits).iter().all(|&t| t <= EOT));
+    }
+
+    #[test]
+    fn test_timestamps_never_go_backwards() {
+        // Inside a segment started at <|0.60|>, its end cannot be earlier.
+        let mut logits = vec![0.0; VOCAB];
+        rules().apply(&mut logits, &[TS, 1, TS + 2, TS + 3, 4]);
+        let timestamps: Vec<u32> = allowed(&logits).into_iter().filter(|&t| t >= TS).collect();
+        assert_eq!(timestamps.first(), Some(&(TS + 4)));
+
+        // Right after a segment end, the next may start at the same time but not before.
+        let mut logits = vec![0.0; VOCAB];
+        rules().apply(&mut logits, &[TS, 1, TS + 3]);
+        let timestamps: Vec<u32> = allowed(&logits).into_iter().filter(|&t| t >= TS).collect();
+        assert_eq!(timestamps.first(), Some(&(TS + 3)));
+    }
+
+    #[test]
+    fn test_timestamp_mass_forces_timestamp() {
+        // No single timestamp beats text token 1, but together they do.
+        let mut logits = vec![f32::NEG_INFINITY; VOCAB];
+        logits[1] = 1.0;
+        for t in TS..VOCAB as u32 {
+            logits[t as usize] = 0.5;
+        }
+        rules().apply(&mut logits, &[TS, 1]);
+        assert!(allowed(&logits).iter().all(|&t| t >= TS));
+    }
+
+    #[test]
+    fn test_filtered_decoder_tracks_tokens() {
+        struct Uniform;
+        impl TokenDecoder for Uniform {
+            type State = ();
+            fn start(&mut self, _prompt: &[u32]) -> Result<((), Vec<f32>)> {
+                Ok(((), vec![0.0; VOCAB]))
+            }
+            fn step(&mut self, _state: &mut (), _token: u32) -> Result<Vec<f32>> {
+                Ok(vec![0.0; VOCAB])
+            }
+        }
+        let filters: Vec<Box<dyn LogitFilter>> = vec![Box::new(rules())];
+        let mut decoder = FilteredDecoder::new(Uniform, &filters);
+        let (mut state, logits) = decoder.start(&[]).unwrap();
+        assert!(allowed(&logits).iter().all(|&t| t >= TS));
+        decoder.step(&mut state, TS).unwrap();
+        let logits = decoder.step(&mut state, 2).unwrap();
+        assert_eq!(state.1, [TS, 2]);
+        assert!(!allowed(&logits).contains(&TS));
+    }
+}
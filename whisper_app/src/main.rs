@@ -3,15 +3,26 @@ use rfd::FileDialog;
 use std::sync::Arc;
 use tokio::sync::Mutex;
 use std::sync::mpsc::{Receiver, Sender, channel};
-use std::thread;
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::fs;
 use common::time_utils::seconds_to_time_str;
 
-mod audio;
-mod whisper_engine;
 use common::ai::DeepSeekClient;
-use whisper_engine::WhisperEngine;
+use whisper_app::batch::{run_batch_parallel, BatchEvent, BatchOptions, FileStatus, TrackSelection};
+use whisper_app::decoding::{DecodingOptions, Task};
+use whisper_app::engine_config::{DeviceChoice, EngineConfig, Precision};
+use whisper_app::guard::{GuardAction, GuardOptions};
+use whisper_app::job::CancellationToken;
+use whisper_app::language::{language_name, LANGUAGES};
+use whisper_app::models::{format_size, DownloadProgress, LocalModel, ModelSource, ModelStore};
+use whisper_app::parallel::{ChunkOptions, ParallelTranscriber};
+use whisper_app::preprocess::{NoiseReduction, Normalization};
+use whisper_app::quantize::{parse_model_id, Quantization};
+use whisper_app::settings::Settings;
+use whisper_app::tracks::list_audio_tracks;
+use whisper_app::transcriber::Transcriber;
+use whisper_app::vad::VadOptions;
+use whisper_app::whisper_engine::WhisperEngine;
 
 struct WhisperApp {
     // Tabs
@@ -20,11 +31,43 @@ struct WhisperApp {
     // Transcription State
     tx_files: Vec<String>,
     tx_model: String,
+    /// Local model folder; takes precedence over `tx_model` when set.
+    tx_model_dir: Option<String>,
+    /// Device, precision and threads used the next time a model is loaded.
+    tx_engine_config: EngineConfig,
+    /// Engines transcribing chunks of one file at once; 1 turns chunking off.
+    tx_parallel_engines: usize,
     tx_output_dir: String,
+    tx_decoding: DecodingOptions,
+    /// Also write `.confidence.json` and `.ass` with uncertain cues marked.
+    tx_export_confidence: bool,
+    tx_tracks: TrackSelection,
+    /// Track numbers typed by the user (from 1) for `TrackSelection::Only`.
+    tx_track_list: String,
+    tx_merge_tracks: bool,
+    /// Files transcribed at once, on engines sharing the model weights.
+    tx_workers: usize,
     is_transcribing: bool,
+    /// Status of every file in `tx_files`, kept the same length.
+    tx_status: Vec<FileStatus>,
+    /// Result of the last batch.
+    tx_summary: Option<String>,
+    /// Cancels the running job; replaced for every new one.
+    tx_cancel: CancellationToken,
+    /// Saved between sessions (glossary).
+    settings: Settings,
+
+    // Model Tab State
+    /// Listed when the tab is first shown and after every change.
+    models: Option<Vec<LocalModel>>,
+    model_download_id: String,
+    /// Latest progress of a running download.
+    model_download: Option<DownloadProgress>,
+    /// Deleted on the second click.
+    model_pending_delete: Option<std::path::PathBuf>,
     
     // Engine State
-    engine: Arc<Mutex<Option<WhisperEngine>>>,
+    engine: Arc<Mutex<Option<Box<dyn Transcriber>>>>,
     rx: Receiver<AppMessage>,
     tx: Sender<AppMessage>,
     
@@ -45,7 +88,14 @@ struct WhisperApp {
 
 enum AppMessage {
     Log(String),
-    ModelLoaded,
+    /// Carries the device description.
+    ModelLoaded(String),
+    /// Index in `tx_files` and its new status.
+    FileStatus(usize, FileStatus),
+    Download(DownloadProgress),
+    /// The model cache may have changed (download, import, delete, failed load);
+    /// carries the log line.
+    ModelsChanged(String),
     TranscriptionDone(String), // Result message
 }
 
@@ -54,6 +104,7 @@ enum Tab {
     Transcription,
     Translation,
     Storyboard,
+    Models,
     Logs,
     Help,
 }
@@ -65,8 +116,29 @@ impl Default for WhisperApp {
             selected_tab: Tab::Transcription,
             tx_files: vec![],
             tx_model: "small".to_string(),
+            tx_model_dir: None,
+            tx_engine_config: EngineConfig::default(),
+            tx_parallel_engines: 1,
             tx_output_dir: std::env::current_dir().unwrap().display().to_string(),
+            tx_decoding: DecodingOptions {
+                beam_size: Some(5),
+                vad: Some(VadOptions::default()),
+                ..Default::default()
+            },
+            tx_export_confidence: false,
+            tx_tracks: TrackSelection::Default,
+            tx_track_list: "1".to_string(),
+            tx_merge_tracks: false,
+            tx_workers: 1,
             is_transcribing: false,
+            tx_status: Vec::new(),
+            tx_summary: None,
+            tx_cancel: CancellationToken::default(),
+            settings: Settings::load(),
+            models: None,
+            model_download_id: "small".to_string(),
+            model_download: None,
+            model_pending_delete: None,
             engine: Arc::new(Mutex::new(None)),
             rx,
             tx,
@@ -95,12 +167,28 @@ impl WhisperApp {
         while let Ok(msg) = self.rx.try_recv() {
             match msg {
                 AppMessage::Log(s) => self.log(&s),
-                AppMessage::ModelLoaded => {
-                    self.log("模型加载成功!");
+                AppMessage::ModelLoaded(device) => {
+                    self.model_download = None;
+                    self.models = None;
+                    self.log(&format!("模型加载成功! 运行于: {}", device));
+                }
+                AppMessage::FileStatus(index, status) => {
+                    if let Some(slot) = self.tx_status.get_mut(index) {
+                        *slot = status;
+                    }
+                }
+                AppMessage::Download(progress) => {
+                    self.model_download = Some(progress);
+                }
+                AppMessage::ModelsChanged(message) => {
+                    self.log(&message);
+                    self.model_download = None;
+                    self.models = None;
                 }
                 AppMessage::TranscriptionDone(res) => {
                     self.log(&res);
                     self.is_transcribing = false;
+                    self.tx_summary = Some(res);
                 }
             }
         }
@@ -159,6 +247,7 @@ impl eframe::App for WhisperApp {
                 ui.selectable_value(&mut self.selected_tab, Tab::Transcription, "🎤 转写");
                 ui.selectable_value(&mut self.selected_tab, Tab::Translation, "🌐 翻译");
                 ui.selectable_value(&mut self.selected_tab, Tab::Storyboard, "🎬 分镜");
+                ui.selectable_value(&mut self.selected_tab, Tab::Models, "📦 模型");
                 ui.selectable_value(&mut self.selected_tab, Tab::Logs, "📋 日志");
                 ui.selectable_value(&mut self.selected_tab, Tab::Help, "❓ 帮助");
             });
@@ -169,12 +258,13 @@ impl eframe::App for WhisperApp {
                 Tab::Transcription => self.show_transcription(ui),
                 Tab::Translation => self.show_translation(ui),
                 Tab::Storyboard => self.show_storyboard(ui),
+                Tab::Models => self.show_models(ui),
                 Tab::Logs => self.show_logs(ui),
                 Tab::Help => self.show_help(ui),
             }
         });
         
-        if self.is_transcribing {
+        if self.is_transcribing || self.model_download.is_some() {
             ctx.request_repaint();
         }
     }
@@ -193,29 +283,280 @@ impl WhisperApp {
                     ui.selectable_value(&mut self.tx_model, "small".to_string(), "Small (推荐)");
                     ui.selectable_value(&mut self.tx_model, "medium".to_string(), "Medium");
                     ui.selectable_value(&mut self.tx_model, "large".to_string(), "Large");
+                    ui.separator();
+                    ui.label("量化 (GGUF, 适合纯 CPU)");
+                    for size in ["tiny", "base", "small", "medium", "large"] {
+                        for q in Quantization::ALL {
+                            let id = format!("{}-{}", size, q.suffix());
+                            ui.selectable_value(&mut self.tx_model, id.clone(), id);
+                        }
+                    }
                 });
             
+            if ui.button("浏览模型文件夹...").clicked() {
+                if let Some(path) = FileDialog::new().pick_folder() {
+                    self.tx_model_dir = Some(path.display().to_string());
+                }
+            }
+
             if ui.button("加载模型").clicked() {
                 let model_id = self.tx_model.clone();
+                let model_dir = self.tx_model_dir.clone();
+                let engine_config = self.tx_engine_config.clone();
+                let parallel_engines = self.tx_parallel_engines;
                 let tx = self.tx.clone();
                 let engine = self.engine.clone();
                 
-                self.log(&format!("正在加载模型: {} (可能需要几分钟下载)...", model_id));
+                match &model_dir {
+                    Some(dir) => self.log(&format!("正在从本地文件夹加载模型: {}", dir)),
+                    None if parse_model_id(&model_id).1.is_some() => self.log(&format!(
+                        "正在加载量化模型: {} (首次使用需下载并转换, 可能需要几分钟)...",
+                        model_id
+                    )),
+                    None => self.log(&format!("正在加载模型: {} (可能需要几分钟下载)...", model_id)),
+                }
                 
                 tokio::spawn(async move {
-                    match WhisperEngine::new(&model_id) {
-                        Ok(e) => {
+                    let load = || match &model_dir {
+                        Some(dir) => WhisperEngine::from_dir_with_config(dir, &engine_config),
+                        None => WhisperEngine::new_with_config(&model_id, &engine_config),
+                    };
+                    let downloaded = match &model_dir {
+                        Some(_) => Ok(()),
+                        None => ModelStore::from_env()
+                            .download(&model_id, |progress| {
+                                let _ = tx.send(AppMessage::Download(progress));
+                            })
+                            .map(|_| ()),
+                    };
+                    let loaded = downloaded.and_then(|_| -> anyhow::Result<(Box<dyn Transcriber>, String)> {
+                        if parallel_engines <= 1 {
+                            let e = load()?;
+                            let device = e.device_description();
+                            return Ok((Box::new(e), device));
+                        }
+                        // Clones share the weights, so the model is in memory once.
+                        let first = load()?;
+                        let engines = (0..parallel_engines).map(|_| first.clone()).collect();
+                        let parallel = ParallelTranscriber::new(engines, ChunkOptions::default())?;
+                        let device = parallel.device_description();
+                        Ok((Box::new(parallel), device))
+                    });
+                    match loaded {
+                        Ok((e, device)) => {
                             *engine.lock().await = Some(e);
-                            let _ = tx.send(AppMessage::ModelLoaded);
+                            let _ = tx.send(AppMessage::ModelLoaded(device));
                         },
                         Err(err) => {
-                            let _ = tx.send(AppMessage::Log(format!("加载模型失败: {}", err)));
+                            let _ = tx.send(AppMessage::ModelsChanged(format!("加载模型失败: {}", err)));
                         }
                     }
                 });
             }
         });
 
+        self.show_download_progress(ui);
+
+        if let Some(dir) = self.tx_model_dir.clone() {
+            ui.horizontal(|ui| {
+                ui.label(format!("本地模型: {}", dir));
+                if ui.button("改用在线模型").clicked() {
+                    self.tx_model_dir = None;
+                }
+            });
+        }
+
+        ui.horizontal(|ui| {
+            let config = &mut self.tx_engine_config;
+            let device_label = |device: DeviceChoice| match device {
+                DeviceChoice::Auto => "自动 (GPU 优先)".to_string(),
+                DeviceChoice::Cpu => "CPU".to_string(),
+                DeviceChoice::Cuda(i) => format!("CUDA {}", i),
+                DeviceChoice::Metal(i) => format!("Metal {}", i),
+            };
+            egui::ComboBox::from_label("设备")
+                .selected_text(device_label(config.device))
+                .show_ui(ui, |ui| {
+                    for device in [DeviceChoice::Auto, DeviceChoice::Cpu, DeviceChoice::Cuda(0), DeviceChoice::Metal(0)] {
+                        ui.selectable_value(&mut config.device, device, device_label(device));
+                    }
+                });
+            egui::ComboBox::from_label("精度")
+                .selected_text(config.precision.to_string())
+                .show_ui(ui, |ui| {
+                    for precision in Precision::ALL {
+                        ui.selectable_value(&mut config.precision, precision, precision.to_string());
+                    }
+                });
+            let mut limit_threads = config.threads.is_some();
+            ui.checkbox(&mut limit_threads, "限制 CPU 线程");
+            if limit_threads {
+                let mut threads = config.threads.unwrap_or(4);
+                ui.add(egui::DragValue::new(&mut threads).range(1..=256));
+                config.threads = Some(threads);
+            } else {
+                config.threads = None;
+            }
+            ui.label("编码批量");
+            ui.add(egui::DragValue::new(&mut config.encoder_batch_size).range(1..=16));
+            ui.label("并行引擎");
+            ui.add(egui::DragValue::new(&mut self.tx_parallel_engines).range(1..=8));
+        })
+        .response
+        .on_hover_text("重新加载模型后生效。不支持的精度 (如 CPU 上的 bf16) 会回退到 f32, 量化模型始终使用 f32。编码批量大于 1 时一次编码多个 30 秒窗口, 转写结果与批量 1 相同。并行引擎大于 1 时把长音频在静音处切成约 5 分钟的块, 由共用同一份模型权重的多个引擎同时转写。");
+
+        ui.horizontal(|ui| {
+            let language_text = match &self.tx_decoding.language {
+                None => "自动检测".to_string(),
+                Some(code) => language_name(code).unwrap_or(code).to_string(),
+            };
+            egui::ComboBox::from_label("语言")
+                .selected_text(language_text)
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.tx_decoding.language, None, "自动检测");
+                    for (code, name) in LANGUAGES {
+                        ui.selectable_value(&mut self.tx_decoding.language, Some(code.to_string()), format!("{} ({})", name, code));
+                    }
+                });
+
+            egui::ComboBox::from_label("任务")
+                .selected_text(match self.tx_decoding.task {
+                    Task::Transcribe => "转写 (原语言)",
+                    Task::Translate => "翻译为英文",
+                })
+                .show_ui(ui, |ui| {
+                    ui.selectable_value(&mut self.tx_decoding.task, Task::Transcribe, "转写 (原语言)");
+                    ui.selectable_value(&mut self.tx_decoding.task, Task::Translate, "翻译为英文");
+                });
+        });
+
+        ui.collapsing("解码参数", |ui| {
+            let decoding = &mut self.tx_decoding;
+            ui.horizontal(|ui| {
+                let mut use_beam = decoding.beam_size.is_some();
+                ui.checkbox(&mut use_beam, "束搜索 (Beam Search)");
+                if use_beam {
+                    let mut beam_size = decoding.beam_size.unwrap_or(5);
+                    ui.add(egui::DragValue::new(&mut beam_size).range(2..=10).prefix("宽度: "));
+                    ui.add(egui::DragValue::new(&mut decoding.patience).range(0.5..=3.0).speed(0.1).prefix("耐心: "));
+                    decoding.beam_size = Some(beam_size);
+                } else {
+                    decoding.beam_size = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                let mut fallback = decoding.temperatures.len() > 1;
+                ui.checkbox(&mut fallback, "温度回退 (0.0 → 1.0)");
+                decoding.temperatures = if fallback {
+                    DecodingOptions::default().temperatures
+                } else {
+                    vec![0.0]
+                };
+                ui.add(egui::DragValue::new(&mut decoding.best_of).range(1..=10).prefix("采样数: "));
+            });
+            ui.horizontal(|ui| {
+                let mut cr = decoding.compression_ratio_threshold.unwrap_or(2.4);
+                ui.add(egui::DragValue::new(&mut cr).range(1.0..=5.0).speed(0.1).prefix("压缩率阈值: "));
+                decoding.compression_ratio_threshold = Some(cr);
+                let mut lp = decoding.logprob_threshold.unwrap_or(-1.0);
+                ui.add(egui::DragValue::new(&mut lp).range(-5.0..=0.0).speed(0.1).prefix("对数概率阈值: "));
+                decoding.logprob_threshold = Some(lp);
+                let mut ns = decoding.no_speech_threshold.unwrap_or(0.6);
+                ui.add(egui::DragValue::new(&mut ns).range(0.0..=1.0).speed(0.05).prefix("无语音阈值: "));
+                decoding.no_speech_threshold = Some(ns);
+            });
+            ui.checkbox(&mut decoding.word_timestamps, "词级时间戳 (额外输出 .words.json)");
+            ui.checkbox(&mut self.tx_export_confidence, "置信度导出 (额外输出 .confidence.json 和 .ass, 低置信度字幕标黄)");
+            ui.checkbox(&mut decoding.condition_on_previous_text, "以前文作为上下文 (名称前后一致, 偶尔会重复)");
+            ui.horizontal(|ui| {
+                let mut use_vad = decoding.vad.is_some();
+                ui.checkbox(&mut use_vad, "语音活动检测 (跳过静音)");
+                if use_vad {
+                    let vad = decoding.vad.get_or_insert_with(VadOptions::default);
+                    ui.add(egui::DragValue::new(&mut vad.energy_threshold_db).range(3.0..=30.0).suffix(" dB").prefix("灵敏度: "));
+                    ui.add(egui::DragValue::new(&mut vad.min_speech_ms).range(50..=2000).suffix(" ms").prefix("最短语音: "));
+                    ui.add(egui::DragValue::new(&mut vad.min_silence_ms).range(100..=3000).suffix(" ms").prefix("最短静音: "));
+                    ui.add(egui::DragValue::new(&mut vad.padding_ms).range(0..=1000).suffix(" ms").prefix("前后留白: "));
+                } else {
+                    decoding.vad = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                let mut use_guard = decoding.guard.is_some();
+                ui.checkbox(&mut use_guard, "幻觉检测 (重复/语速异常)");
+                if use_guard {
+                    let guard = decoding.guard.get_or_insert_with(GuardOptions::default);
+                    ui.radio_value(&mut guard.action, GuardAction::Redecode, "重新解码");
+                    ui.radio_value(&mut guard.action, GuardAction::Drop, "删除");
+                    ui.add(egui::DragValue::new(&mut guard.max_repeats).range(2..=20).prefix("最多重复: "));
+                } else {
+                    decoding.guard = None;
+                }
+            });
+        });
+
+        ui.collapsing("音频预处理", |ui| {
+            ui.label("适用于嘈杂的外景录音或音量很小的领夹麦; 在识别前处理音频。");
+            let preprocess = &mut self.tx_decoding.preprocess;
+            ui.horizontal(|ui| {
+                let mut high_pass = preprocess.high_pass_hz.is_some();
+                ui.checkbox(&mut high_pass, "高通滤波 (去除低频隆隆声)");
+                if high_pass {
+                    let mut hz = preprocess.high_pass_hz.unwrap_or(80.0);
+                    ui.add(egui::DragValue::new(&mut hz).range(20.0..=300.0).suffix(" Hz").prefix("截止: "));
+                    preprocess.high_pass_hz = Some(hz);
+                } else {
+                    preprocess.high_pass_hz = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                let mut denoise = preprocess.noise_reduction.is_some();
+                ui.checkbox(&mut denoise, "降噪 (去除持续的底噪、嘶声)");
+                if denoise {
+                    let nr = preprocess.noise_reduction.get_or_insert_with(NoiseReduction::default);
+                    ui.add(egui::DragValue::new(&mut nr.strength).range(0.5..=4.0).speed(0.1).prefix("强度: "));
+                    ui.add(egui::DragValue::new(&mut nr.floor_db).range(-40.0..=-3.0).suffix(" dB").prefix("最低增益: "));
+                } else {
+                    preprocess.noise_reduction = None;
+                }
+            });
+            ui.horizontal(|ui| {
+                let mut normalize = preprocess.normalization.is_some();
+                ui.checkbox(&mut normalize, "音量标准化");
+                if normalize {
+                    let normalization = preprocess.normalization.get_or_insert_with(Normalization::loudness);
+                    let is_peak = matches!(normalization, Normalization::Peak { .. });
+                    if ui.radio(!is_peak, "响度").clicked() {
+                        *normalization = Normalization::loudness();
+                    }
+                    if ui.radio(is_peak, "峰值").clicked() {
+                        *normalization = Normalization::peak();
+                    }
+                    match normalization {
+                        Normalization::Peak { target_db } | Normalization::Loudness { target_db, .. } => {
+                            ui.add(egui::DragValue::new(target_db).range(-40.0..=0.0).suffix(" dBFS").prefix("目标: "));
+                        }
+                    }
+                } else {
+                    preprocess.normalization = None;
+                }
+            });
+        });
+
+        ui.collapsing("术语表 (热词)", |ui| {
+            ui.label("产品名、专有名词等, 每行一个或用逗号分隔; 每段识别都会作为提示词传给模型。");
+            let response = ui.add(
+                egui::TextEdit::multiline(&mut self.settings.glossary)
+                    .desired_rows(3)
+                    .hint_text("例如: DaVinci Resolve, 剪映, Kubernetes"),
+            );
+            if response.changed() {
+                if let Err(e) = self.settings.save() {
+                    self.log(&format!("保存术语表失败: {}", e));
+                }
+            }
+        });
+
         ui.horizontal(|ui| {
             ui.label("输出目录:");
             ui.text_edit_singleline(&mut self.tx_output_dir);
@@ -235,75 +576,224 @@ impl WhisperApp {
                     }
                 }
             }
-            if ui.button("清空列表").clicked() {
+            if ui.add_enabled(!self.is_transcribing, egui::Button::new("清空列表")).clicked() {
                 self.tx_files.clear();
+                self.tx_status.clear();
             }
         });
 
+        self.tx_status.resize(self.tx_files.len(), FileStatus::Queued);
         egui::ScrollArea::vertical().max_height(300.0).show(ui, |ui| {
-            for f in &self.tx_files {
-                ui.label(f);
-            }
+            egui::Grid::new("tx_files").num_columns(2).striped(true).show(ui, |ui| {
+                for (file, status) in self.tx_files.iter().zip(&self.tx_status) {
+                    ui.label(file);
+                    match status {
+                        FileStatus::Queued => ui.label("等待"),
+                        FileStatus::Running(None) => ui.label("⏳ 转写中"),
+                        FileStatus::Running(Some(progress)) => ui.add(
+                            egui::ProgressBar::new(progress.percent() / 100.0)
+                                .desired_width(200.0)
+                                .text(format!("第 {} 个窗口 · {:.0}%", progress.window, progress.percent())),
+                        ),
+                        FileStatus::Done => ui.colored_label(egui::Color32::GREEN, "✔ 完成"),
+                        FileStatus::Failed(error) => ui.colored_label(egui::Color32::RED, "✖ 失败").on_hover_text(error),
+                    };
+                    ui.end_row();
+                }
+            });
         });
 
-        ui.separator();
-        if ui.button(if self.is_transcribing { "⏳ 转写中..." } else { "▶️ 开始转写" }).clicked() {
-            if !self.is_transcribing {
-                if self.tx_files.is_empty() {
-                    self.log("未选择文件!");
-                    return;
-                }
-                
-                self.is_transcribing = true;
-                self.log("开始转写队列...");
-                
+        ui.horizontal(|ui| {
+            ui.label("音轨:");
+            let only = matches!(self.tx_tracks, TrackSelection::Only(_));
+            if ui.radio(self.tx_tracks == TrackSelection::Default, "默认").clicked() {
+                self.tx_tracks = TrackSelection::Default;
+            }
+            if ui.radio(self.tx_tracks == TrackSelection::All, "全部").clicked() {
+                self.tx_tracks = TrackSelection::All;
+            }
+            if ui.radio(only, "指定").clicked() || only {
+                ui.add(egui::TextEdit::singleline(&mut self.tx_track_list).desired_width(80.0).hint_text("如 1,3"));
+                let indices = self
+                    .tx_track_list
+                    .split(|c: char| c == ',' || c.is_whitespace())
+                    .filter_map(|n| n.trim().parse::<usize>().ok())
+                    .filter(|&n| n >= 1)
+                    .map(|n| n - 1)
+                    .collect();
+                self.tx_tracks = TrackSelection::Only(indices);
+            }
+            if self.tx_tracks != TrackSelection::Default {
+                ui.checkbox(&mut self.tx_merge_tracks, "合并为一个字幕 (按音轨标注)");
+            }
+            if ui.button("查看音轨").clicked() {
                 let files = self.tx_files.clone();
-                let engine = self.engine.clone();
                 let tx = self.tx.clone();
-                let output_dir = self.tx_output_dir.clone();
-                
                 tokio::spawn(async move {
-                    let mut guard = engine.lock().await;
-                    if let Some(engine) = guard.as_mut() {
-                        for file in files {
-                            let _ = tx.send(AppMessage::Log(format!("正在处理: {}", file)));
-                            match engine.transcribe(&file) {
-                                Ok(segments) => {
-                                    let mut srt_content = String::new();
-                                    for (i, (start, end, text)) in segments.iter().enumerate() {
-                                        srt_content.push_str(&format!(
-                                            "{}\n{} --> {}\n{}\n\n",
-                                            i + 1,
-                                            seconds_to_time_str(*start),
-                                            seconds_to_time_str(*end),
-                                            text.trim()
-                                        ));
-                                    }
-                                    
-                                    let input_path = Path::new(&file);
-                                    let file_stem = input_path.file_stem().unwrap().to_string_lossy();
-                                    let output_path = Path::new(&output_dir).join(format!("{}.srt", file_stem));
-                                    
-                                    if let Err(e) = fs::write(&output_path, srt_content) {
-                                         let _ = tx.send(AppMessage::Log(format!("保存 SRT 失败: {}", e)));
-                                    } else {
-                                         let _ = tx.send(AppMessage::Log(format!("SRT 已保存至: {}", output_path.display())));
-                                    }
-                                }
-                                Err(e) => {
-                                    let _ = tx.send(AppMessage::Log(format!("处理失败 {}: {}", file, e)));
-                                }
+                    for file in files {
+                        let message = match list_audio_tracks(&file) {
+                            Ok(tracks) => {
+                                let tracks: Vec<String> = tracks.iter().map(|t| t.to_string()).collect();
+                                format!("{} 的音轨: {}", file, tracks.join(" | "))
                             }
-                        }
-                        let _ = tx.send(AppMessage::TranscriptionDone("所有文件处理完毕。".to_string()));
-                    } else {
-                        let _ = tx.send(AppMessage::TranscriptionDone("错误: 模型未加载! 请先点击加载模型。".to_string()));
+                            Err(e) => format!("读取音轨失败 {}: {}", file, e),
+                        };
+                        let _ = tx.send(AppMessage::Log(message));
                     }
                 });
             }
+        });
+
+        ui.separator();
+        let failed: Vec<usize> = (0..self.tx_status.len()).filter(|&i| matches!(self.tx_status[i], FileStatus::Failed(_))).collect();
+        let mut start = None;
+        ui.horizontal(|ui| {
+            if ui.button(if self.is_transcribing { "⏳ 转写中..." } else { "▶️ 开始转写" }).clicked() && !self.is_transcribing {
+                start = Some((0..self.tx_files.len()).collect());
+            }
+            if !self.is_transcribing && !failed.is_empty() && ui.button(format!("🔁 重试失败项 ({})", failed.len())).clicked() {
+                start = Some(failed.clone());
+            }
+            if self.is_transcribing && ui.button("⏹ 取消").clicked() {
+                self.tx_cancel.cancel();
+            }
+            ui.label("同时处理文件数");
+            ui.add_enabled(!self.is_transcribing, egui::DragValue::new(&mut self.tx_workers).range(1..=8))
+                .on_hover_text("多个文件同时转写, 共用已加载模型的权重; 每多一个文件只多占用解码所需的内存。");
+        });
+        if self.is_transcribing {
+            let finished = self.tx_status.iter().filter(|s| matches!(s, FileStatus::Done | FileStatus::Failed(_))).count();
+            let running = self.tx_status.iter().filter(|s| matches!(s, FileStatus::Running(_))).count();
+            let total = self.tx_status.len().max(1);
+            ui.add(egui::ProgressBar::new(finished as f32 / total as f32).text(format!(
+                "已结束 {}/{} · 正在转写 {} 个",
+                finished,
+                self.tx_status.len(),
+                running
+            )));
+        } else if let Some(summary) = &self.tx_summary {
+            ui.label(summary);
+        }
+        if let Some(indices) = start {
+            self.start_batch(indices);
         }
     }
 
+    /// Transcribe the files of `tx_files` at `indices`, `tx_workers` at a time.
+    fn start_batch(&mut self, indices: Vec<usize>) {
+        if indices.is_empty() {
+            self.log("未选择文件!");
+            return;
+        }
+
+        self.is_transcribing = true;
+        self.tx_summary = None;
+        self.log("开始转写队列...");
+        self.tx_cancel = CancellationToken::default();
+        for &i in &indices {
+            self.tx_status[i] = FileStatus::Queued;
+        }
+
+        let cancel = self.tx_cancel.clone();
+        let files: Vec<String> = indices.iter().map(|&i| self.tx_files[i].clone()).collect();
+        let workers = self.tx_workers;
+        let engine = self.engine.clone();
+        let tx = self.tx.clone();
+        let export_confidence = self.tx_export_confidence;
+        let batch = BatchOptions {
+            export_confidence,
+            tracks: self.tx_tracks.clone(),
+            merge_tracks: self.tx_merge_tracks,
+            ..BatchOptions::new(&self.tx_output_dir)
+        };
+        let mut options = self.tx_decoding.clone();
+        options.hotwords = self.settings.hotwords();
+
+        tokio::spawn(async move {
+            let started = std::time::Instant::now();
+            let mut guard = engine.lock().await;
+            let Some(engine) = guard.as_mut() else {
+                let _ = tx.send(AppMessage::TranscriptionDone("错误: 模型未加载! 请先点击加载模型。".to_string()));
+                return;
+            };
+            // The other workers share the loaded model's weights.
+            let mut shared: Vec<Box<dyn Transcriber>> = (1..workers.min(files.len())).map_while(|_| engine.share()).collect();
+            let mut transcribers: Vec<&mut dyn Transcriber> = vec![engine.as_mut()];
+            transcribers.extend(shared.iter_mut().map(|t| t.as_mut() as &mut dyn Transcriber));
+            let thresholds = batch.confidence.clone();
+            let summary = run_batch_parallel(&mut transcribers, &files, &options, &batch, &cancel, |event| {
+                if let Some(status) = event.status() {
+                    let _ = tx.send(AppMessage::FileStatus(indices[event.index()], status));
+                }
+                let message = match event {
+                    BatchEvent::Started { file, .. } => format!("正在处理: {}", file),
+                    BatchEvent::Progress { .. } | BatchEvent::Completed { .. } | BatchEvent::Cancelled { .. } => return,
+                    BatchEvent::Transcribed { track, transcription, .. } => {
+                        for flagged in &transcription.flagged {
+                            let issues: Vec<String> = flagged.issues.iter().map(|i| i.to_string()).collect();
+                            let outcome = match &flagged.replacement {
+                                Some(text) => format!("已重新解码: {}", text.trim()),
+                                None => "已删除".to_string(),
+                            };
+                            let _ = tx.send(AppMessage::Log(format!(
+                                "可疑片段 [{} --> {}] {} ({}) → {}",
+                                seconds_to_time_str(flagged.start),
+                                seconds_to_time_str(flagged.end),
+                                flagged.text.trim(),
+                                issues.join(", "),
+                                outcome
+                            )));
+                        }
+                        let mut message = track.map(|t| format!("音轨 {}: ", t)).unwrap_or_default();
+                        message.push_str(&format!(
+                            "语言: {} ({:.0}%)",
+                            language_name(&transcription.language).unwrap_or(&transcription.language),
+                            transcription.language_probability * 100.0
+                        ));
+                        if let Some(decoder) = transcription.audio_decoder {
+                            message.push_str(&format!(", 解码器: {}", decoder));
+                        }
+                        if export_confidence {
+                            let uncertain = transcription.detailed.iter().filter(|s| thresholds.is_low_confidence(s)).count();
+                            message.push_str(&format!(", {} 条低置信度字幕", uncertain));
+                        }
+                        message
+                    }
+                    BatchEvent::Written { path, .. } => format!("已保存至: {}", path.display()),
+                    BatchEvent::WriteFailed { path, error, .. } => format!("保存失败 {}: {}", path.display(), error),
+                    BatchEvent::Failed { index, error } => format!("处理失败 {}: {}", files[index], error),
+                };
+                let _ = tx.send(AppMessage::Log(message));
+            });
+
+            let elapsed = started.elapsed().as_secs();
+            let mut done = if summary.cancelled {
+                "转写已取消, 未完成的文件没有输出。".to_string()
+            } else {
+                "所有文件处理完毕。".to_string()
+            };
+            done.push_str(&format!(
+                " 成功 {} 个, 失败 {} 个",
+                summary.completed.len(),
+                summary.failed.len()
+            ));
+            let unfinished = files.len() - summary.completed.len() - summary.failed.len();
+            if unfinished > 0 {
+                done.push_str(&format!(", 未完成 {} 个", unfinished));
+            }
+            done.push_str(&format!(", 用时 {}:{:02}。", elapsed / 60, elapsed % 60));
+            if !summary.failed.is_empty() {
+                let names: Vec<String> = summary
+                    .failed
+                    .iter()
+                    .map(|f| Path::new(f).file_name().unwrap_or_default().to_string_lossy().to_string())
+                    .collect();
+                done.push_str(&format!(" 失败的文件: {} (可点击“重试失败项”)", names.join(", ")));
+            }
+            let _ = tx.send(AppMessage::TranscriptionDone(done));
+        });
+    }
+
     fn show_translation(&mut self, ui: &mut egui::Ui) {
         ui.heading("字幕翻译 (AI)");
         ui.separator();
@@ -350,7 +840,7 @@ impl WhisperApp {
                     match client.translate(&content, &lang).await {
                          Ok(translated) => {
                              let out_path = file.replace(".srt", &format!("_{}.srt", lang));
-                             if let Ok(_) = fs::write(&out_path, translated) {
+                             if fs::write(&out_path, translated).is_ok() {
                                   let _ = tx.send(AppMessage::Log(format!("翻译保存至: {}", out_path)));
                              } else {
                                   let _ = tx.send(AppMessage::Log("保存失败".to_string()));
@@ -408,7 +898,7 @@ impl WhisperApp {
                      match client.generate_storyboard(&content).await {
                          Ok(res) => {
                              let out_path = file.replace(".srt", "_storyboard.txt").replace(".txt", "_storyboard.txt");
-                             if let Ok(_) = fs::write(&out_path, res) {
+                             if fs::write(&out_path, res).is_ok() {
                                   let _ = tx.send(AppMessage::Log(format!("分镜已保存: {}", out_path)));
                              }
                          }
@@ -421,6 +911,140 @@ impl WhisperApp {
         }
     }
 
+    fn show_models(&mut self, ui: &mut egui::Ui) {
+        ui.heading("模型管理");
+        let store = ModelStore::from_env();
+        ui.label(format!("缓存目录: {}", store.local_dir().parent().unwrap_or(Path::new("")).display()));
+        ui.separator();
+
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_label("")
+                .selected_text(&self.model_download_id)
+                .show_ui(ui, |ui| {
+                    for size in ["tiny", "base", "small", "medium", "large"] {
+                        ui.selectable_value(&mut self.model_download_id, size.to_string(), size);
+                    }
+                });
+            let downloading = self.model_download.is_some();
+            if ui.add_enabled(!downloading, egui::Button::new("⬇ 预下载")).clicked() {
+                let model_id = self.model_download_id.clone();
+                let tx = self.tx.clone();
+                self.log(&format!("正在下载模型: {}", model_id));
+                self.model_download = Some(DownloadProgress { file: String::new(), downloaded: 0, total: 0 });
+                tokio::spawn(async move {
+                    let result = store.download(&model_id, |progress| {
+                        let _ = tx.send(AppMessage::Download(progress));
+                    });
+                    let _ = tx.send(AppMessage::ModelsChanged(match result {
+                        Ok(model) => format!("模型已下载: {} ({})", model.id, format_size(model.size)),
+                        Err(e) => format!("下载模型失败: {}", e),
+                    }));
+                });
+            }
+            if ui.button("📂 导入模型文件夹...").clicked() {
+                if let Some(path) = FileDialog::new().pick_folder() {
+                    let store = ModelStore::from_env();
+                    let tx = self.tx.clone();
+                    self.log(&format!("正在导入模型: {}", path.display()));
+                    tokio::spawn(async move {
+                        let _ = tx.send(AppMessage::ModelsChanged(match store.import(&path) {
+                            Ok(model) => format!("模型已导入: {} ({})", model.id, format_size(model.size)),
+                            Err(e) => format!("导入模型失败: {}", e),
+                        }));
+                    });
+                }
+            }
+            if ui.button("🔄 刷新").clicked() {
+                self.models = None;
+            }
+        });
+        self.show_download_progress(ui);
+        ui.separator();
+
+        if self.models.is_none() {
+            match ModelStore::from_env().list() {
+                Ok(models) => self.models = Some(models),
+                Err(e) => {
+                    self.log(&format!("读取模型列表失败: {}", e));
+                    self.models = Some(Vec::new());
+                }
+            }
+        }
+        let models = self.models.clone().unwrap_or_default();
+        if models.is_empty() {
+            ui.label("本地还没有模型。");
+            return;
+        }
+
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            egui::Grid::new("models").striped(true).num_columns(4).show(ui, |ui| {
+                for model in models {
+                    ui.label(&model.id);
+                    ui.label(match model.source {
+                        ModelSource::Hub => "Hugging Face",
+                        ModelSource::Local => "本地",
+                    });
+                    ui.label(format_size(model.size));
+                    ui.horizontal(|ui| {
+                        if ui.button("使用").on_hover_text(model.dir.display().to_string()).clicked() {
+                            self.tx_model_dir = Some(model.dir.display().to_string());
+                            self.selected_tab = Tab::Transcription;
+                            self.log(&format!("已选择模型 {}, 请点击加载模型。", model.id));
+                        }
+                        if ui.button("校验").clicked() {
+                            let tx = self.tx.clone();
+                            self.log(&format!("正在校验模型: {} ...", model.id));
+                            let model = model.clone();
+                            tokio::spawn(async move {
+                                let message = match ModelStore::from_env().verify(&model) {
+                                    Ok(problems) if problems.is_empty() => format!("模型 {} 校验通过。", model.id),
+                                    Ok(problems) => {
+                                        let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+                                        format!("模型 {} 已损坏, 建议删除后重新下载: {}", model.id, problems.join("; "))
+                                    }
+                                    Err(e) => format!("校验模型 {} 失败: {}", model.id, e),
+                                };
+                                let _ = tx.send(AppMessage::Log(message));
+                            });
+                        }
+                        if self.model_pending_delete.as_ref() == Some(&model.path) {
+                            if ui.button(egui::RichText::new("确认删除").color(egui::Color32::RED)).clicked() {
+                                self.model_pending_delete = None;
+                                let message = match ModelStore::from_env().delete(&model) {
+                                    Ok(()) => format!("已删除模型: {}", model.id),
+                                    Err(e) => format!("删除模型失败: {}", e),
+                                };
+                                let _ = self.tx.send(AppMessage::ModelsChanged(message));
+                            }
+                            if ui.button("取消").clicked() {
+                                self.model_pending_delete = None;
+                            }
+                        } else if ui.button("删除").clicked() {
+                            self.model_pending_delete = Some(model.path.clone());
+                        }
+                    });
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    fn show_download_progress(&self, ui: &mut egui::Ui) {
+        if let Some(progress) = &self.model_download {
+            let text = if progress.total == 0 {
+                "正在连接 Hugging Face...".to_string()
+            } else {
+                format!(
+                    "下载 {}: {} / {}",
+                    progress.file,
+                    format_size(progress.downloaded),
+                    format_size(progress.total)
+                )
+            };
+            ui.add(egui::ProgressBar::new(progress.fraction()).text(text));
+        }
+    }
+
     fn show_logs(&mut self, ui: &mut egui::Ui) {
         egui::ScrollArea::vertical().show(ui, |ui| {
             for log in &self.logs {
@@ -437,7 +1061,12 @@ impl WhisperApp {
             ui.label(egui::RichText::new("1. 🎤 语音转字幕 (Transcription)").strong());
             ui.label("   - **步骤**: 选择模型 -> 加载模型 -> 添加音频/视频 -> 开始转写。");
             ui.label("   - **模型**: 推荐使用 Small。第一次加载会自动下载。");
+            ui.label("   - **量化**: 带 q8_0/q5_0/q4_0 后缀的模型更小更快, 适合没有显卡的电脑; 本地文件夹也可以放 .gguf 模型。");
+            ui.label("   - **离线**: 点击“浏览模型文件夹”选择包含 config.json、tokenizer.json、model.safetensors 的本地文件夹。");
             ui.label("   - **输出**: 默认输出到与输入文件同名的 .srt 文件。");
+            ui.label("   - **音轨**: 多音轨视频可点击“查看音轨”列出语言和编码, 选择全部或指定音轨; 每个音轨单独输出 .trackN.srt, 或合并为一个按音轨标注的字幕。");
+            ui.label("   - **格式**: MKV/WebM/AVI 等内置解码器读不了的文件会自动改用 ffmpeg 解码 (需已安装 ffmpeg)。");
+            ui.label("   - **长音频**: “并行引擎”设为 2 以上并重新加载模型, 长录音会在静音处切块、由多个引擎同时转写后按时间拼接; “同时处理文件数”则让多个文件同时转写, 失败的文件可一键重试。");
             ui.add_space(10.0);
             
             ui.label(egui::RichText::new("2. 🌐 字幕翻译 (Translation)").strong());
@@ -450,7 +1079,7 @@ impl WhisperApp {
             ui.add_space(10.0);
             
             ui.label(egui::RichText::new("⚠️ 注意事项").color(egui::Color32::RED));
-            ui.label("   - 模型文件保存在 ~/.cache/huggingface/hub 下，较大。");
+            ui.label("   - 模型文件保存在 ~/.cache/huggingface/hub 下，较大; 可在“📦 模型”页查看大小、校验、删除或导入。");
             ui.label("   - AI 功能依赖网络连接。");
         });
     }
@@ -0,0 +1,535 @@
+use anyhow::{Context, Result};
+use candle_core::quantized::gguf_file;
+use candle_transformers::models::whisper::Config;
+use hf_hub::api::sync::ApiBuilder;
+use hf_hub::{Cache, Repo, RepoType};
+use sha1::{Digest, Sha1};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokenizers::Tokenizer;
+
+use crate::quantize::parse_model_id;
+use crate::whisper_engine::find_weights;
+
+const HUB_PREFIX: &str = "models--openai--whisper-";
+/// Files `download` fetches; the last one is optional.
+const HUB_FILES: [&str; 4] = ["config.json", "tokenizer.json", "model.safetensors", "generation_config.json"];
+
+/// Where a model on disk came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelSource {
+    /// `openai/whisper-*` in the Hugging Face cache.
+    Hub,
+    /// A folder of our own: a GGUF conversion or an imported model.
+    Local,
+}
+
+#[derive(Debug, Clone)]
+pub struct LocalModel {
+    /// Model id (`small`, `small-q5_0`), or the folder name of an imported model.
+    pub id: String,
+    pub source: ModelSource,
+    /// Folder with the model files, loadable with `WhisperEngine::from_dir`.
+    pub dir: PathBuf,
+    /// Everything the model occupies; what `delete` removes.
+    pub path: PathBuf,
+    /// Bytes on disk.
+    pub size: u64,
+}
+
+/// Something wrong with a model's files.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    Missing(String),
+    /// The content does not match the checksum the hub recorded for it.
+    Corrupt(String),
+    Invalid { file: String, reason: String },
+}
+
+impl std::fmt::Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Problem::Missing(file) => write!(f, "{} is missing", file),
+            Problem::Corrupt(file) => write!(f, "{} does not match its checksum", file),
+            Problem::Invalid { file, reason } => write!(f, "{}: {}", file, reason),
+        }
+    }
+}
+
+/// One step of `ModelStore::download`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DownloadProgress {
+    pub file: String,
+    pub downloaded: u64,
+    pub total: u64,
+}
+
+impl DownloadProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            1.0
+        } else {
+            (self.downloaded as f64 / self.total as f64).min(1.0) as f32
+        }
+    }
+}
+
+/// The Whisper models in the Hugging Face cache and our folder next to it.
+#[derive(Debug, Clone)]
+pub struct ModelStore {
+    cache_dir: PathBuf,
+}
+
+impl ModelStore {
+    /// The cache `hf_hub` uses (`HF_HOME`, or `~/.cache/huggingface/hub`).
+    pub fn from_env() -> Self {
+        Self::new(Cache::from_env().path().clone())
+    }
+
+    pub fn new(cache_dir: impl Into<PathBuf>) -> Self {
+        Self { cache_dir: cache_dir.into() }
+    }
+
+    /// Folder for GGUF conversions and imported models.
+    pub fn local_dir(&self) -> PathBuf {
+        self.cache_dir.join("whisper-rust-tools")
+    }
+
+    /// Hub models first, then local ones, each sorted by id.
+    pub fn list(&self) -> Result<Vec<LocalModel>> {
+        let mut models = Vec::new();
+        for (root, source) in [(self.cache_dir.clone(), ModelSource::Hub), (self.local_dir(), ModelSource::Local)] {
+            let Ok(entries) = std::fs::read_dir(&root) else { continue };
+            for entry in entries {
+                let path = entry?.path();
+                let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+                if !path.is_dir() || name.ends_with(".part") {
+                    continue;
+                }
+                let model = match source {
+                    ModelSource::Hub => match name.strip_prefix(HUB_PREFIX) {
+                        Some(id) => hub_model(id, &path)?,
+                        None => continue,
+                    },
+                    ModelSource::Local => local_model(&path)?,
+                };
+                models.push(model);
+            }
+        }
+        models.sort_by(|a, b| (a.source == ModelSource::Local, &a.id).cmp(&(b.source == ModelSource::Local, &b.id)));
+        Ok(models)
+    }
+
+    /// Check that `model` has every file it needs, that they parse, and for
+    /// hub models that each file matches the checksum it was downloaded with.
+    /// Reads the whole model, so it takes a few seconds for the large ones.
+    pub fn verify(&self, model: &LocalModel) -> Result<Vec<Problem>> {
+        let mut problems = Vec::new();
+        if model.source == ModelSource::Hub {
+            problems.extend(verify_checksums(&model.dir)?);
+        }
+        for problem in verify_dir(&model.dir) {
+            if !problems.contains(&problem) {
+                problems.push(problem);
+            }
+        }
+        Ok(problems)
+    }
+
+    pub fn delete(&self, model: &LocalModel) -> Result<()> {
+        if !model.path.starts_with(&self.cache_dir) {
+            anyhow::bail!("{} is not in the model cache", model.path.display());
+        }
+        std::fs::remove_dir_all(&model.path).with_context(|| format!("deleting {}", model.path.display()))
+    }
+
+    /// Copy a model folder, e.g. one copied from another machine, into the
+    /// local folder. It is checked first; the folder name becomes the id.
+    pub fn import(&self, src: &Path) -> Result<LocalModel> {
+        let problems = verify_dir(src);
+        if !problems.is_empty() {
+            let problems: Vec<String> = problems.iter().map(|p| p.to_string()).collect();
+            anyhow::bail!("{} is not a usable model: {}", src.display(), problems.join("; "));
+        }
+        let name = src.file_name().context("model folder has no name")?.to_string_lossy();
+        let id = name.strip_prefix("whisper-").unwrap_or(&name);
+        let dst = self.local_dir().join(format!("whisper-{}", id));
+        if dst.exists() {
+            anyhow::bail!("a model named {} already exists", id);
+        }
+
+        // Copy into a `.part` folder first so an interrupted import is never listed.
+        let tmp = PathBuf::from(format!("{}.part", dst.display()));
+        let _ = std::fs::remove_dir_all(&tmp);
+        std::fs::create_dir_all(&tmp)?;
+        for entry in std::fs::read_dir(src)? {
+            let path = entry?.path();
+            if path.is_file() {
+                std::fs::copy(&path, tmp.join(path.file_name().unwrap_or_default()))
+                    .with_context(|| format!("copying {}", path.display()))?;
+            }
+        }
+        std::fs::rename(&tmp, &dst)?;
+        local_model(&dst)
+    }
+
+    /// Fetch `openai/whisper-{model_id}` into the cache, reporting progress for
+    /// every file that is not there yet. Quantized ids fetch their base model;
+    /// the conversion happens when the model is first loaded.
+    pub fn download(&self, model_id: &str, mut on_progress: impl FnMut(DownloadProgress)) -> Result<LocalModel> {
+        let (base_id, _) = parse_model_id(model_id);
+        let repo = Repo::new(format!("openai/whisper-{}", base_id), RepoType::Model);
+        let cache = Cache::new(self.cache_dir.clone());
+        let api = ApiBuilder::from_cache(cache.clone()).with_progress(false).build()?;
+        let api_repo = api.repo(repo.clone());
+        for (i, file) in HUB_FILES.into_iter().enumerate() {
+            if cache.repo(repo.clone()).get(file).is_some() {
+                continue;
+            }
+            let reporter = Reporter { file: file.to_string(), downloaded: 0, total: 0, reported: 0, on_progress: &mut on_progress };
+            match api_repo.download_with_progress(file, reporter) {
+                Ok(_) => {}
+                Err(_) if i == HUB_FILES.len() - 1 => {}
+                Err(e) => return Err(e).with_context(|| format!("downloading {} of whisper-{}", file, base_id)),
+            }
+        }
+        hub_model(base_id, &self.cache_dir.join(format!("{}{}", HUB_PREFIX, base_id)))
+    }
+}
+
+/// Forwards `hf_hub` progress at most once per MiB.
+struct Reporter<'a, F> {
+    file: String,
+    downloaded: u64,
+    total: u64,
+    reported: u64,
+    on_progress: &'a mut F,
+}
+
+impl<F: FnMut(DownloadProgress)> Reporter<'_, F> {
+    fn report(&mut self) {
+        self.reported = self.downloaded;
+        (self.on_progress)(DownloadProgress { file: self.file.clone(), downloaded: self.downloaded, total: self.total });
+    }
+}
+
+impl<F: FnMut(DownloadProgress)> hf_hub::api::Progress for Reporter<'_, F> {
+    fn init(&mut self, size: usize, _filename: &str) {
+        self.total = size as u64;
+        self.report();
+    }
+
+    fn update(&mut self, size: usize) {
+        self.downloaded += size as u64;
+        if self.downloaded - self.reported >= 1 << 20 {
+            self.report();
+        }
+    }
+
+    fn finish(&mut self) {
+        self.downloaded = self.total;
+        self.report();
+    }
+}
+
+fn hub_model(id: &str, repo_dir: &Path) -> Result<LocalModel> {
+    // The snapshot `refs/main` points to, else any snapshot (a download cut short).
+    let snapshots = repo_dir.join("snapshots");
+    let dir = match std::fs::read_to_string(repo_dir.join("refs").join("main")) {
+        Ok(commit) => snapshots.join(commit.trim()),
+        Err(_) => std::fs::read_dir(&snapshots)
+            .ok()
+            .and_then(|mut entries| entries.next())
+            .and_then(|entry| entry.ok())
+            .map_or(snapshots, |entry| entry.path()),
+    };
+    Ok(LocalModel {
+        id: id.to_string(),
+        source: ModelSource::Hub,
+        dir,
+        path: repo_dir.to_path_buf(),
+        size: dir_size(repo_dir)?,
+    })
+}
+
+fn local_model(dir: &Path) -> Result<LocalModel> {
+    let name = dir.file_name().unwrap_or_default().to_string_lossy();
+    Ok(LocalModel {
+        id: name.strip_prefix("whisper-").unwrap_or(&name).to_string(),
+        source: ModelSource::Local,
+        dir: dir.to_path_buf(),
+        path: dir.to_path_buf(),
+        size: dir_size(dir)?,
+    })
+}
+
+/// Total size of the regular files under `dir`; symlinks (hub snapshots
+/// point into `blobs`) are not followed, so nothing is counted twice.
+fn dir_size(dir: &Path) -> Result<u64> {
+    let mut size = 0;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        if metadata.is_dir() {
+            size += dir_size(&entry.path())?;
+        } else if metadata.is_file() {
+            size += metadata.len();
+        }
+    }
+    Ok(size)
+}
+
+/// Check the files of a model folder the way `WhisperEngine::from_dir` would
+/// load them, without loading the weights.
+pub fn verify_dir(dir: &Path) -> Vec<Problem> {
+    let mut problems = Vec::new();
+    let invalid = |file: &str, reason: String| Problem::Invalid { file: file.to_string(), reason };
+    if !dir.is_dir() {
+        return vec![Problem::Missing(dir.display().to_string())];
+    }
+
+    match std::fs::read_to_string(dir.join("config.json")) {
+        Err(_) => problems.push(Problem::Missing("config.json".into())),
+        Ok(json) => {
+            if let Err(e) = serde_json::from_str::<Config>(&json) {
+                problems.push(invalid("config.json", e.to_string()));
+            }
+        }
+    }
+    if !dir.join("tokenizer.json").is_file() {
+        problems.push(Problem::Missing("tokenizer.json".into()));
+    } else if let Err(e) = Tokenizer::from_file(dir.join("tokenizer.json")) {
+        problems.push(invalid("tokenizer.json", e.to_string()));
+    }
+
+    match find_weights(dir) {
+        Ok(Some(weights)) => {
+            let name = weights.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let checked = if weights.extension().is_some_and(|ext| ext == "gguf") {
+                check_gguf(&weights)
+            } else {
+                check_safetensors(&weights)
+            };
+            if let Err(e) = checked {
+                problems.push(invalid(&name, e.to_string()));
+            }
+        }
+        Ok(None) => problems.push(Problem::Missing("model.safetensors (or a .gguf file)".into())),
+        Err(e) => problems.push(invalid("weights", e.to_string())),
+    }
+    problems
+}
+
+/// The header parses and the file is exactly as long as the tensors it lists.
+fn check_safetensors(path: &Path) -> Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let mut header_len = [0u8; 8];
+    file.read_exact(&mut header_len).context("file is truncated")?;
+    let header_len = u64::from_le_bytes(header_len);
+    if header_len > len - 8 {
+        anyhow::bail!("file is truncated");
+    }
+    let mut header = vec![0u8; header_len as usize];
+    file.read_exact(&mut header)?;
+    let header: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&header).context("bad header")?;
+    let data_len = header
+        .iter()
+        .filter(|(name, _)| *name != "__metadata__")
+        .filter_map(|(_, info)| info.get("data_offsets")?.get(1)?.as_u64())
+        .max()
+        .unwrap_or(0);
+    let expected = 8 + header_len + data_len;
+    if len != expected {
+        anyhow::bail!("expected {} bytes, found {}", expected, len);
+    }
+    Ok(())
+}
+
+/// The header parses and every tensor lies within the file.
+fn check_gguf(path: &Path) -> Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let len = file.metadata()?.len();
+    let content = gguf_file::Content::read(&mut file).context("bad header")?;
+    for (name, info) in &content.tensor_infos {
+        let bytes = info.shape.elem_count() / info.ggml_dtype.block_size() * info.ggml_dtype.type_size();
+        if content.tensor_data_offset + info.offset + bytes as u64 > len {
+            anyhow::bail!("file is truncated (tensor {})", name);
+        }
+    }
+    Ok(())
+}
+
+/// Compare every file of a hub snapshot with its blob name, which is the
+/// file's etag: the sha256 for LFS files, the git blob id for small ones.
+/// Snapshots without symlinks (hf_hub moves files where it cannot link) have
+/// nothing to compare against.
+fn verify_checksums(snapshot: &Path) -> Result<Vec<Problem>> {
+    let mut problems = Vec::new();
+    let Ok(entries) = std::fs::read_dir(snapshot) else { return Ok(problems) };
+    for entry in entries {
+        let path = entry?.path();
+        let Ok(target) = std::fs::read_link(&path) else { continue };
+        let file = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let etag = target.file_name().unwrap_or_default().to_string_lossy().to_string();
+        if !path.is_file() {
+            problems.push(Problem::Missing(file));
+            continue;
+        }
+        let actual = match etag.len() {
+            64 => sha256_hex(&path)?,
+            40 => git_blob_id(&path)?,
+            _ => continue,
+        };
+        if actual != etag {
+            problems.push(Problem::Corrupt(file));
+        }
+    }
+    Ok(problems)
+}
+
+fn sha256_hex(path: &Path) -> Result<String> {
+    let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+    let mut file = std::fs::File::open(path)?;
+    let mut buffer = vec![0u8; 1 << 20];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        context.update(&buffer[..n]);
+    }
+    Ok(hex(context.finish().as_ref()))
+}
+
+fn git_blob_id(path: &Path) -> Result<String> {
+    let contents = std::fs::read(path)?;
+    let mut hasher = Sha1::new();
+    hasher.update(format!("blob {}\0", contents.len()));
+    hasher.update(&contents);
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Human-readable size, e.g. `461.2 MB`.
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KB", "MB", "GB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{Device, Tensor};
+    use std::collections::HashMap;
+
+    const CONFIG: &str = r#"{"num_mel_bins": 80, "max_source_positions": 1500, "d_model": 384,
+        "encoder_attention_heads": 6, "encoder_layers": 4, "vocab_size": 51865,
+        "max_target_positions": 448, "decoder_attention_heads": 6, "decoder_layers": 4,
+        "suppress_tokens": []}"#;
+    const TOKENIZER: &str = r#"{"version": "1.0", "truncation": null, "padding": null, "added_tokens": [],
+        "normalizer": null, "pre_tokenizer": null, "post_processor": null, "decoder": null,
+        "model": {"type": "WordLevel", "vocab": {"<unk>": 0}, "unk_token": "<unk>"}}"#;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("whisper-models-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A model folder with tiny but well-formed files.
+    fn write_model(dir: &Path) {
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("config.json"), CONFIG).unwrap();
+        std::fs::write(dir.join("tokenizer.json"), TOKENIZER).unwrap();
+        let tensors = HashMap::from([("w".to_string(), Tensor::ones((4, 4), candle_core::DType::F32, &Device::Cpu).unwrap())]);
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors")).unwrap();
+    }
+
+    /// Lay `model` out like hf_hub does: blobs named by etag, snapshot symlinks.
+    #[cfg(unix)]
+    fn write_hub_model(cache: &Path, id: &str, model: &Path) {
+        let repo = cache.join(format!("{}{}", HUB_PREFIX, id));
+        std::fs::create_dir_all(repo.join("blobs")).unwrap();
+        std::fs::create_dir_all(repo.join("refs")).unwrap();
+        std::fs::write(repo.join("refs").join("main"), "abc123").unwrap();
+        let snapshot = repo.join("snapshots").join("abc123");
+        std::fs::create_dir_all(&snapshot).unwrap();
+        for name in ["config.json", "tokenizer.json", "model.safetensors"] {
+            let src = model.join(name);
+            let etag = if name.ends_with(".safetensors") { sha256_hex(&src) } else { git_blob_id(&src) }.unwrap();
+            std::fs::copy(&src, repo.join("blobs").join(&etag)).unwrap();
+            std::os::unix::fs::symlink(Path::new("../../blobs").join(&etag), snapshot.join(name)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_verify_dir_finds_missing_and_truncated_files() {
+        let dir = temp_dir("verify");
+        write_model(&dir);
+        assert!(verify_dir(&dir).is_empty());
+
+        std::fs::remove_file(dir.join("tokenizer.json")).unwrap();
+        let weights = std::fs::read(dir.join("model.safetensors")).unwrap();
+        std::fs::write(dir.join("model.safetensors"), &weights[..weights.len() - 4]).unwrap();
+        let problems = verify_dir(&dir);
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert_eq!(problems[0], Problem::Missing("tokenizer.json".into()));
+        assert!(problems[1].to_string().starts_with("model.safetensors: expected"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_list_verify_import_and_delete() {
+        let cache = temp_dir("store");
+        let source = temp_dir("source").join("whisper-finetuned");
+        write_model(&source);
+        write_hub_model(&cache, "tiny", &source);
+        let store = ModelStore::new(&cache);
+
+        let imported = store.import(&source).unwrap();
+        assert_eq!(imported.id, "finetuned");
+        assert!(store.import(&source).is_err(), "importing twice");
+        let models = store.list().unwrap();
+        let ids: Vec<(&str, ModelSource)> = models.iter().map(|m| (m.id.as_str(), m.source)).collect();
+        assert_eq!(ids, [("tiny", ModelSource::Hub), ("finetuned", ModelSource::Local)]);
+        // The hub copy also holds its `refs/main` file.
+        assert_eq!(models[0].size, models[1].size + "abc123".len() as u64);
+        assert!(models[0].dir.ends_with("snapshots/abc123"));
+        assert!(store.verify(&models[0]).unwrap().is_empty());
+        assert!(store.verify(&models[1]).unwrap().is_empty());
+
+        // Same length, different bytes: only the checksum notices.
+        let blob = std::fs::canonicalize(models[0].dir.join("config.json")).unwrap();
+        std::fs::write(&blob, CONFIG.replace("384", "385")).unwrap();
+        assert_eq!(store.verify(&models[0]).unwrap(), [Problem::Corrupt("config.json".into())]);
+
+        store.delete(&models[0]).unwrap();
+        store.delete(&models[1]).unwrap();
+        assert!(store.list().unwrap().is_empty());
+        std::fs::remove_dir_all(&cache).unwrap();
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_format_size() {
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(1536), "1.5 KB");
+        assert_eq!(format_size(3 * 1024 * 1024 * 1024), "3.0 GB");
+    }
+}
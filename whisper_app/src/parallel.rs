@@ -0,0 +1,508 @@
+use anyhow::Result;
+use std::ops::Range;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc;
+
+use crate::audio::SAMPLE_RATE;
+use crate::decoding::DecodingOptions;
+use crate::job::{Job, Progress};
+use crate::transcriber::Transcriber;
+use crate::whisper_engine::{load_pcm, Segment, Transcription, WhisperEngine};
+
+/// Frames (20 ms) in which `split_at_silence` measures loudness.
+const FRAME_LENGTH: usize = 320;
+/// Loudness around a candidate cut is averaged over this many frames (0.5 s),
+/// so cuts land in pauses rather than between two syllables.
+const QUIET_FRAMES: usize = 25;
+
+/// How `split_at_silence` cuts long audio.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChunkOptions {
+    /// Longest stretch of audio before a cut, in seconds.
+    pub chunk_secs: f64,
+    /// Each cut is placed in the quietest half second of the last `search_secs`
+    /// before the chunk would grow too long.
+    pub search_secs: f64,
+    /// Every chunk but the last runs this far past its cut, so a word the cut
+    /// clips is still heard whole by one of the chunks.
+    pub overlap_secs: f64,
+}
+
+impl Default for ChunkOptions {
+    fn default() -> Self {
+        Self {
+            chunk_secs: 300.0,
+            search_secs: 30.0,
+            overlap_secs: 2.0,
+        }
+    }
+}
+
+/// Cut 16 kHz mono PCM into chunks of at most `chunk_secs` (plus the overlap)
+/// at quiet points, as sample ranges. Each range but the last ends
+/// `overlap_secs` past the start of the next one.
+pub fn split_at_silence(pcm: &[f32], options: &ChunkOptions) -> Vec<Range<usize>> {
+    let samples = |secs: f64| (secs.max(0.0) * SAMPLE_RATE as f64) as usize;
+    let chunk = samples(options.chunk_secs).max(SAMPLE_RATE);
+    let search = samples(options.search_secs).min(chunk / 2) / FRAME_LENGTH;
+    let overlap = samples(options.overlap_secs);
+
+    let energies: Vec<f32> = pcm
+        .chunks(FRAME_LENGTH)
+        .map(|frame| frame.iter().map(|x| x * x).sum::<f32>() / frame.len() as f32)
+        .collect();
+    let loudness = |frame: usize| {
+        let around = &energies[frame.saturating_sub(QUIET_FRAMES / 2)..(frame + QUIET_FRAMES / 2 + 1).min(energies.len())];
+        around.iter().sum::<f32>() / around.len() as f32
+    };
+
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while pcm.len() - start > chunk {
+        let target = (start + chunk) / FRAME_LENGTH;
+        // Of equally quiet frames, the one closest to the target wins.
+        let cut = (target - search..=target)
+            .rev()
+            .min_by(|&a, &b| loudness(a).total_cmp(&loudness(b)))
+            .unwrap_or(target)
+            * FRAME_LENGTH;
+        chunks.push(start..(cut + overlap).min(pcm.len()));
+        start = cut;
+    }
+    chunks.push(start..pcm.len());
+    chunks
+}
+
+/// Join the transcriptions of the chunks `split_at_silence` made, in order,
+/// with times moved onto the timeline of the whole audio.
+///
+/// Near each cut, a segment is kept from the chunk its middle falls in. When
+/// the last segment before the cut still overlaps the first one after it, the
+/// words the later segment repeats are dropped from it.
+pub fn stitch(chunks: &[Range<usize>], transcriptions: Vec<Transcription>) -> Transcription {
+    let secs = |sample: usize| sample as f64 / SAMPLE_RATE as f64;
+    let mut detailed: Vec<Segment> = Vec::new();
+    let mut flagged = Vec::new();
+    let mut first = None;
+    for (i, mut transcription) in transcriptions.into_iter().enumerate() {
+        let offset = secs(chunks[i].start);
+        let next_start = chunks.get(i + 1).map_or(f64::INFINITY, |next| secs(next.start));
+        let before_cut = |start: f64, end: f64| (start + end) / 2.0 < next_start;
+
+        for mut segment in transcription.detailed.drain(..) {
+            segment.start += offset;
+            segment.end += offset;
+            for word in &mut segment.words {
+                word.start += offset;
+                word.end += offset;
+            }
+            if !before_cut(segment.start, segment.end) {
+                continue;
+            }
+            if let Some(previous) = detailed.last().filter(|previous| previous.end > segment.start) {
+                let text = strip_repeated(&previous.text, &segment.text);
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let previous_end = previous.end;
+                segment.text = text;
+                segment.start = previous_end.min(segment.end);
+                segment.words.retain(|word| word.end > previous_end);
+            }
+            detailed.push(segment);
+        }
+        for mut segment in transcription.flagged.drain(..) {
+            segment.start += offset;
+            segment.end += offset;
+            if before_cut(segment.start, segment.end) {
+                flagged.push(segment);
+            }
+        }
+        first.get_or_insert(transcription);
+    }
+
+    let first = first.expect("at least one chunk");
+    Transcription {
+        segments: detailed.iter().map(|s| (s.start, s.end, s.text.clone())).collect(),
+        detailed,
+        language: first.language,
+        language_probability: first.language_probability,
+        flagged,
+        audio_decoder: first.audio_decoder,
+    }
+}
+
+/// `text` without the words at its start that `previous` ends with. Text
+/// without spaces (Chinese, Japanese) is compared by characters, and then at
+/// least two must repeat.
+fn strip_repeated(previous: &str, text: &str) -> String {
+    let by_words = [previous, text].iter().any(|t| t.trim().contains(char::is_whitespace));
+    let before = units(previous, by_words);
+    let after = units(text, by_words);
+    let normalize = |unit: &str| unit.chars().filter(|c| c.is_alphanumeric()).flat_map(char::to_lowercase).collect::<String>();
+    let min_repeat = if by_words { 1 } else { 2 };
+    let repeated = (min_repeat..=before.len().min(after.len()))
+        .rev()
+        .find(|&n| {
+            before[before.len() - n..]
+                .iter()
+                .zip(&after[..n])
+                .all(|((_, a), (_, b))| normalize(a) == normalize(b))
+        })
+        .unwrap_or(0);
+    match (repeated, after.get(repeated)) {
+        (0, _) => text.to_string(),
+        (_, Some(&(at, _))) if by_words => format!(" {}", &text[at..]),
+        (_, Some(&(at, _))) => text[at..].to_string(),
+        (_, None) => String::new(),
+    }
+}
+
+/// Words (or characters) of `text` with their byte offsets.
+fn units(text: &str, by_words: bool) -> Vec<(usize, &str)> {
+    let mut units = Vec::new();
+    let mut start = None;
+    for (i, c) in text.char_indices() {
+        if c.is_whitespace() {
+            if let Some(s) = start.take() {
+                units.push((s, &text[s..i]));
+            }
+        } else if !by_words {
+            units.push((i, &text[i..i + c.len_utf8()]));
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        units.push((s, &text[s..]));
+    }
+    units
+}
+
+enum ChunkEvent {
+    Progress(usize, Progress),
+    Done(usize, Result<Transcription>),
+}
+
+/// Transcribe `pcm` in the chunks `split_at_silence` cuts it into, each worker
+/// taking the next chunk as soon as it is free, and `stitch` the results.
+/// `transcribe` runs one chunk on one worker; its progress is added up over
+/// the chunks and reported to `job`. The first chunk that fails stops the
+/// workers from starting new ones and its error is returned.
+///
+/// Without a language in `options`, `detect_language` finds it in the whole
+/// of `pcm` first and every chunk is decoded in that language, so one file
+/// does not come back in several.
+pub fn transcribe_chunked<W: Send>(
+    workers: &mut [W],
+    pcm: &[f32],
+    chunking: &ChunkOptions,
+    options: &DecodingOptions,
+    job: &mut Job,
+    detect_language: impl FnOnce(&mut W, &[f32], &DecodingOptions) -> Result<(String, f32)>,
+    transcribe: impl Fn(&mut W, &[f32], &DecodingOptions, &mut Job) -> Result<Transcription> + Sync,
+) -> Result<Transcription> {
+    if workers.is_empty() {
+        anyhow::bail!("no workers to transcribe with");
+    }
+    let mut options = options.clone();
+    let detected = match options.language {
+        Some(_) => None,
+        None => {
+            let (language, probability) = detect_language(&mut workers[0], pcm, &options)?;
+            options.language = Some(language.clone());
+            Some((language, probability))
+        }
+    };
+    let options = &options;
+    let chunks = split_at_silence(pcm, chunking);
+    let total_secs = pcm.len() as f64 / SAMPLE_RATE as f64;
+    let (sender, receiver) = mpsc::channel();
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let mut results: Vec<Option<Result<Transcription>>> = chunks.iter().map(|_| None).collect();
+
+    std::thread::scope(|scope| {
+        for worker in workers.iter_mut() {
+            let (sender, chunks, next, failed, transcribe) = (sender.clone(), &chunks, &next, &failed, &transcribe);
+            let worker_job = job.quiet();
+            scope.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= chunks.len() {
+                        break;
+                    }
+                    let progress_sender = sender.clone();
+                    let mut chunk_job = worker_job.quiet().on_progress(move |progress| {
+                        let _ = progress_sender.send(ChunkEvent::Progress(i, progress));
+                    });
+                    let result = transcribe(worker, &pcm[chunks[i].clone()], options, &mut chunk_job);
+                    if result.is_err() {
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                    let _ = sender.send(ChunkEvent::Done(i, result));
+                }
+            });
+        }
+        drop(sender);
+
+        let mut progress = vec![Progress { window: 0, processed_secs: 0.0, total_secs: 0.0 }; chunks.len()];
+        for event in receiver {
+            match event {
+                ChunkEvent::Progress(i, chunk_progress) => progress[i] = chunk_progress,
+                ChunkEvent::Done(i, result) => {
+                    // With VAD a chunk counts only its speech; once done, all of it is processed.
+                    progress[i].processed_secs = chunks[i].len() as f64 / SAMPLE_RATE as f64;
+                    results[i] = Some(result);
+                }
+            }
+            job.report(Progress {
+                window: progress.iter().map(|p| p.window).sum(),
+                processed_secs: progress.iter().map(|p| p.processed_secs).sum::<f64>().min(total_secs),
+                total_secs,
+            });
+        }
+    });
+
+    let mut transcriptions = Vec::with_capacity(chunks.len());
+    for result in results {
+        match result {
+            Some(Ok(transcription)) => transcriptions.push(transcription),
+            Some(Err(e)) => return Err(e),
+            // Not started because another chunk failed.
+            None => {}
+        }
+    }
+    if transcriptions.len() < chunks.len() {
+        anyhow::bail!("transcription stopped before every chunk was done");
+    }
+    let mut transcription = stitch(&chunks, transcriptions);
+    if let Some((language, probability)) = detected {
+        transcription.language = language;
+        transcription.language_probability = probability;
+    }
+    Ok(transcription)
+}
+
+/// Transcribes each file in chunks on several engines at once, see
+/// `transcribe_chunked`. Clones of one engine share its weights.
+#[derive(Clone)]
+pub struct ParallelTranscriber {
+    engines: Vec<WhisperEngine>,
+    pub chunking: ChunkOptions,
+}
+
+impl ParallelTranscriber {
+    pub fn new(engines: Vec<WhisperEngine>, chunking: ChunkOptions) -> Result<Self> {
+        if engines.is_empty() {
+            anyhow::bail!("a parallel transcriber needs at least one engine");
+        }
+        Ok(Self { engines, chunking })
+    }
+
+    /// E.g. `4 × CPU, f32, 4 threads`.
+    pub fn device_description(&self) -> String {
+        format!("{} × {}", self.engines.len(), self.engines[0].device_description())
+    }
+
+    pub fn transcribe_pcm_with(&mut self, pcm: &[f32], options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        transcribe_chunked(
+            &mut self.engines,
+            pcm,
+            &self.chunking,
+            options,
+            job,
+            |engine, pcm, options| engine.detect_language_pcm(pcm, options),
+            |engine, chunk, options, job| engine.transcribe_pcm_with(chunk, options, job),
+        )
+    }
+}
+
+impl Transcriber for ParallelTranscriber {
+    fn transcribe_with(&mut self, audio_path: &str, options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        let (pcm, decoder) = load_pcm(audio_path, options)?;
+        job.check()?;
+        let mut transcription = self.transcribe_pcm_with(&pcm, options, job)?;
+        transcription.audio_decoder = Some(decoder);
+        Ok(transcription)
+    }
+
+    fn share(&self) -> Option<Box<dyn Transcriber>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::job::{CancellationToken, Cancelled};
+    use crate::transcriber::{segment, transcription};
+
+    fn detect_english<W>(_: &mut W, _: &[f32], _: &DecodingOptions) -> Result<(String, f32)> {
+        Ok(("en".to_string(), 1.0))
+    }
+
+    /// Bursts of `(seconds of silence, seconds of sound)`; burst `k` has
+    /// amplitude `0.1 + 0.01 * k`, so `fake_transcribe` can name it.
+    fn bursts(pattern: &[(f64, f64)]) -> Vec<f32> {
+        let samples = |secs: f64| (secs * SAMPLE_RATE as f64) as usize;
+        let mut pcm = Vec::new();
+        for (k, &(silence, sound)) in pattern.iter().enumerate() {
+            pcm.resize(pcm.len() + samples(silence), 0.0);
+            let amplitude = 0.1 + 0.01 * k as f32;
+            pcm.extend((0..samples(sound)).map(|i| if i % 2 == 0 { amplitude } else { -amplitude }));
+        }
+        pcm
+    }
+
+    /// One segment per burst, times relative to `pcm`.
+    fn fake_transcribe(pcm: &[f32]) -> Transcription {
+        let secs = |sample: usize| sample as f64 / SAMPLE_RATE as f64;
+        let mut segments = Vec::new();
+        let mut start = None;
+        for (i, x) in pcm.iter().chain([&0.0]).enumerate() {
+            match (start, x.abs() > 0.0) {
+                (None, true) => start = Some(i),
+                (Some(s), false) => {
+                    let k = ((pcm[s].abs() - 0.1) / 0.01).round() as usize;
+                    segments.push(segment(secs(s), secs(i), &format!(" word{}", k)));
+                    start = None;
+                }
+                _ => {}
+            }
+        }
+        transcription(segments)
+    }
+
+    #[test]
+    fn test_cuts_in_pauses() {
+        let pcm = bursts(&[(0.5, 7.0), (1.0, 7.0), (1.0, 7.0), (1.0, 3.0)]);
+        let options = ChunkOptions { chunk_secs: 10.0, search_secs: 4.0, overlap_secs: 1.0 };
+        let chunks = split_at_silence(&pcm, &options);
+        assert_eq!(chunks.len(), 4, "{:?}", chunks);
+        assert_eq!(chunks[0].start, 0);
+        assert_eq!(chunks.last().unwrap().end, pcm.len());
+        for pair in chunks.windows(2) {
+            let cut = pair[1].start;
+            assert_eq!(pair[0].end, cut + SAMPLE_RATE);
+            assert_eq!(pcm[cut], 0.0, "cut at {} s is not in a pause", cut as f64 / SAMPLE_RATE as f64);
+            assert!(pair[1].start - pair[0].start <= 10 * SAMPLE_RATE);
+        }
+        // Short audio is one chunk.
+        let short = split_at_silence(&pcm[..SAMPLE_RATE], &options);
+        assert_eq!(short.len(), 1);
+        assert_eq!(short[0], 0..SAMPLE_RATE);
+    }
+
+    #[test]
+    fn test_transcribes_chunks_in_parallel_and_stitches_them() {
+        let pattern: Vec<(f64, f64)> = (0..12).map(|k| (0.8, 2.0 + (k % 3) as f64)).collect();
+        let pcm = bursts(&pattern);
+        let expected = fake_transcribe(&pcm);
+        let options = ChunkOptions { chunk_secs: 10.0, search_secs: 5.0, overlap_secs: 1.5 };
+        assert!(split_at_silence(&pcm, &options).len() > 3);
+
+        let mut workers = vec![0usize; 3];
+        let mut reports = Vec::new();
+        let stitched = {
+            let mut job = Job::default().on_progress(|p| reports.push(p));
+            transcribe_chunked(&mut workers, &pcm, &options, &DecodingOptions::default(), &mut job, detect_english, |chunks_done, chunk, _, job| {
+                *chunks_done += 1;
+                job.report(Progress { window: 1, processed_secs: chunk.len() as f64 / SAMPLE_RATE as f64, total_secs: 30.0 });
+                Ok(fake_transcribe(chunk))
+            })
+            .unwrap()
+        };
+
+        let texts: Vec<&str> = stitched.detailed.iter().map(|s| s.text.as_str()).collect();
+        let expected_texts: Vec<&str> = expected.detailed.iter().map(|s| s.text.as_str()).collect();
+        assert_eq!(texts, expected_texts);
+        for (actual, expected) in stitched.detailed.iter().zip(&expected.detailed) {
+            assert!((actual.start - expected.start).abs() < 1e-3 && (actual.end - expected.end).abs() < 1e-3);
+        }
+        assert_eq!(workers.iter().sum::<usize>(), split_at_silence(&pcm, &options).len());
+        assert!(reports.last().unwrap().percent() == 100.0);
+    }
+
+    #[test]
+    fn test_stitch_drops_repeats_at_the_cut() {
+        let chunks = [0..12 * SAMPLE_RATE, 10 * SAMPLE_RATE..20 * SAMPLE_RATE];
+        let first = transcription(vec![segment(0.0, 4.0, " Hello there."), segment(6.0, 11.0, " We will meet again")]);
+        // The second chunk starts at 10 s, in the middle of the sentence.
+        let second = transcription(vec![segment(0.0, 2.5, " meet again tomorrow."), segment(3.0, 5.0, " Bye.")]);
+        let stitched = stitch(&chunks, vec![first, second]);
+        let cues: Vec<(f64, f64, &str)> = stitched.segments.iter().map(|(s, e, t)| (*s, *e, t.as_str())).collect();
+        assert_eq!(cues, [(0.0, 4.0, " Hello there."), (6.0, 11.0, " We will meet again"), (11.0, 12.5, " tomorrow."), (13.0, 15.0, " Bye.")]);
+
+        assert_eq!(strip_repeated("我们明天再见", "明天再见吧"), "吧");
+        assert_eq!(strip_repeated("你好", "好的"), "好的");
+        assert_eq!(strip_repeated(" all done", " Done."), "");
+    }
+
+    #[test]
+    fn test_failed_or_cancelled_chunk_stops_the_rest() {
+        let pcm = bursts(&(0..8).map(|_| (1.0, 3.0)).collect::<Vec<_>>());
+        let options = ChunkOptions { chunk_secs: 6.0, search_secs: 2.0, overlap_secs: 0.5 };
+        let calls = AtomicUsize::new(0);
+        let error = transcribe_chunked(&mut [()], &pcm, &options, &DecodingOptions::default(), &mut Job::default(), detect_english, |_, _, _, _| {
+            calls.fetch_add(1, Ordering::Relaxed);
+            anyhow::bail!("out of memory")
+        })
+        .unwrap_err();
+        assert_eq!(error.to_string(), "out of memory");
+        assert_eq!(calls.into_inner(), 1);
+
+        let cancel = CancellationToken::default();
+        cancel.cancel();
+        let error = transcribe_chunked(&mut [(), ()], &pcm, &options, &DecodingOptions::default(), &mut Job::new(cancel), detect_english, |_, chunk, _, job| {
+            job.check()?;
+            Ok(fake_transcribe(chunk))
+        })
+        .unwrap_err();
+        assert!(error.is::<Cancelled>());
+    }
+
+    #[test]
+    fn test_every_chunk_gets_the_detected_language() {
+        let pcm = bursts(&(0..8).map(|_| (1.0, 3.0)).collect::<Vec<_>>());
+        let options = ChunkOptions { chunk_secs: 6.0, search_secs: 2.0, overlap_secs: 0.5 };
+        let chunk_languages = |decoding: &DecodingOptions| {
+            let seen = std::sync::Mutex::new(Vec::new());
+            let detections = AtomicUsize::new(0);
+            let transcription = transcribe_chunked(
+                &mut [(), ()],
+                &pcm,
+                &options,
+                decoding,
+                &mut Job::default(),
+                |_, audio, _| {
+                    assert_eq!(audio.len(), pcm.len());
+                    detections.fetch_add(1, Ordering::Relaxed);
+                    Ok(("de".to_string(), 0.8))
+                },
+                |_, chunk, decoding, _| {
+                    seen.lock().unwrap().push(decoding.language.clone());
+                    let mut transcription = fake_transcribe(chunk);
+                    // Left to itself, a chunk could settle on any language.
+                    transcription.language = decoding.language.clone().unwrap_or_else(|| format!("chunk of {}", chunk.len()));
+                    Ok(transcription)
+                },
+            )
+            .unwrap();
+            (seen.into_inner().unwrap(), detections.into_inner(), transcription)
+        };
+
+        let (seen, detections, transcription) = chunk_languages(&DecodingOptions::default());
+        assert_eq!(seen.len(), split_at_silence(&pcm, &options).len());
+        assert!(seen.iter().all(|language| language.as_deref() == Some("de")), "{:?}", seen);
+        assert_eq!(detections, 1);
+        assert_eq!(transcription.language, "de");
+        assert_eq!(transcription.language_probability, 0.8);
+
+        let french = DecodingOptions { language: Some("fr".to_string()), ..Default::default() };
+        let (seen, detections, transcription) = chunk_languages(&french);
+        assert!(seen.iter().all(|language| language.as_deref() == Some("fr")), "{:?}", seen);
+        assert_eq!(detections, 0);
+        assert_eq!(transcription.language, "fr");
+    }
+}
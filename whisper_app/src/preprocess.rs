@@ -0,0 +1,299 @@
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::f32::consts::PI;
+
+use crate::audio::SAMPLE_RATE;
+
+/// STFT frame of the noise reduction (32 ms at 16 kHz), hopped by half.
+const NR_FRAME: usize = 512;
+/// Blocks of the loudness measurement (400 ms, as in EBU R128).
+const LOUDNESS_BLOCK: usize = SAMPLE_RATE * 2 / 5;
+
+/// Clean-up applied to 16 kHz mono PCM before the mel spectrogram, in the
+/// order high-pass, noise reduction, normalization. Everything is off by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PreprocessOptions {
+    /// Cut-off (Hz) of a 2nd-order Butterworth high-pass against rumble and
+    /// handling noise, e.g. 80.
+    pub high_pass_hz: Option<f32>,
+    pub noise_reduction: Option<NoiseReduction>,
+    pub normalization: Option<Normalization>,
+}
+
+impl PreprocessOptions {
+    pub fn is_enabled(&self) -> bool {
+        self.high_pass_hz.is_some() || self.noise_reduction.is_some() || self.normalization.is_some()
+    }
+}
+
+/// Spectral subtraction of a stationary noise floor (hiss, hum, fans).
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseReduction {
+    /// How many times the estimated noise magnitude to subtract.
+    pub strength: f32,
+    /// Lowest gain (dB) a frequency bin can get, so nothing is cut to
+    /// silence and "musical noise" stays low.
+    pub floor_db: f32,
+}
+
+impl Default for NoiseReduction {
+    fn default() -> Self {
+        Self { strength: 1.5, floor_db: -20.0 }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Scale so the loudest sample reaches this level (dBFS).
+    Peak { target_db: f32 },
+    /// Scale so the gated RMS level reaches this level (dBFS), without
+    /// clipping and by at most `max_gain_db`.
+    Loudness { target_db: f32, max_gain_db: f32 },
+}
+
+impl Normalization {
+    pub fn peak() -> Self {
+        Normalization::Peak { target_db: -1.0 }
+    }
+
+    pub fn loudness() -> Self {
+        Normalization::Loudness { target_db: -20.0, max_gain_db: 30.0 }
+    }
+}
+
+/// Apply `options` to `pcm`.
+pub fn preprocess(pcm: &[f32], options: &PreprocessOptions) -> Vec<f32> {
+    let mut pcm = pcm.to_vec();
+    if let Some(cutoff) = options.high_pass_hz {
+        high_pass(&mut pcm, cutoff);
+    }
+    if let Some(noise_reduction) = &options.noise_reduction {
+        pcm = reduce_noise(&pcm, noise_reduction);
+    }
+    match options.normalization {
+        Some(Normalization::Peak { target_db }) => normalize_peak(&mut pcm, target_db),
+        Some(Normalization::Loudness { target_db, max_gain_db }) => normalize_loudness(&mut pcm, target_db, max_gain_db),
+        None => {}
+    }
+    pcm
+}
+
+/// 2nd-order Butterworth high-pass (a biquad from the Audio EQ Cookbook), in place.
+pub fn high_pass(pcm: &mut [f32], cutoff_hz: f32) {
+    let w0 = 2.0 * PI * cutoff_hz / SAMPLE_RATE as f32;
+    let alpha = w0.sin() / (2.0 * std::f32::consts::FRAC_1_SQRT_2);
+    let cos = w0.cos();
+    let a0 = 1.0 + alpha;
+    let (b0, b1, b2) = ((1.0 + cos) / 2.0 / a0, -(1.0 + cos) / a0, (1.0 + cos) / 2.0 / a0);
+    let (a1, a2) = (-2.0 * cos / a0, (1.0 - alpha) / a0);
+
+    let (mut x1, mut x2, mut y1, mut y2) = (0f32, 0f32, 0f32, 0f32);
+    for sample in pcm.iter_mut() {
+        let x = *sample;
+        let y = b0 * x + b1 * x1 + b2 * x2 - a1 * y1 - a2 * y2;
+        (x2, x1, y2, y1) = (x1, x, y1, y);
+        *sample = y;
+    }
+}
+
+pub fn normalize_peak(pcm: &mut [f32], target_db: f32) {
+    let peak = pcm.iter().fold(0f32, |m, x| m.max(x.abs()));
+    if peak > 0.0 {
+        let gain = db_to_gain(target_db) / peak;
+        pcm.iter_mut().for_each(|x| *x *= gain);
+    }
+}
+
+/// RMS normalization over 400 ms blocks, ignoring silent blocks and those
+/// more than 10 dB below the average (the gating of EBU R128, without its
+/// frequency weighting).
+pub fn normalize_loudness(pcm: &mut [f32], target_db: f32, max_gain_db: f32) {
+    let Some(level) = gated_level_db(pcm) else { return };
+    let peak = pcm.iter().fold(0f32, |m, x| m.max(x.abs()));
+    let gain_db = (target_db - level).min(max_gain_db).min(-20.0 * peak.log10());
+    let gain = db_to_gain(gain_db);
+    pcm.iter_mut().for_each(|x| *x *= gain);
+}
+
+/// Gated RMS level of `pcm` (dBFS); `None` for silence.
+pub fn gated_level_db(pcm: &[f32]) -> Option<f32> {
+    let blocks: Vec<f32> = pcm
+        .chunks(LOUDNESS_BLOCK)
+        .map(|block| block.iter().map(|x| x * x).sum::<f32>() / block.len() as f32)
+        .filter(|&power| power_db(power) > -70.0)
+        .collect();
+    if blocks.is_empty() {
+        return None;
+    }
+    let mean = blocks.iter().sum::<f32>() / blocks.len() as f32;
+    let gated: Vec<f32> = blocks.into_iter().filter(|&power| power_db(power) > power_db(mean) - 10.0).collect();
+    Some(power_db(gated.iter().sum::<f32>() / gated.len() as f32))
+}
+
+/// Spectral subtraction: the noise spectrum is the average magnitude of the
+/// quietest tenth of the frames, subtracted from every frame with a floor.
+/// Hann frames at 50% overlap add back up to the input where nothing is removed.
+pub fn reduce_noise(pcm: &[f32], options: &NoiseReduction) -> Vec<f32> {
+    let hop = NR_FRAME / 2;
+    if pcm.len() < NR_FRAME {
+        return pcm.to_vec();
+    }
+    // Pad so every sample is covered by two frames.
+    let mut padded = vec![0f32; hop];
+    padded.extend_from_slice(pcm);
+    padded.resize(hop + pcm.len().div_ceil(hop) * hop + hop, 0.0);
+
+    let window: Vec<f32> = (0..NR_FRAME).map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / NR_FRAME as f32).cos())).collect();
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(NR_FRAME);
+    let ifft = planner.plan_fft_inverse(NR_FRAME);
+
+    let mut spectra: Vec<Vec<Complex<f32>>> = padded
+        .windows(NR_FRAME)
+        .step_by(hop)
+        .map(|frame| {
+            let mut buffer: Vec<Complex<f32>> = frame.iter().zip(&window).map(|(x, w)| Complex::new(x * w, 0.0)).collect();
+            fft.process(&mut buffer);
+            buffer
+        })
+        .collect();
+
+    let mut by_energy: Vec<(f32, usize)> = spectra
+        .iter()
+        .enumerate()
+        .map(|(i, spectrum)| (spectrum.iter().map(|c| c.norm_sqr()).sum::<f32>(), i))
+        .collect();
+    by_energy.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let quiet = &by_energy[..(by_energy.len() / 10).max(1)];
+    let mut noise = vec![0f32; NR_FRAME];
+    for &(_, i) in quiet {
+        for (n, c) in noise.iter_mut().zip(&spectra[i]) {
+            *n += c.norm() / quiet.len() as f32;
+        }
+    }
+
+    let floor = db_to_gain(options.floor_db);
+    let mut out = vec![0f32; padded.len()];
+    for (i, spectrum) in spectra.iter_mut().enumerate() {
+        for (c, n) in spectrum.iter_mut().zip(&noise) {
+            let magnitude = c.norm();
+            let gain = if magnitude > 0.0 {
+                ((magnitude - options.strength * n) / magnitude).max(floor)
+            } else {
+                floor
+            };
+            *c *= gain;
+        }
+        ifft.process(spectrum);
+        for (o, c) in out[i * hop..].iter_mut().zip(spectrum.iter()) {
+            *o += c.re / NR_FRAME as f32;
+        }
+    }
+    out[hop..hop + pcm.len()].to_vec()
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+fn power_db(power: f32) -> f32 {
+    10.0 * power.max(1e-12).log10()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Rng, SeedableRng};
+
+    fn sine(hz: f32, amplitude: f32, seconds: f32) -> Vec<f32> {
+        let n = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..n).map(|i| amplitude * (2.0 * PI * hz * i as f32 / SAMPLE_RATE as f32).sin()).collect()
+    }
+
+    fn noise(amplitude: f32, seconds: f32) -> Vec<f32> {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(7);
+        let n = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..n).map(|_| amplitude * rng.gen_range(-1.0..1.0)).collect()
+    }
+
+    /// RMS level (dBFS), skipping the first `skip` samples (filter settling).
+    fn rms_db(pcm: &[f32], skip: usize) -> f32 {
+        let pcm = &pcm[skip..];
+        power_db(pcm.iter().map(|x| x * x).sum::<f32>() / pcm.len() as f32)
+    }
+
+    #[test]
+    fn test_disabled_is_a_no_op() {
+        let pcm = sine(440.0, 0.3, 0.5);
+        assert!(!PreprocessOptions::default().is_enabled());
+        assert_eq!(preprocess(&pcm, &PreprocessOptions::default()), pcm);
+    }
+
+    #[test]
+    fn test_high_pass_removes_rumble_keeps_voice() {
+        let mut rumble = sine(20.0, 0.5, 1.0);
+        let mut voice = sine(1000.0, 0.5, 1.0);
+        let (rumble_db, voice_db) = (rms_db(&rumble, 0), rms_db(&voice, 0));
+        high_pass(&mut rumble, 80.0);
+        high_pass(&mut voice, 80.0);
+        // 12 dB per octave, two octaves below the cut-off.
+        assert!(rms_db(&rumble, 4000) < rumble_db - 20.0, "{} dB", rms_db(&rumble, 4000) - rumble_db);
+        assert!((rms_db(&voice, 4000) - voice_db).abs() < 0.2);
+    }
+
+    #[test]
+    fn test_peak_normalization() {
+        let mut pcm = sine(440.0, 0.05, 0.5);
+        normalize_peak(&mut pcm, -1.0);
+        let peak = pcm.iter().fold(0f32, |m, x| m.max(x.abs()));
+        assert!((peak - db_to_gain(-1.0)).abs() < 1e-4, "peak {peak}");
+    }
+
+    #[test]
+    fn test_loudness_normalization_ignores_silence_and_never_clips() {
+        // A quiet lapel mic: -43 dBFS speech-like tone between long silences.
+        let quiet = [vec![0.0; SAMPLE_RATE * 2], sine(300.0, 0.01, 2.0), vec![0.0; SAMPLE_RATE * 2]].concat();
+        let mut pcm = quiet.clone();
+        normalize_loudness(&mut pcm, -20.0, 30.0);
+        let level = gated_level_db(&pcm).unwrap();
+        assert!((level - -20.0).abs() < 0.5, "{level} dBFS");
+
+        // Raising a loud tone to -3 dBFS RMS would clip; the peak limits the gain.
+        let mut loud = sine(300.0, 0.5, 1.0);
+        normalize_loudness(&mut loud, -3.0, 30.0);
+        assert!(loud.iter().all(|x| x.abs() <= 1.0 + 1e-5));
+
+        // Gain stops at max_gain_db.
+        let mut faint = sine(300.0, 0.001, 1.0);
+        normalize_loudness(&mut faint, -20.0, 30.0);
+        let gain_db = gated_level_db(&faint).unwrap() - gated_level_db(&sine(300.0, 0.001, 1.0)).unwrap();
+        assert!((gain_db - 30.0).abs() < 0.1, "{gain_db} dB");
+        assert_eq!(gated_level_db(&vec![0.0; SAMPLE_RATE]), None);
+    }
+
+    #[test]
+    fn test_noise_reduction_improves_snr() {
+        // Hiss throughout, a tone in the middle second.
+        let clean = [vec![0.0; SAMPLE_RATE], sine(500.0, 0.2, 1.0), vec![0.0; SAMPLE_RATE]].concat();
+        let hiss = noise(0.02, 3.0);
+        let noisy: Vec<f32> = clean.iter().zip(&hiss).map(|(c, n)| c + n).collect();
+        let snr = |pcm: &[f32]| {
+            let error: Vec<f32> = pcm.iter().zip(&clean).map(|(x, c)| x - c).collect();
+            rms_db(&clean, 0) - rms_db(&error, 0)
+        };
+
+        let denoised = reduce_noise(&noisy, &NoiseReduction::default());
+        assert_eq!(denoised.len(), noisy.len());
+        assert!(snr(&denoised) > snr(&noisy) + 6.0, "{} -> {} dB", snr(&noisy), snr(&denoised));
+        // The tone itself survives.
+        let tone = SAMPLE_RATE..2 * SAMPLE_RATE;
+        assert!((rms_db(&denoised[tone.clone()], 0) - rms_db(&clean[tone], 0)).abs() < 1.0);
+    }
+
+    #[test]
+    fn test_noise_reduction_passes_clean_signal() {
+        let pcm = sine(440.0, 0.3, 1.0);
+        let out = reduce_noise(&pcm, &NoiseReduction { strength: 0.0, floor_db: -20.0 });
+        let max_error = pcm.iter().zip(&out).fold(0f32, |m, (a, b)| m.max((a - b).abs()));
+        assert!(max_error < 1e-4, "{max_error}");
+    }
+}
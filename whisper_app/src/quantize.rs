@@ -0,0 +1,166 @@
+use anyhow::Result;
+use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+use candle_core::safetensors::MmapedSafetensors;
+use candle_core::Device;
+use std::path::Path;
+
+/// GGUF weight formats offered for CPU inference.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantization {
+    Q4_0,
+    Q5_0,
+    Q8_0,
+}
+
+impl Quantization {
+    pub const ALL: [Quantization; 3] = [Quantization::Q8_0, Quantization::Q5_0, Quantization::Q4_0];
+
+    /// Suffix used in model ids and file names, e.g. `small-q5_0`.
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Quantization::Q4_0 => "q4_0",
+            Quantization::Q5_0 => "q5_0",
+            Quantization::Q8_0 => "q8_0",
+        }
+    }
+
+    fn dtype(self) -> GgmlDType {
+        match self {
+            Quantization::Q4_0 => GgmlDType::Q4_0,
+            Quantization::Q5_0 => GgmlDType::Q5_0,
+            Quantization::Q8_0 => GgmlDType::Q8_0,
+        }
+    }
+}
+
+/// Split a model id such as `small-q5_0` into the hub model and its quantization.
+pub fn parse_model_id(model_id: &str) -> (&str, Option<Quantization>) {
+    for q in Quantization::ALL {
+        if let Some(base) = model_id.strip_suffix(q.suffix()).and_then(|b| b.strip_suffix('-')) {
+            return (base, Some(q));
+        }
+    }
+    (model_id, None)
+}
+
+/// Candle's hub repo of ready-made GGUF Whisper models.
+pub const PUBLISHED_REPO: &str = "lmz/candle-whisper";
+
+/// File names of a model in `PUBLISHED_REPO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PublishedGguf {
+    pub config: String,
+    pub tokenizer: String,
+    pub weights: String,
+}
+
+/// The files `PUBLISHED_REPO` has for `base_id` at `quantization`; `None` when
+/// it publishes no such model and the checkpoint has to be converted locally.
+pub fn published_gguf(base_id: &str, quantization: Quantization) -> Option<PublishedGguf> {
+    let name = match (base_id, quantization) {
+        ("tiny", Quantization::Q8_0) => "tiny",
+        ("tiny.en", Quantization::Q8_0) => "tiny-en",
+        _ => return None,
+    };
+    Some(PublishedGguf {
+        config: format!("config-{}.json", name),
+        tokenizer: format!("tokenizer-{}.json", name),
+        weights: format!("model-{}-q80.gguf", name),
+    })
+}
+
+/// Convert a safetensors checkpoint to GGUF. Matrices whose rows fit the block
+/// size are quantized; biases, norms and convolutions are kept as f32, which is
+/// what candle's quantized Whisper expects.
+pub fn quantize_safetensors(src: &Path, dst: &Path, quantization: Quantization) -> Result<()> {
+    let st = unsafe { MmapedSafetensors::new(src)? };
+    let mut names: Vec<String> = st.tensors().into_iter().map(|(name, _)| name).collect();
+    names.sort();
+
+    let block_size = quantization.dtype().block_size();
+    let mut tensors = Vec::with_capacity(names.len());
+    for name in names {
+        let tensor = st.load(&name, &Device::Cpu)?;
+        let dims = tensor.dims();
+        let dtype = if dims.len() == 2 && dims[1] % block_size == 0 {
+            quantization.dtype()
+        } else {
+            GgmlDType::F32
+        };
+        tensors.push((name, QTensor::quantize(&tensor, dtype)?));
+    }
+
+    // Write next to the destination first so an interrupted run leaves no half file.
+    let tmp = dst.with_extension("gguf.part");
+    let mut file = std::fs::File::create(&tmp)?;
+    let tensors: Vec<(&str, &QTensor)> = tensors.iter().map(|(n, t)| (n.as_str(), t)).collect();
+    gguf_file::write(&mut file, &[], &tensors)?;
+    drop(file);
+    std::fs::rename(tmp, dst)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_model_id() {
+        assert_eq!(parse_model_id("small"), ("small", None));
+        assert_eq!(parse_model_id("small-q5_0"), ("small", Some(Quantization::Q5_0)));
+        assert_eq!(parse_model_id("large-v3-q4_0"), ("large-v3", Some(Quantization::Q4_0)));
+    }
+
+    #[test]
+    fn test_published_gguf() {
+        let tiny_en = published_gguf("tiny.en", Quantization::Q8_0).unwrap();
+        assert_eq!(tiny_en.weights, "model-tiny-en-q80.gguf");
+        assert_eq!(tiny_en.config, "config-tiny-en.json");
+        assert_eq!(published_gguf("tiny", Quantization::Q8_0).unwrap().tokenizer, "tokenizer-tiny.json");
+        assert_eq!(published_gguf("tiny", Quantization::Q4_0), None);
+        assert_eq!(published_gguf("small", Quantization::Q8_0), None);
+    }
+
+    #[test]
+    fn test_quantizes_matrices_and_keeps_the_rest_f32() -> Result<()> {
+        use candle_core::{DType, Tensor};
+        use std::collections::HashMap;
+
+        let dir = std::env::temp_dir().join(format!("whisper-quantize-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let device = Device::Cpu;
+        let tensors: HashMap<String, Tensor> = [
+            ("model.decoder.layers.0.fc1.weight", Tensor::randn(0f32, 1.0, (4, 64), &device)?),
+            ("model.decoder.layers.0.fc1.bias", Tensor::randn(0f32, 1.0, 4, &device)?),
+            ("model.encoder.conv1.weight", Tensor::randn(0f32, 1.0, (4, 2, 3), &device)?),
+            // Rows of 10 do not fill a 32-value block.
+            ("model.decoder.odd.weight", Tensor::randn(0f32, 1.0, (4, 10), &device)?),
+        ]
+        .into_iter()
+        .map(|(name, tensor)| (name.to_string(), tensor))
+        .collect();
+        candle_core::safetensors::save(&tensors, dir.join("model.safetensors"))?;
+
+        quantize_safetensors(&dir.join("model.safetensors"), &dir.join("model.gguf"), Quantization::Q8_0)?;
+        let mut file = std::fs::File::open(dir.join("model.gguf"))?;
+        let content = gguf_file::Content::read(&mut file)?;
+        let dtype = |name: &str| content.tensor_infos[name].ggml_dtype;
+        assert_eq!(dtype("model.decoder.layers.0.fc1.weight"), GgmlDType::Q8_0);
+        assert_eq!(dtype("model.decoder.layers.0.fc1.bias"), GgmlDType::F32);
+        assert_eq!(dtype("model.encoder.conv1.weight"), GgmlDType::F32);
+        assert_eq!(dtype("model.decoder.odd.weight"), GgmlDType::F32);
+
+        // Quantized values stay close and the shapes survive.
+        let restored = content.tensor(&mut file, "model.decoder.layers.0.fc1.weight", &device)?.dequantize(&device)?;
+        let original = &tensors["model.decoder.layers.0.fc1.weight"];
+        assert_eq!(restored.dims(), original.dims());
+        let diff = (restored - original)?.abs()?.max_all()?.to_dtype(DType::F32)?.to_scalar::<f32>()?;
+        assert!(diff < 0.05, "max difference {}", diff);
+
+        let mut names: Vec<String> = std::fs::read_dir(&dir)?.map(|e| e.unwrap().file_name().to_string_lossy().to_string()).collect();
+        names.sort();
+        assert_eq!(names, ["model.gguf", "model.safetensors"]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,165 @@
+use std::f64::consts::PI;
+
+/// Fraction of the output Nyquist frequency kept by the anti-aliasing filter.
+const ROLLOFF: f64 = 0.945;
+/// Zero crossings of the windowed sinc on each side of the centre tap.
+const ZERO_CROSSINGS: f64 = 16.0;
+
+/// Average interleaved frames of `channels` samples into a single mono channel.
+pub fn downmix_to_mono(interleaved: &[f32], channels: usize) -> Vec<f32> {
+    if channels <= 1 {
+        return interleaved.to_vec();
+    }
+    interleaved
+        .chunks_exact(channels)
+        .map(|frame| frame.iter().sum::<f32>() / channels as f32)
+        .collect()
+}
+
+/// Band-limited resampling of mono PCM from `from_rate` to `to_rate`.
+///
+/// Uses a polyphase Blackman-windowed sinc filter. The ratio is reduced to
+/// `up / down`, one filter phase is precomputed per possible fractional
+/// position, and when downsampling the cutoff is lowered to the output
+/// Nyquist frequency so content above it is removed instead of aliased.
+pub fn resample(input: &[f32], from_rate: u32, to_rate: u32) -> Vec<f32> {
+    if from_rate == to_rate || input.is_empty() || from_rate == 0 || to_rate == 0 {
+        return input.to_vec();
+    }
+
+    let g = gcd(from_rate, to_rate);
+    let up = (to_rate / g) as usize;
+    let down = (from_rate / g) as usize;
+
+    // Cutoff as a fraction of the input Nyquist frequency.
+    let cutoff = ROLLOFF * (up as f64 / down as f64).min(1.0);
+    let half_taps = (ZERO_CROSSINGS / cutoff).ceil() as usize;
+    let taps = 2 * half_taps;
+
+    let mut table = vec![0f32; up * taps];
+    for phase in 0..up {
+        let frac = phase as f64 / up as f64;
+        let row = &mut table[phase * taps..(phase + 1) * taps];
+        let mut sum = 0.0;
+        for (j, tap) in row.iter_mut().enumerate() {
+            let x = (j as f64 - half_taps as f64 + 1.0) - frac;
+            let w = cutoff * sinc(cutoff * x) * blackman(x / half_taps as f64);
+            *tap = w as f32;
+            sum += w;
+        }
+        // Normalise every phase to unity DC gain.
+        for tap in row.iter_mut() {
+            *tap /= sum as f32;
+        }
+    }
+
+    let out_len = (input.len() * up).div_ceil(down);
+    let mut output = Vec::with_capacity(out_len);
+    for n in 0..out_len {
+        let pos = n * down;
+        let base = pos / up;
+        let row = &table[(pos % up) * taps..(pos % up + 1) * taps];
+
+        // Input index of the first tap is base - half_taps + 1.
+        let first = base as isize - half_taps as isize + 1;
+        let lo = (-first).max(0) as usize;
+        let hi = taps.min((input.len() as isize - first).max(0) as usize);
+        let mut acc = 0f32;
+        for j in lo..hi {
+            acc += input[(first + j as isize) as usize] * row[j];
+        }
+        output.push(acc);
+    }
+    output
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-12 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Blackman window over `x` in [-1, 1].
+fn blackman(x: f64) -> f64 {
+    if x.abs() > 1.0 {
+        return 0.0;
+    }
+    0.42 + 0.5 * (PI * x).cos() + 0.08 * (2.0 * PI * x).cos()
+}
+
+fn gcd(mut a: u32, mut b: u32) -> u32 {
+    while b != 0 {
+        let t = a % b;
+        a = b;
+        b = t;
+    }
+    a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(freq: f64, rate: u32, secs: f64) -> Vec<f32> {
+        let n = (rate as f64 * secs) as usize;
+        (0..n)
+            .map(|i| (2.0 * PI * freq * i as f64 / rate as f64).sin() as f32)
+            .collect()
+    }
+
+    fn rms(x: &[f32]) -> f32 {
+        (x.iter().map(|v| v * v).sum::<f32>() / x.len() as f32).sqrt()
+    }
+
+    /// Magnitude of `freq` in `x` (single-bin DFT), normalised so a unit sine gives ~0.5.
+    fn tone_level(x: &[f32], freq: f64, rate: u32) -> f64 {
+        let (mut re, mut im) = (0.0, 0.0);
+        for (i, v) in x.iter().enumerate() {
+            let ph = 2.0 * PI * freq * i as f64 / rate as f64;
+            re += *v as f64 * ph.cos();
+            im += *v as f64 * ph.sin();
+        }
+        (re * re + im * im).sqrt() / x.len() as f64
+    }
+
+    #[test]
+    fn test_resample_preserves_tone_at_common_rates() {
+        for rate in [8000, 22050, 32000, 44100, 48000] {
+            let input = sine(440.0, rate, 1.0);
+            let out = resample(&input, rate, 16000);
+            assert!((out.len() as i64 - 16000).abs() <= 1, "rate {rate}: len {}", out.len());
+
+            // Skip the filter's edge transients.
+            let body = &out[400..out.len() - 400];
+            assert!((rms(body) - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01, "rate {rate}: rms {}", rms(body));
+            assert!(tone_level(body, 440.0, 16000) > 0.49, "rate {rate}: 440 Hz lost");
+            assert!(tone_level(body, 1320.0, 16000) < 0.01, "rate {rate}: wrong playback speed");
+        }
+    }
+
+    #[test]
+    fn test_resample_removes_content_above_output_nyquist() {
+        // 12 kHz would alias to 4 kHz after naive decimation to 16 kHz.
+        let input = sine(12000.0, 48000, 1.0);
+        let out = resample(&input, 48000, 16000);
+        let body = &out[400..out.len() - 400];
+        assert!(rms(body) < 0.01, "aliased energy {}", rms(body));
+    }
+
+    #[test]
+    fn test_resample_identity() {
+        let input = sine(440.0, 16000, 0.1);
+        assert_eq!(resample(&input, 16000, 16000), input);
+    }
+
+    #[test]
+    fn test_downmix_averages_channels() {
+        let stereo = [1.0, 0.0, 0.5, 0.5, -1.0, 1.0];
+        assert_eq!(downmix_to_mono(&stereo, 2), vec![0.5, 0.5, 0.0]);
+
+        let surround: Vec<f32> = (0..12).map(|i| if i % 6 == 0 { 6.0 } else { 0.0 }).collect();
+        assert_eq!(downmix_to_mono(&surround, 6), vec![1.0, 1.0]);
+    }
+}
@@ -0,0 +1,55 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+/// GUI settings kept between sessions in `<config dir>/whisper-rust-tools/settings.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    /// Domain terms, one per line or comma separated, passed as hotwords.
+    pub glossary: String,
+}
+
+impl Settings {
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("whisper-rust-tools").join("settings.json"))
+    }
+
+    /// Saved settings, or the defaults when there are none or they cannot be read.
+    pub fn load() -> Self {
+        Self::path()
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let path = Self::path().ok_or_else(|| anyhow::anyhow!("no configuration directory"))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// The glossary split into terms.
+    pub fn hotwords(&self) -> Vec<String> {
+        self.glossary
+            .split(['\n', ',', '，', '、'])
+            .map(str::trim)
+            .filter(|term| !term.is_empty())
+            .map(str::to_string)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glossary_terms() {
+        let settings = Settings { glossary: "Kubernetes, gRPC\n\n 剪映，达芬奇、Final Cut Pro ".to_string() };
+        assert_eq!(settings.hotwords(), ["Kubernetes", "gRPC", "剪映", "达芬奇", "Final Cut Pro"]);
+    }
+}
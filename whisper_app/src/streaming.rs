@@ -0,0 +1,274 @@
+use anyhow::Result;
+
+use crate::audio::SAMPLE_RATE;
+use crate::whisper_engine::{Segment, Transcription};
+
+/// Tuning for `StreamingTranscriber`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamOptions {
+    /// Pending audio is decoded again whenever this much new audio has arrived,
+    /// so provisional text lags the input by at most this plus the decode time.
+    pub latency_secs: f64,
+    /// Segments ending closer than this to the end of the pending audio may
+    /// still change and stay provisional.
+    pub hold_back_secs: f64,
+    /// Once this much audio is pending, everything but the last segment is
+    /// finalized (all of it if there is only one). Keep it below 30 s so the
+    /// pending audio fits one Whisper window.
+    pub max_buffer_secs: f64,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            latency_secs: 1.0,
+            hold_back_secs: 1.0,
+            max_buffer_secs: 20.0,
+        }
+    }
+}
+
+/// Output of `StreamingTranscriber`; times are seconds from the start of the stream.
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// Current guess for the audio not finalized yet; replaces the previous one.
+    Provisional(Vec<Segment>),
+    /// A segment that will not change any more.
+    Final(Segment),
+}
+
+/// Transcribes audio that arrives in chunks, e.g. a recording still being written.
+///
+/// Audio that has not been finalized is kept in a buffer and decoded again every
+/// `latency_secs`. Segments that end well before the buffer end are finalized and
+/// cut from the buffer; the rest is reported as provisional.
+///
+/// The transcription itself is supplied by the caller (see
+/// `WhisperEngine::push_stream`) and gets the pending 16 kHz mono audio.
+pub struct StreamingTranscriber {
+    options: StreamOptions,
+    /// Audio not finalized yet.
+    buffer: Vec<f32>,
+    /// Stream position of `buffer[0]`, in samples.
+    buffer_start: usize,
+    /// Samples pushed since the buffer was last decoded.
+    undecoded: usize,
+    language: Option<String>,
+}
+
+impl StreamingTranscriber {
+    pub fn new(options: StreamOptions) -> Self {
+        Self {
+            options,
+            buffer: Vec::new(),
+            buffer_start: 0,
+            undecoded: 0,
+            language: None,
+        }
+    }
+
+    /// Language of the first non-empty transcription, reused for the rest of the stream.
+    pub fn language(&self) -> Option<&str> {
+        self.language.as_deref()
+    }
+
+    /// Seconds of audio received so far.
+    pub fn duration(&self) -> f64 {
+        (self.buffer_start + self.buffer.len()) as f64 / SAMPLE_RATE as f64
+    }
+
+    /// Add 16 kHz mono PCM, decoding the pending audio when `latency_secs` of it are new.
+    pub fn push<F>(&mut self, chunk: &[f32], transcribe: F) -> Result<Vec<StreamEvent>>
+    where
+        F: FnMut(&[f32]) -> Result<Transcription>,
+    {
+        self.buffer.extend_from_slice(chunk);
+        self.undecoded += chunk.len();
+        let step = (self.options.latency_secs * SAMPLE_RATE as f64) as usize;
+        if self.undecoded < step.max(1) {
+            return Ok(Vec::new());
+        }
+        self.decode(false, transcribe)
+    }
+
+    /// The stream has ended: decode what is left and finalize all of it.
+    pub fn finish<F>(&mut self, transcribe: F) -> Result<Vec<StreamEvent>>
+    where
+        F: FnMut(&[f32]) -> Result<Transcription>,
+    {
+        if self.buffer.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.decode(true, transcribe)
+    }
+
+    fn decode<F>(&mut self, finish: bool, mut transcribe: F) -> Result<Vec<StreamEvent>>
+    where
+        F: FnMut(&[f32]) -> Result<Transcription>,
+    {
+        self.undecoded = 0;
+        let transcription = transcribe(&self.buffer)?;
+        if self.language.is_none() && !transcription.detailed.is_empty() {
+            self.language = Some(transcription.language);
+        }
+
+        let buffer_secs = self.buffer.len() as f64 / SAMPLE_RATE as f64;
+        let mut segments = transcription.detailed;
+        let n_final = if finish {
+            segments.len()
+        } else {
+            let stable = segments
+                .iter()
+                .take_while(|s| s.end <= buffer_secs - self.options.hold_back_secs)
+                .count();
+            if buffer_secs >= self.options.max_buffer_secs {
+                stable.max(segments.len().saturating_sub(1)).max(1).min(segments.len())
+            } else {
+                stable
+            }
+        };
+
+        // Cut the finalized audio; without any segment, an overlong buffer is
+        // silence or noise and is dropped up to the hold-back margin.
+        let cut_secs = match n_final {
+            0 if !finish && buffer_secs >= self.options.max_buffer_secs => buffer_secs - self.options.hold_back_secs,
+            0 if finish => buffer_secs,
+            0 => 0.0,
+            n if n == segments.len() && finish => buffer_secs,
+            n => segments[n - 1].end,
+        };
+        let offset = self.buffer_start as f64 / SAMPLE_RATE as f64;
+        for segment in &mut segments {
+            shift(segment, offset);
+        }
+        let provisional = segments.split_off(n_final);
+        let mut events: Vec<StreamEvent> = segments.into_iter().map(StreamEvent::Final).collect();
+        events.push(StreamEvent::Provisional(provisional));
+
+        let cut = ((cut_secs.max(0.0) * SAMPLE_RATE as f64) as usize).min(self.buffer.len());
+        self.buffer.drain(..cut);
+        self.buffer_start += cut;
+        Ok(events)
+    }
+}
+
+fn shift(segment: &mut Segment, offset: f64) {
+    segment.start += offset;
+    segment.end += offset;
+    for word in &mut segment.words {
+        word.start += offset;
+        word.end += offset;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transcriber::{segment, transcription};
+    use crate::whisper_engine::load_audio;
+
+    /// Half-second "words" encoded as constant sample values; 0.0 is a pause.
+    fn recording() -> Vec<f32> {
+        let words = [1, 2, 0, 3, 4, 5, 0, 0, 6, 7, 0];
+        words
+            .iter()
+            .flat_map(|&w| std::iter::repeat_n(w as f32 / 100.0, SAMPLE_RATE / 2))
+            .collect()
+    }
+
+    /// Reads the "words" back: one segment per run of equal non-zero samples.
+    fn fake_transcribe(pcm: &[f32]) -> Result<Transcription> {
+        let mut detailed: Vec<Segment> = Vec::new();
+        let mut run_start = 0;
+        for i in 1..=pcm.len() {
+            if i == pcm.len() || pcm[i] != pcm[run_start] {
+                let word = (pcm[run_start] * 100.0).round() as u32;
+                if word != 0 {
+                    detailed.push(segment(run_start as f64 / SAMPLE_RATE as f64, i as f64 / SAMPLE_RATE as f64, &format!("w{word}")));
+                }
+                run_start = i;
+            }
+        }
+        Ok(transcription(detailed))
+    }
+
+    /// 16-bit mono PCM WAV.
+    fn write_wav(path: &std::path::Path, pcm: &[f32]) {
+        use byteorder::{LittleEndian, WriteBytesExt};
+        let data_len = (pcm.len() * 2) as u32;
+        let mut out = Vec::new();
+        out.extend_from_slice(b"RIFF");
+        out.write_u32::<LittleEndian>(36 + data_len).unwrap();
+        out.extend_from_slice(b"WAVEfmt ");
+        out.write_u32::<LittleEndian>(16).unwrap();
+        out.write_u16::<LittleEndian>(1).unwrap(); // PCM
+        out.write_u16::<LittleEndian>(1).unwrap(); // mono
+        out.write_u32::<LittleEndian>(SAMPLE_RATE as u32).unwrap();
+        out.write_u32::<LittleEndian>(SAMPLE_RATE as u32 * 2).unwrap();
+        out.write_u16::<LittleEndian>(2).unwrap();
+        out.write_u16::<LittleEndian>(16).unwrap();
+        out.extend_from_slice(b"data");
+        out.write_u32::<LittleEndian>(data_len).unwrap();
+        for x in pcm {
+            out.write_i16::<LittleEndian>((x * i16::MAX as f32).round() as i16).unwrap();
+        }
+        std::fs::write(path, out).unwrap();
+    }
+
+    #[test]
+    fn test_wav_in_small_chunks() {
+        let path = std::env::temp_dir().join(format!("whisper-stream-test-{}.wav", std::process::id()));
+        write_wav(&path, &recording());
+        let (pcm, _) = load_audio(&path, None).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let options = StreamOptions { latency_secs: 0.5, hold_back_secs: 0.5, max_buffer_secs: 20.0 };
+        let mut stream = StreamingTranscriber::new(options);
+        let mut finals: Vec<Segment> = Vec::new();
+        let mut provisional_seen = 0;
+        // 100 ms chunks, as if read from a growing file.
+        for chunk in pcm.chunks(SAMPLE_RATE / 10) {
+            for event in stream.push(chunk, fake_transcribe).unwrap() {
+                match event {
+                    StreamEvent::Final(segment) => {
+                        // Finalized no later than a hold-back plus one latency step after it ended.
+                        assert!(stream.duration() - segment.end <= 1.0 + 1e-6, "{segment:?}");
+                        finals.push(segment);
+                    }
+                    StreamEvent::Provisional(segments) => provisional_seen += segments.len(),
+                }
+            }
+        }
+        for event in stream.finish(fake_transcribe).unwrap() {
+            if let StreamEvent::Final(segment) = event {
+                finals.push(segment);
+            }
+        }
+
+        assert!(provisional_seen > 0);
+        let texts: Vec<&str> = finals.iter().map(|s| s.text.as_str()).collect();
+        // Adjacent words without a pause come out as separate runs of the fake decoder.
+        assert_eq!(texts, ["w1", "w2", "w3", "w4", "w5", "w6", "w7"]);
+        assert!((finals[2].start - 1.5).abs() < 1e-3 && (finals[2].end - 2.0).abs() < 1e-3);
+        assert!((finals[6].start - 4.5).abs() < 1e-3 && (finals[6].end - 5.0).abs() < 1e-3);
+        assert_eq!(stream.language(), Some("en"));
+    }
+
+    #[test]
+    fn test_long_buffer_is_forced_final() {
+        // One word that never ends: it must be finalized once the buffer is full.
+        let options = StreamOptions { latency_secs: 1.0, hold_back_secs: 1.0, max_buffer_secs: 5.0 };
+        let mut stream = StreamingTranscriber::new(options);
+        let second = vec![0.01f32; SAMPLE_RATE];
+        let mut finals = 0;
+        for _ in 0..6 {
+            for event in stream.push(&second, fake_transcribe).unwrap() {
+                if matches!(event, StreamEvent::Final(_)) {
+                    finals += 1;
+                }
+            }
+        }
+        assert_eq!(finals, 1);
+        assert!(stream.buffer.len() < SAMPLE_RATE * 2);
+    }
+}
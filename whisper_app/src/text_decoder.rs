@@ -0,0 +1,541 @@
+use candle_core::{DType, Device, IndexOp, Result, Shape, Tensor, D};
+use candle_nn::{Embedding, LayerNorm, Module, VarBuilder};
+use candle_transformers::models::whisper::Config;
+use candle_transformers::{quantized_nn, quantized_var_builder};
+
+use crate::decoding::TokenDecoder;
+
+// Weight-compatible with candle's `whisper::model::TextDecoder` (same tensor names),
+// but the attention layers also hand back their raw query/key scores so the
+// cross-attention can be used to align words with audio frames.
+// Loads from either a safetensors or a GGUF checkpoint; only the projections
+// stay quantized, everything else is dequantized like candle's quantized model.
+
+/// Where the weights come from; shared with `AudioEncoder`.
+#[derive(Clone)]
+pub(crate) enum Weights<'a> {
+    Full(VarBuilder<'a>),
+    Quantized(quantized_var_builder::VarBuilder),
+}
+
+impl Weights<'_> {
+    pub(crate) fn pp(&self, s: impl ToString) -> Self {
+        match self {
+            Self::Full(vb) => Self::Full(vb.pp(s)),
+            Self::Quantized(vb) => Self::Quantized(vb.pp(s)),
+        }
+    }
+
+    pub(crate) fn device(&self) -> &Device {
+        match self {
+            Self::Full(vb) => vb.device(),
+            Self::Quantized(vb) => vb.device(),
+        }
+    }
+
+    /// Type of the tensors loaded; quantized weights are dequantized to f32.
+    pub(crate) fn dtype(&self) -> DType {
+        match self {
+            Self::Full(vb) => vb.dtype(),
+            Self::Quantized(_) => DType::F32,
+        }
+    }
+
+    pub(crate) fn tensor(&self, shape: impl Into<Shape>, name: &str) -> Result<Tensor> {
+        match self {
+            Self::Full(vb) => vb.get(shape, name),
+            Self::Quantized(vb) => vb.get(shape, name)?.dequantize(vb.device()),
+        }
+    }
+
+    pub(crate) fn linear(&self, in_dim: usize, out_dim: usize, bias: bool) -> Result<Linear> {
+        Ok(match self {
+            Self::Full(vb) => Linear::Full(candle_nn::linear_b(in_dim, out_dim, bias, vb.clone())?),
+            Self::Quantized(vb) => Linear::Quantized(quantized_nn::linear_b(in_dim, out_dim, bias, vb.clone())?),
+        })
+    }
+
+    pub(crate) fn layer_norm(&self, size: usize) -> Result<LayerNorm> {
+        match self {
+            Self::Full(vb) => candle_nn::layer_norm(size, 1e-5, vb.clone()),
+            Self::Quantized(vb) => quantized_nn::layer_norm(size, 1e-5, vb.clone()),
+        }
+    }
+
+    fn embedding(&self, in_size: usize, out_size: usize) -> Result<Embedding> {
+        Ok(Embedding::new(self.tensor((in_size, out_size), "weight")?, out_size))
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum Linear {
+    Full(candle_nn::Linear),
+    Quantized(quantized_nn::Linear),
+}
+
+impl Module for Linear {
+    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Full(linear) => linear.forward(x),
+            Self::Quantized(linear) => linear.forward(x),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct MultiHeadAttention {
+    query: Linear,
+    key: Linear,
+    value: Linear,
+    out: Linear,
+    n_head: usize,
+    kv_cache: Option<(Tensor, Tensor)>,
+}
+
+impl MultiHeadAttention {
+    pub(crate) fn load(n_state: usize, n_head: usize, vb: Weights) -> Result<Self> {
+        Ok(Self {
+            query: vb.pp("q_proj").linear(n_state, n_state, true)?,
+            key: vb.pp("k_proj").linear(n_state, n_state, false)?,
+            value: vb.pp("v_proj").linear(n_state, n_state, true)?,
+            out: vb.pp("out_proj").linear(n_state, n_state, true)?,
+            n_head,
+            kv_cache: None,
+        })
+    }
+
+    /// Self-attention over `x`, appending its keys and values to `past`.
+    /// Returns the output and the keys/values of every position seen so far.
+    pub(crate) fn forward_self(&self, x: &Tensor, mask: Option<&Tensor>, past: Option<&(Tensor, Tensor)>) -> Result<(Tensor, (Tensor, Tensor))> {
+        let q = self.query.forward(x)?;
+        let mut k = self.key.forward(x)?;
+        let mut v = self.value.forward(x)?;
+        if let Some((past_k, past_v)) = past {
+            k = Tensor::cat(&[past_k, &k], 1)?;
+            v = Tensor::cat(&[past_v, &v], 1)?;
+        }
+        let (wv, _) = self.qkv_attention(&q, &k, &v, mask)?;
+        Ok((self.out.forward(&wv)?, (k, v)))
+    }
+
+    /// Cross-attention to the audio. Returns the output and the pre-softmax
+    /// scores `(batch, head, query, key)`.
+    fn forward_cross(&mut self, x: &Tensor, xa: &Tensor, flush_cache: bool) -> Result<(Tensor, Tensor)> {
+        let q = self.query.forward(x)?;
+        // Cross-attention keys only depend on the audio, so they are cached per window.
+        if flush_cache {
+            self.kv_cache = None;
+        }
+        let (k, v) = match &self.kv_cache {
+            Some((k, v)) => (k.clone(), v.clone()),
+            None => {
+                let k = self.key.forward(xa)?;
+                let v = self.value.forward(xa)?;
+                self.kv_cache = Some((k.clone(), v.clone()));
+                (k, v)
+            }
+        };
+        let (wv, qk) = self.qkv_attention(&q, &k, &v, None)?;
+        Ok((self.out.forward(&wv)?, qk))
+    }
+
+    fn reshape_head(&self, x: &Tensor) -> Result<Tensor> {
+        let (n_batch, n_ctx, n_state) = x.dims3()?;
+        x.reshape((n_batch, n_ctx, self.n_head, n_state / self.n_head))?.transpose(1, 2)
+    }
+
+    /// `mask` is already cut to `(queries, keys)`.
+    fn qkv_attention(&self, q: &Tensor, k: &Tensor, v: &Tensor, mask: Option<&Tensor>) -> Result<(Tensor, Tensor)> {
+        let n_state = q.dim(D::Minus1)?;
+        let scale = ((n_state / self.n_head) as f64).powf(-0.25);
+        let q = (self.reshape_head(q)? * scale)?;
+        let k = (self.reshape_head(k)?.transpose(2, 3)? * scale)?;
+        let v = self.reshape_head(v)?.contiguous()?;
+        let mut qk = q.matmul(&k)?;
+        if let Some(mask) = mask {
+            qk = qk.broadcast_add(mask)?;
+        }
+        let w = candle_nn::ops::softmax_last_dim(&qk)?;
+        let wv = w.matmul(&v)?.transpose(1, 2)?.flatten_from(2)?;
+        Ok((wv, qk))
+    }
+}
+
+#[derive(Debug, Clone)]
+struct DecoderBlock {
+    attn: MultiHeadAttention,
+    attn_ln: LayerNorm,
+    cross_attn: MultiHeadAttention,
+    cross_attn_ln: LayerNorm,
+    mlp_linear1: Linear,
+    mlp_linear2: Linear,
+    mlp_ln: LayerNorm,
+}
+
+impl DecoderBlock {
+    fn load(n_state: usize, n_head: usize, vb: Weights) -> Result<Self> {
+        let n_mlp = n_state * 4;
+        Ok(Self {
+            attn: MultiHeadAttention::load(n_state, n_head, vb.pp("self_attn"))?,
+            attn_ln: vb.pp("self_attn_layer_norm").layer_norm(n_state)?,
+            cross_attn: MultiHeadAttention::load(n_state, n_head, vb.pp("encoder_attn"))?,
+            cross_attn_ln: vb.pp("encoder_attn_layer_norm").layer_norm(n_state)?,
+            mlp_linear1: vb.pp("fc1").linear(n_state, n_mlp, true)?,
+            mlp_linear2: vb.pp("fc2").linear(n_mlp, n_state, true)?,
+            mlp_ln: vb.pp("final_layer_norm").layer_norm(n_state)?,
+        })
+    }
+
+    /// Returns the block output, the cross-attention scores and the self-attention keys/values.
+    fn forward(
+        &mut self,
+        x: &Tensor,
+        xa: &Tensor,
+        mask: Option<&Tensor>,
+        flush_kv_cache: bool,
+        past: Option<&(Tensor, Tensor)>,
+    ) -> Result<(Tensor, Tensor, (Tensor, Tensor))> {
+        let (attn, kv) = self.attn.forward_self(&self.attn_ln.forward(x)?, mask, past)?;
+        let x = (x + attn)?;
+        let (cross, qk) = self
+            .cross_attn
+            .forward_cross(&self.cross_attn_ln.forward(&x)?, xa, flush_kv_cache)?;
+        let x = (x + cross)?;
+        let mlp = self
+            .mlp_linear2
+            .forward(&self.mlp_linear1.forward(&self.mlp_ln.forward(&x)?)?.gelu()?)?;
+        Ok(((x + mlp)?, qk, kv))
+    }
+}
+
+/// Self-attention keys and values of the tokens decoded so far, one pair per layer.
+/// Tensors are reference counted, so cloning is cheap and lets beams branch.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    layers: Vec<(Tensor, Tensor)>,
+}
+
+impl KvCache {
+    /// Number of tokens already fed through the decoder.
+    pub fn len(&self) -> usize {
+        self.layers.first().map_or(0, |(k, _)| k.dim(1).unwrap_or(0))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whisper text decoder that can also report its cross-attention scores.
+#[derive(Debug, Clone)]
+pub struct TextDecoder {
+    token_embedding: Embedding,
+    positional_embedding: Tensor,
+    blocks: Vec<DecoderBlock>,
+    ln: LayerNorm,
+    mask: Tensor,
+}
+
+impl TextDecoder {
+    /// Load from the `model.decoder` prefix of a Hugging Face Whisper checkpoint.
+    pub fn load(vb: VarBuilder, cfg: &Config) -> Result<Self> {
+        Self::load_weights(Weights::Full(vb), cfg)
+    }
+
+    /// Load from the `model.decoder` prefix of a GGUF checkpoint.
+    pub fn load_quantized(vb: quantized_var_builder::VarBuilder, cfg: &Config) -> Result<Self> {
+        Self::load_weights(Weights::Quantized(vb), cfg)
+    }
+
+    fn load_weights(vb: Weights, cfg: &Config) -> Result<Self> {
+        let n_state = cfg.d_model;
+        let n_ctx = cfg.max_target_positions;
+        let token_embedding = vb.pp("embed_tokens").embedding(cfg.vocab_size, n_state)?;
+        let positional_embedding = vb.tensor((n_ctx, n_state), "embed_positions.weight")?;
+        let blocks = (0..cfg.decoder_layers)
+            .map(|i| DecoderBlock::load(n_state, cfg.decoder_attention_heads, vb.pp(format!("layers.{i}"))))
+            .collect::<Result<Vec<_>>>()?;
+        let ln = vb.pp("layer_norm").layer_norm(n_state)?;
+        let mask: Vec<_> = (0..n_ctx)
+            .flat_map(|i| (0..n_ctx).map(move |j| if j > i { f32::NEG_INFINITY } else { 0f32 }))
+            .collect();
+        let mask = Tensor::from_vec(mask, (n_ctx, n_ctx), vb.device())?.to_dtype(vb.dtype())?;
+        Ok(Self {
+            token_embedding,
+            positional_embedding,
+            blocks,
+            ln,
+            mask,
+        })
+    }
+
+    /// Run the whole token sequence `x` from position 0.
+    pub fn forward(&mut self, x: &Tensor, xa: &Tensor, flush_kv_cache: bool) -> Result<Tensor> {
+        Ok(self.run(x, xa, flush_kv_cache, None)?.0)
+    }
+
+    /// Feed only the tokens not in `cache` yet and extend it. The audio's
+    /// cross-attention keys are recomputed when the cache is empty.
+    pub fn forward_cached(&mut self, x: &Tensor, xa: &Tensor, cache: &mut KvCache) -> Result<Tensor> {
+        let flush_kv_cache = cache.is_empty();
+        Ok(self.run(x, xa, flush_kv_cache, Some(cache))?.0)
+    }
+
+    /// Like `forward`, also returning each layer's pre-softmax cross-attention
+    /// scores with shape `(batch, head, token, audio_position)`.
+    pub fn forward_with_cross_attention(&mut self, x: &Tensor, xa: &Tensor, flush_kv_cache: bool) -> Result<(Tensor, Vec<Tensor>)> {
+        self.run(x, xa, flush_kv_cache, None)
+    }
+
+    fn run(&mut self, x: &Tensor, xa: &Tensor, flush_kv_cache: bool, mut cache: Option<&mut KvCache>) -> Result<(Tensor, Vec<Tensor>)> {
+        let offset = cache.as_ref().map_or(0, |c| c.len());
+        let n_tokens = x.dim(D::Minus1)?;
+        let token_embedding = self.token_embedding.forward(x)?;
+        let positional_embedding = self.positional_embedding.narrow(0, offset, n_tokens)?;
+        let mut x = token_embedding.broadcast_add(&positional_embedding)?;
+        // A single new token may look at everything before it.
+        let mask = match n_tokens {
+            1 => None,
+            _ => Some(self.mask.i((offset..offset + n_tokens, 0..offset + n_tokens))?),
+        };
+        let mut cross_qks = Vec::with_capacity(self.blocks.len());
+        let mut layers = Vec::with_capacity(self.blocks.len());
+        for (i, block) in self.blocks.iter_mut().enumerate() {
+            let past = cache.as_ref().and_then(|c| c.layers.get(i));
+            let (out, qk, kv) = block.forward(&x, xa, mask.as_ref(), flush_kv_cache, past)?;
+            x = out;
+            cross_qks.push(qk);
+            layers.push(kv);
+        }
+        if let Some(cache) = cache.as_mut() {
+            cache.layers = layers;
+        }
+        Ok((self.ln.forward(&x)?, cross_qks))
+    }
+
+    pub fn final_linear(&self, x: &Tensor) -> Result<Tensor> {
+        let b_size = x.dim(0)?;
+        let w = self.token_embedding.embeddings().broadcast_left(b_size)?;
+        x.matmul(&w.t()?)
+    }
+}
+
+/// Decodes one window against its encoder output, feeding only the newest token
+/// at each step and keeping the self-attention keys/values in the state.
+pub struct WindowDecoder<'a> {
+    decoder: &'a mut TextDecoder,
+    audio_features: &'a Tensor,
+    /// Prompt position of `<|startoftranscript|>` and the `<|nospeech|>` token.
+    no_speech: Option<(usize, u32)>,
+    no_speech_prob: Option<f32>,
+}
+
+impl<'a> WindowDecoder<'a> {
+    pub fn new(decoder: &'a mut TextDecoder, audio_features: &'a Tensor) -> Self {
+        Self {
+            decoder,
+            audio_features,
+            no_speech: None,
+            no_speech_prob: None,
+        }
+    }
+
+    /// Also measure the probability of `no_speech_token` following the
+    /// `<|startoftranscript|>` at `sot_index` of the prompt.
+    pub fn with_no_speech(mut self, sot_index: usize, no_speech_token: u32) -> Self {
+        self.no_speech = Some((sot_index, no_speech_token));
+        self
+    }
+
+    /// Set once `start` has run with `with_no_speech`.
+    pub fn no_speech_prob(&self) -> Option<f32> {
+        self.no_speech_prob
+    }
+
+    fn logits_at(&self, hidden: &Tensor, position: usize) -> anyhow::Result<Vec<f32>> {
+        let logits = self.decoder.final_linear(&hidden.narrow(1, position, 1)?)?;
+        Ok(logits.squeeze(0)?.squeeze(0)?.to_dtype(DType::F32)?.to_vec1()?)
+    }
+
+    fn forward(&mut self, tokens: &[u32], cache: &mut KvCache) -> anyhow::Result<Tensor> {
+        let input = Tensor::new(tokens, self.audio_features.device())?.unsqueeze(0)?;
+        Ok(self.decoder.forward_cached(&input, self.audio_features, cache)?)
+    }
+}
+
+impl TokenDecoder for WindowDecoder<'_> {
+    type State = KvCache;
+
+    fn start(&mut self, prompt: &[u32]) -> anyhow::Result<(KvCache, Vec<f32>)> {
+        let mut cache = KvCache::default();
+        let hidden = self.forward(prompt, &mut cache)?;
+        if let Some((sot_index, no_speech_token)) = self.no_speech {
+            let logits = self.logits_at(&hidden, sot_index)?;
+            let prob = crate::decoding::log_softmax(&logits)
+                .get(no_speech_token as usize)
+                .map_or(0.0, |l| l.exp());
+            self.no_speech_prob = Some(prob);
+        }
+        let logits = self.logits_at(&hidden, prompt.len() - 1)?;
+        Ok((cache, logits))
+    }
+
+    fn step(&mut self, cache: &mut KvCache, token: u32) -> anyhow::Result<Vec<f32>> {
+        let hidden = self.forward(&[token], cache)?;
+        self.logits_at(&hidden, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candle_core::{DType, Device};
+    use candle_nn::VarMap;
+    use candle_transformers::models::whisper::model::Whisper;
+
+    fn tiny_config() -> Config {
+        config_with_width(16)
+    }
+
+    fn config_with_width(d_model: usize) -> Config {
+        Config {
+            num_mel_bins: 8,
+            max_source_positions: 12,
+            d_model,
+            encoder_attention_heads: 2,
+            encoder_layers: 1,
+            vocab_size: 40,
+            max_target_positions: 24,
+            decoder_attention_heads: 2,
+            decoder_layers: 2,
+            suppress_tokens: vec![],
+        }
+    }
+
+    fn random_weights(cfg: &Config) -> VarMap {
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        Whisper::load(&vb, cfg.clone()).unwrap();
+        for (i, var) in varmap.all_vars().iter().enumerate() {
+            let init = Tensor::randn(0f32, 0.5, var.shape(), &device).unwrap();
+            var.set(&(init + (i as f64 * 1e-3)).unwrap()).unwrap();
+        }
+        varmap
+    }
+
+    /// Candle's model and ours loaded from the same randomly initialised weights.
+    fn load_both() -> (Whisper, TextDecoder) {
+        let varmap = random_weights(&tiny_config());
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+        let candle_model = Whisper::load(&vb, tiny_config()).unwrap();
+        let ours = TextDecoder::load(vb.pp("model.decoder"), &tiny_config()).unwrap();
+        (candle_model, ours)
+    }
+
+    #[test]
+    fn test_matches_candle_decoder() {
+        let (mut candle_model, mut ours) = load_both();
+        let device = Device::Cpu;
+        let xa = Tensor::randn(0f32, 1.0, (1, 12, 16), &device).unwrap();
+        let tokens = Tensor::new(&[[1u32, 5, 7, 3]], &device).unwrap();
+
+        let expected = candle_model.decoder.forward(&tokens, &xa, true).unwrap();
+        let expected = candle_model.decoder.final_linear(&expected).unwrap();
+        let (hidden, cross_qks) = ours.forward_with_cross_attention(&tokens, &xa, true).unwrap();
+        let actual = ours.final_linear(&hidden).unwrap();
+
+        let diff = (expected - actual).unwrap().abs().unwrap().max_all().unwrap().to_scalar::<f32>().unwrap();
+        assert!(diff < 1e-4, "max difference {diff}");
+        assert_eq!(cross_qks.len(), 2);
+        assert_eq!(cross_qks[0].dims(), &[1, 2, 4, 12]);
+    }
+
+    #[test]
+    fn test_quantized_close_to_full() {
+        use crate::quantize::{quantize_safetensors, Quantization};
+
+        // Wide enough that the projections fill whole quantization blocks.
+        let cfg = config_with_width(32);
+        let varmap = random_weights(&cfg);
+        let dir = std::env::temp_dir().join(format!("whisper-quantize-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let safetensors = dir.join("model.safetensors");
+        let gguf = dir.join("model.gguf");
+        varmap.save(&safetensors).unwrap();
+        quantize_safetensors(&safetensors, &gguf, Quantization::Q8_0).unwrap();
+
+        let device = Device::Cpu;
+        let vb = VarBuilder::from_varmap(&varmap, DType::F32, &device);
+        let mut full = TextDecoder::load(vb.pp("model.decoder"), &cfg).unwrap();
+        let qvb = quantized_var_builder::VarBuilder::from_gguf(&gguf, &device).unwrap();
+        // candle's quantized encoder must accept the converted file too.
+        candle_transformers::models::whisper::quantized_model::Whisper::load(&qvb, cfg.clone()).unwrap();
+        let mut quantized = TextDecoder::load_quantized(qvb.pp("model.decoder"), &cfg).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let xa = Tensor::randn(0f32, 1.0, (1, 12, 32), &device).unwrap();
+        let tokens = Tensor::new(&[[1u32, 5, 7, 3]], &device).unwrap();
+        let hidden = full.forward(&tokens, &xa, true).unwrap();
+        let expected = full.final_linear(&hidden).unwrap();
+        let hidden = quantized.forward(&tokens, &xa, true).unwrap();
+        let actual = quantized.final_linear(&hidden).unwrap();
+
+        // Relative L2 error; single logits can drift more with random weights.
+        let norm = |t: &Tensor| t.sqr().unwrap().sum_all().unwrap().sqrt().unwrap().to_scalar::<f32>().unwrap();
+        let error = norm(&(&expected - &actual).unwrap()) / norm(&expected);
+        assert!(error > 0.0, "nothing was quantized");
+        assert!(error < 0.2, "relative error {error}");
+    }
+
+    /// The decoding path before the KV cache: the whole sequence at every step.
+    struct UncachedDecoder<'a> {
+        decoder: &'a mut TextDecoder,
+        xa: &'a Tensor,
+    }
+
+    impl UncachedDecoder<'_> {
+        fn logits(&mut self, tokens: &[u32], flush: bool) -> anyhow::Result<Vec<f32>> {
+            let input = Tensor::new(tokens, &Device::Cpu)?.unsqueeze(0)?;
+            let hidden = self.decoder.forward(&input, self.xa, flush)?;
+            let logits = self.decoder.final_linear(&hidden.narrow(1, tokens.len() - 1, 1)?)?;
+            Ok(logits.flatten_all()?.to_vec1()?)
+        }
+    }
+
+    impl TokenDecoder for UncachedDecoder<'_> {
+        type State = Vec<u32>;
+
+        fn start(&mut self, prompt: &[u32]) -> anyhow::Result<(Vec<u32>, Vec<f32>)> {
+            Ok((prompt.to_vec(), self.logits(prompt, true)?))
+        }
+
+        fn step(&mut self, state: &mut Vec<u32>, token: u32) -> anyhow::Result<Vec<f32>> {
+            state.push(token);
+            self.logits(state, false)
+        }
+    }
+
+    #[test]
+    fn test_cached_decoding_matches_uncached() {
+        use crate::decoding::{decode, DecodingOptions};
+        use rand::{rngs::StdRng, SeedableRng};
+
+        let (_, mut decoder) = load_both();
+        let xa = Tensor::randn(0f32, 1.0, (1, 12, 16), &Device::Cpu).unwrap();
+        let prompt = [1u32, 5, 7];
+        let eot = 39;
+        for (beam_size, temperature) in [(None, 0.0), (Some(3), 0.0), (None, 0.7)] {
+            let options = DecodingOptions { beam_size, ..Default::default() };
+            let mut cached = WindowDecoder::new(&mut decoder, &xa);
+            let mut rng = StdRng::seed_from_u64(7);
+            let expected = decode(&mut cached, &prompt, eot, 16, temperature, &options, &mut rng).unwrap();
+            let mut uncached = UncachedDecoder { decoder: &mut decoder, xa: &xa };
+            let mut rng = StdRng::seed_from_u64(7);
+            let actual = decode(&mut uncached, &prompt, eot, 16, temperature, &options, &mut rng).unwrap();
+
+            assert_eq!(expected.tokens, actual.tokens, "beam {beam_size:?} temperature {temperature}");
+            assert!((expected.sum_logprob - actual.sum_logprob).abs() < 1e-3);
+        }
+    }
+}
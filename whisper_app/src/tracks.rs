@@ -0,0 +1,154 @@
+use anyhow::Result;
+use serde::Serialize;
+use std::path::Path;
+use symphonia::core::codecs::CODEC_TYPE_NULL;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+use crate::ffmpeg;
+
+/// One audio track of a media file.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AudioTrack {
+    /// Position among the file's audio tracks, from 0; what
+    /// `DecodingOptions::audio_track` selects. Counts every audio stream when
+    /// ffprobe lists the file, and only those symphonia can decode otherwise.
+    pub index: usize,
+    /// Short codec name such as `aac` or `opus`.
+    pub codec: String,
+    /// Language tag from the container, e.g. `eng`.
+    pub language: Option<String>,
+    pub title: Option<String>,
+    pub channels: Option<usize>,
+    pub sample_rate: Option<u32>,
+}
+
+impl AudioTrack {
+    /// Label for merged subtitles: the title, else the language, else `Track N`.
+    pub fn label(&self) -> String {
+        self.title
+            .clone()
+            .or_else(|| self.language.clone())
+            .unwrap_or_else(|| format!("Track {}", self.index + 1))
+    }
+}
+
+impl std::fmt::Display for AudioTrack {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "#{} {}", self.index + 1, self.codec)?;
+        if let Some(channels) = self.channels {
+            write!(f, ", {} ch", channels)?;
+        }
+        if let Some(rate) = self.sample_rate {
+            write!(f, ", {} Hz", rate)?;
+        }
+        if let Some(language) = &self.language {
+            write!(f, ", {}", language)?;
+        }
+        if let Some(title) = &self.title {
+            write!(f, ", \"{}\"", title)?;
+        }
+        Ok(())
+    }
+}
+
+/// The audio tracks of `path`. Uses ffprobe when it is installed, since only
+/// it reads track titles, and symphonia otherwise.
+pub fn list_audio_tracks(path: impl AsRef<Path>) -> Result<Vec<AudioTrack>> {
+    let path = path.as_ref();
+    if !path.is_file() {
+        anyhow::bail!("file not found: {}", path.display());
+    }
+    if ffmpeg::is_available() {
+        match ffmpeg::probe_audio_tracks(path) {
+            Ok(tracks) => return Ok(tracks),
+            Err(e) => log::warn!("ffprobe cannot read {} ({}), trying symphonia", path.display(), e),
+        }
+    }
+    let format = open(path)?;
+    let tracks: Vec<AudioTrack> = audio_tracks(format.as_ref())
+        .enumerate()
+        .map(|(index, track)| AudioTrack {
+            index,
+            codec: symphonia::default::get_codecs()
+                .get_codec(track.codec_params.codec)
+                .map_or("unknown", |codec| codec.short_name)
+                .to_string(),
+            language: track.language.clone(),
+            title: None,
+            channels: track.codec_params.channels.map(|c| c.count()),
+            sample_rate: track.codec_params.sample_rate,
+        })
+        .collect();
+    if tracks.is_empty() {
+        anyhow::bail!("no audio track found in {}", path.display());
+    }
+    Ok(tracks)
+}
+
+/// Open `path` with symphonia's probe.
+pub(crate) fn open(path: &Path) -> Result<Box<dyn FormatReader>> {
+    let src = std::fs::File::open(path)?;
+    let mss = MediaSourceStream::new(Box::new(src), Default::default());
+    let probed = symphonia::default::get_probe().format(
+        &Hint::new(),
+        mss,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    Ok(probed.format)
+}
+
+/// The tracks symphonia knows a codec for, in container order.
+pub(crate) fn audio_tracks(format: &dyn FormatReader) -> impl Iterator<Item = &Track> {
+    format.tracks().iter().filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ffmpeg::tests::synthesize;
+
+    #[test]
+    fn test_lists_tracks_with_metadata() {
+        let Some(path) = synthesize(
+            "tracks.mka",
+            &[
+                "-f", "lavfi", "-i", "sine=frequency=300:duration=1",
+                "-f", "lavfi", "-i", "sine=frequency=900:duration=1",
+                "-map", "0:a", "-map", "1:a", "-c:a", "pcm_s16le",
+                "-metadata:s:a:0", "language=eng", "-metadata:s:a:1", "language=deu",
+                "-metadata:s:a:1", "title=Commentary",
+            ],
+        ) else {
+            return;
+        };
+        let tracks = list_audio_tracks(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[0].language.as_deref(), Some("eng"));
+        assert_eq!(tracks[0].channels, Some(1));
+        assert_eq!(tracks[1].index, 1);
+        assert_eq!(tracks[1].language.as_deref(), Some("deu"));
+        assert_eq!(tracks[1].label(), "Commentary");
+    }
+
+    #[test]
+    fn test_label() {
+        let track = AudioTrack {
+            index: 2,
+            codec: "aac".into(),
+            language: Some("eng".into()),
+            title: None,
+            channels: Some(2),
+            sample_rate: Some(48000),
+        };
+        assert_eq!(track.label(), "eng");
+        assert_eq!(track.to_string(), "#3 aac, 2 ch, 48000 Hz, eng");
+        let titled = AudioTrack { title: Some("Host".into()), ..track.clone() };
+        assert_eq!(titled.label(), "Host");
+        assert_eq!(AudioTrack { language: None, ..track }.label(), "Track 3");
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::Result;
+use std::collections::HashMap;
+
+use crate::decoding::DecodingOptions;
+use crate::job::{Job, Progress};
+use crate::tracks::{self, AudioTrack};
+use crate::whisper_engine::{Segment, Transcription, WhisperEngine};
+
+/// Anything that turns an audio file into segments; the batch pipeline and the
+/// GUI only depend on this.
+pub trait Transcriber: Send {
+    /// Transcribe `audio_path`, reporting progress to `job` and stopping with a
+    /// `Cancelled` error when it is cancelled.
+    fn transcribe_with(&mut self, audio_path: &str, options: &DecodingOptions, job: &mut Job) -> Result<Transcription>;
+
+    /// The audio tracks of `audio_path`, for `DecodingOptions::audio_track`.
+    fn audio_tracks(&self, audio_path: &str) -> Result<Vec<AudioTrack>> {
+        tracks::list_audio_tracks(audio_path)
+    }
+
+    /// Another transcriber on the same model weights, so several files can run
+    /// at once (see `batch::run_batch_parallel`); `None` when it cannot be shared.
+    fn share(&self) -> Option<Box<dyn Transcriber>> {
+        None
+    }
+}
+
+impl Transcriber for WhisperEngine {
+    fn transcribe_with(&mut self, audio_path: &str, options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        WhisperEngine::transcribe_with(self, audio_path, options, job)
+    }
+
+    fn share(&self) -> Option<Box<dyn Transcriber>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// A segment with confident scores and no words, for the mock and tests.
+pub(crate) fn segment(start: f64, end: f64, text: &str) -> Segment {
+    Segment {
+        start,
+        end,
+        text: text.to_string(),
+        words: Vec::new(),
+        avg_logprob: -0.1,
+        no_speech_prob: 0.0,
+        compression_ratio: 1.0,
+        tokens: Vec::new(),
+    }
+}
+
+/// An English transcription made of `segments`, for the mock and tests.
+pub(crate) fn transcription(segments: Vec<Segment>) -> Transcription {
+    Transcription {
+        segments: segments.iter().map(|s| (s.start, s.end, s.text.clone())).collect(),
+        detailed: segments,
+        language: "en".to_string(),
+        language_probability: 1.0,
+        flagged: Vec::new(),
+        audio_decoder: None,
+    }
+}
+
+/// Deterministic stand-in for a model, for tests.
+///
+/// Files given to `with_segments` or `with_error` get that outcome; any other
+/// file gets one segment holding its path (and the track number when a track is
+/// selected). Files have the tracks given to `with_tracks`, or one. Every file
+/// takes `windows` windows, with progress reported and cancellation checked
+/// before each, like the engine. Shared copies keep the script but record
+/// their own calls.
+#[derive(Debug, Clone)]
+pub struct MockTranscriber {
+    outcomes: HashMap<String, Result<Vec<Segment>, String>>,
+    tracks: HashMap<String, Vec<AudioTrack>>,
+    windows: usize,
+    /// Paths passed to `transcribe_with`, in call order.
+    pub calls: Vec<String>,
+}
+
+impl Default for MockTranscriber {
+    fn default() -> Self {
+        Self {
+            outcomes: HashMap::new(),
+            tracks: HashMap::new(),
+            windows: 1,
+            calls: Vec::new(),
+        }
+    }
+}
+
+impl MockTranscriber {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_segments(mut self, audio_path: &str, segments: &[(f64, f64, &str)]) -> Self {
+        let segments = segments.iter().map(|&(start, end, text)| segment(start, end, text)).collect();
+        self.outcomes.insert(audio_path.to_string(), Ok(segments));
+        self
+    }
+
+    pub fn with_error(mut self, audio_path: &str, message: &str) -> Self {
+        self.outcomes.insert(audio_path.to_string(), Err(message.to_string()));
+        self
+    }
+
+    /// Give `audio_path` one audio track per language.
+    pub fn with_tracks(mut self, audio_path: &str, languages: &[&str]) -> Self {
+        let tracks = languages.iter().enumerate().map(|(index, language)| mock_track(index, language)).collect();
+        self.tracks.insert(audio_path.to_string(), tracks);
+        self
+    }
+
+    pub fn with_windows(mut self, windows: usize) -> Self {
+        self.windows = windows.max(1);
+        self
+    }
+}
+
+impl Transcriber for MockTranscriber {
+    fn transcribe_with(&mut self, audio_path: &str, options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        self.calls.push(audio_path.to_string());
+        if let Some(track) = options.audio_track {
+            let count = self.audio_tracks(audio_path)?.len();
+            if track >= count {
+                anyhow::bail!("no audio track {} (the file has {})", track + 1, count);
+            }
+        }
+        let segments = match (self.outcomes.get(audio_path), options.audio_track) {
+            (Some(Ok(segments)), _) => segments.clone(),
+            (Some(Err(message)), _) => anyhow::bail!("{}", message),
+            (None, None) => vec![segment(0.0, 1.0, &format!(" {}", audio_path))],
+            (None, Some(track)) => vec![segment(0.0, 1.0, &format!(" {} #{}", audio_path, track + 1))],
+        };
+
+        let total_secs = segments.last().map_or(1.0, |s| s.end);
+        for window in 1..=self.windows {
+            job.check()?;
+            job.report(Progress {
+                window,
+                processed_secs: total_secs * window as f64 / self.windows as f64,
+                total_secs,
+            });
+        }
+
+        let mut transcription = transcription(segments);
+        if let Some(language) = &options.language {
+            transcription.language = language.clone();
+        }
+        Ok(transcription)
+    }
+
+    fn audio_tracks(&self, audio_path: &str) -> Result<Vec<AudioTrack>> {
+        Ok(self.tracks.get(audio_path).cloned().unwrap_or_else(|| vec![mock_track(0, "und")]))
+    }
+
+    fn share(&self) -> Option<Box<dyn Transcriber>> {
+        Some(Box::new(Self { calls: Vec::new(), ..self.clone() }))
+    }
+}
+
+fn mock_track(index: usize, language: &str) -> AudioTrack {
+    AudioTrack {
+        index,
+        codec: "pcm_s16le".to_string(),
+        language: Some(language.to_string()),
+        title: None,
+        channels: Some(1),
+        sample_rate: Some(16000),
+    }
+}
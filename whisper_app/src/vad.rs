@@ -0,0 +1,265 @@
+use rustfft::{num_complex::Complex, FftPlanner};
+use std::f32::consts::PI;
+use std::ops::Range;
+
+use crate::audio::SAMPLE_RATE;
+
+/// Analysis frame length (20 ms at 16 kHz).
+const FRAME_LENGTH: usize = 320;
+/// Voice energy lives roughly between these frequencies; hum and hiss mostly do not.
+const SPEECH_BAND_HZ: (f32, f32) = (80.0, 4000.0);
+/// Frames on either side of a frame searched for its noise floor (3 s).
+const FLOOR_RADIUS: usize = 150;
+
+/// Tuning for `detect_speech`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VadOptions {
+    /// How far (dB) a frame must rise above the estimated noise floor.
+    pub energy_threshold_db: f32,
+    /// Frames quieter than this (dBFS) are silence whatever the noise floor.
+    pub min_energy_db: f32,
+    /// Frames louder than this (dBFS) are loud enough whatever the noise floor,
+    /// for talk with no gaps that would show the background.
+    pub max_energy_db: f32,
+    /// Share of a frame's energy that must fall in the 80–4000 Hz voice band.
+    pub speech_band_ratio: f32,
+    /// Shorter bursts are dropped as clicks or noise.
+    pub min_speech_ms: u32,
+    /// Shorter pauses do not split a speech region.
+    pub min_silence_ms: u32,
+    /// Added before and after each region so word onsets are not clipped.
+    pub padding_ms: u32,
+}
+
+impl Default for VadOptions {
+    fn default() -> Self {
+        Self {
+            energy_threshold_db: 12.0,
+            min_energy_db: -55.0,
+            max_energy_db: -45.0,
+            speech_band_ratio: 0.5,
+            min_speech_ms: 250,
+            min_silence_ms: 500,
+            padding_ms: 200,
+        }
+    }
+}
+
+/// Find the speech regions of 16 kHz mono PCM, as sample ranges.
+///
+/// A 20 ms frame counts as speech when its energy is well above the noise floor
+/// and most of it is in the voice band. The floor is the quietest frame within
+/// 3 s either side, so the dips between syllables set it even when nobody
+/// pauses, and a quiet speaker is judged against the background around them
+/// rather than against louder parts of the recording. Regions are then merged
+/// across short pauses, filtered by length and padded.
+pub fn detect_speech(pcm: &[f32], options: &VadOptions) -> Vec<Range<usize>> {
+    let n_frames = pcm.len() / FRAME_LENGTH;
+    if n_frames == 0 {
+        return Vec::new();
+    }
+
+    let window: Vec<f32> = (0..FRAME_LENGTH)
+        .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / FRAME_LENGTH as f32).cos()))
+        .collect();
+    let fft = FftPlanner::new().plan_fft_forward(FRAME_LENGTH);
+    let bin_hz = SAMPLE_RATE as f32 / FRAME_LENGTH as f32;
+    let band = (SPEECH_BAND_HZ.0 / bin_hz).ceil() as usize..=(SPEECH_BAND_HZ.1 / bin_hz).floor() as usize;
+
+    let mut energies = Vec::with_capacity(n_frames);
+    let mut band_ratios = Vec::with_capacity(n_frames);
+    let mut buffer = vec![Complex::new(0f32, 0f32); FRAME_LENGTH];
+    for frame in pcm.chunks_exact(FRAME_LENGTH) {
+        let mean_square = frame.iter().map(|x| x * x).sum::<f32>() / FRAME_LENGTH as f32;
+        energies.push(10.0 * mean_square.max(1e-12).log10());
+
+        for ((b, x), w) in buffer.iter_mut().zip(frame).zip(&window) {
+            *b = Complex::new(x * w, 0.0);
+        }
+        fft.process(&mut buffer);
+        let power: Vec<f32> = buffer[..=FRAME_LENGTH / 2].iter().map(|c| c.norm_sqr()).collect();
+        let total = power.iter().sum::<f32>();
+        let in_band = power[band.clone()].iter().sum::<f32>();
+        band_ratios.push(if total > 0.0 { in_band / total } else { 0.0 });
+    }
+
+    // Runs of speech frames, in frames.
+    let mut regions: Vec<Range<usize>> = Vec::new();
+    for (i, (&energy, &ratio)) in energies.iter().zip(&band_ratios).enumerate() {
+        let nearby = &energies[i.saturating_sub(FLOOR_RADIUS)..(i + FLOOR_RADIUS + 1).min(n_frames)];
+        let noise_floor = nearby.iter().copied().fold(f32::INFINITY, f32::min);
+        let threshold = (noise_floor + options.energy_threshold_db)
+            .min(options.max_energy_db)
+            .max(options.min_energy_db);
+        if energy < threshold || ratio < options.speech_band_ratio {
+            continue;
+        }
+        match regions.last_mut() {
+            Some(last) if last.end == i => last.end = i + 1,
+            _ => regions.push(i..i + 1),
+        }
+    }
+
+    let ms_to_frames = |ms: u32| (ms as usize * SAMPLE_RATE / 1000).div_ceil(FRAME_LENGTH);
+    let regions = merge_close(regions, ms_to_frames(options.min_silence_ms));
+    let min_speech = ms_to_frames(options.min_speech_ms);
+    let padding = options.padding_ms as usize * SAMPLE_RATE / 1000;
+    let padded = regions
+        .into_iter()
+        .filter(|r| r.len() >= min_speech)
+        .map(|r| (r.start * FRAME_LENGTH).saturating_sub(padding)..(r.end * FRAME_LENGTH + padding).min(pcm.len()))
+        .collect();
+    merge_close(padded, 0)
+}
+
+/// Join ranges separated by fewer than `max_gap` units (overlapping ones always).
+fn merge_close(regions: Vec<Range<usize>>, max_gap: usize) -> Vec<Range<usize>> {
+    let mut merged: Vec<Range<usize>> = Vec::with_capacity(regions.len());
+    for region in regions {
+        match merged.last_mut() {
+            Some(last) if region.start <= last.end + max_gap => last.end = last.end.max(region.end),
+            _ => merged.push(region),
+        }
+    }
+    merged
+}
+
+/// The speech regions of `pcm` joined back to back.
+pub fn collect_speech(pcm: &[f32], regions: &[Range<usize>]) -> Vec<f32> {
+    regions.iter().flat_map(|r| pcm[r.clone()].iter().copied()).collect()
+}
+
+/// Maps times in the audio built by `collect_speech` back to the original timeline.
+#[derive(Debug, Clone)]
+pub struct SpeechMap {
+    /// `(start in collected audio, start in original audio, duration)` in seconds.
+    chunks: Vec<(f64, f64, f64)>,
+}
+
+impl SpeechMap {
+    pub fn new(regions: &[Range<usize>]) -> Self {
+        let mut collected = 0.0;
+        let chunks = regions
+            .iter()
+            .map(|r| {
+                let duration = r.len() as f64 / SAMPLE_RATE as f64;
+                let chunk = (collected, r.start as f64 / SAMPLE_RATE as f64, duration);
+                collected += duration;
+                chunk
+            })
+            .collect();
+        Self { chunks }
+    }
+
+    /// Original time of `t`. A time exactly on the boundary between two regions
+    /// belongs to the earlier one when `is_end`, so segment ends do not jump
+    /// across the silence that was cut out.
+    pub fn to_original(&self, t: f64, is_end: bool) -> f64 {
+        let chunk = self
+            .chunks
+            .iter()
+            .rev()
+            .find(|(start, _, _)| if is_end { *start < t } else { *start <= t })
+            .or(self.chunks.first());
+        match chunk {
+            Some(&(start, original, duration)) => original + (t - start).clamp(0.0, duration),
+            None => t,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A harmonic tone with a 150 Hz fundamental, loud enough to pass as voice.
+    fn voiced(seconds: f32) -> Vec<f32> {
+        let n = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..n)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                (1..=5).map(|h| 0.1 / h as f32 * (2.0 * PI * 150.0 * h as f32 * t).sin()).sum()
+            })
+            .collect()
+    }
+
+    fn silence(seconds: f32) -> Vec<f32> {
+        // Faint deterministic noise so the floor is not digital zero.
+        let n = (seconds * SAMPLE_RATE as f32) as usize;
+        (0..n).map(|i| 1e-4 * ((i * 7919 % 101) as f32 / 50.0 - 1.0)).collect()
+    }
+
+    #[test]
+    fn test_finds_speech_with_padding() {
+        let pcm = [silence(1.0), voiced(1.0), silence(1.0)].concat();
+        let regions = detect_speech(&pcm, &VadOptions::default());
+        assert_eq!(regions.len(), 1);
+        let start = regions[0].start as f32 / SAMPLE_RATE as f32;
+        let end = regions[0].end as f32 / SAMPLE_RATE as f32;
+        assert!((start - 0.8).abs() < 0.03, "start {start}");
+        assert!((end - 2.2).abs() < 0.03, "end {end}");
+    }
+
+    #[test]
+    fn test_short_pause_merged_and_blip_dropped() {
+        let options = VadOptions::default();
+        let pcm = [
+            silence(1.0),
+            voiced(0.5),
+            silence(0.3), // shorter than min_silence: same region
+            voiced(0.5),
+            silence(1.5),
+            voiced(0.1), // shorter than min_speech: dropped
+            silence(1.0),
+        ]
+        .concat();
+        let regions = detect_speech(&pcm, &options);
+        assert_eq!(regions.len(), 1, "{regions:?}");
+        assert!(regions[0].end < (2.6 * SAMPLE_RATE as f32) as usize);
+    }
+
+    /// Continuous talk at 0, -10 and -20 dB, 4 s each. Syllables of 300 ms
+    /// are separated by 20 ms dips, far shorter than any pause, so only one
+    /// frame in sixteen is background.
+    fn talk_at_levels(dip: bool) -> Vec<f32> {
+        let syllable = voiced(0.3);
+        let gap = if dip { silence(0.02) } else { voiced(0.02) };
+        [1.0f32, 0.316, 0.1]
+            .iter()
+            .flat_map(|&gain| {
+                let burst: Vec<f32> = syllable.iter().chain(&gap).map(|x| x * gain).collect();
+                burst.repeat(12)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_quiet_talk_without_pauses_is_kept() {
+        for dip in [true, false] {
+            let pcm = talk_at_levels(dip);
+            let regions = detect_speech(&pcm, &VadOptions::default());
+            assert_eq!(regions.len(), 1, "dips between syllables: {dip}, {regions:?}");
+            assert_eq!(regions[0], 0..pcm.len());
+        }
+    }
+
+    #[test]
+    fn test_silence_only() {
+        assert!(detect_speech(&silence(3.0), &VadOptions::default()).is_empty());
+        assert!(detect_speech(&[], &VadOptions::default()).is_empty());
+    }
+
+    #[test]
+    fn test_speech_map() {
+        let sr = SAMPLE_RATE;
+        let regions = vec![sr..2 * sr, 5 * sr..7 * sr];
+        let pcm = vec![0.5f32; 8 * sr];
+        assert_eq!(collect_speech(&pcm, &regions).len(), 3 * sr);
+
+        let map = SpeechMap::new(&regions);
+        assert!((map.to_original(0.5, false) - 1.5).abs() < 1e-9);
+        assert!((map.to_original(1.0, false) - 5.0).abs() < 1e-9);
+        assert!((map.to_original(1.0, true) - 2.0).abs() < 1e-9);
+        assert!((map.to_original(2.5, true) - 6.5).abs() < 1e-9);
+    }
+}
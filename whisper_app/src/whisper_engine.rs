@@ -1,149 +1,1112 @@
 use anyhow::{Error, Result};
-use candle_core::{Device, Tensor};
-use candle_transformers::models::whisper::{self as m, Config};
+use candle_core::{DType, Device, Tensor};
+use candle_transformers::models::whisper::Config;
 use hf_hub::{api::sync::Api, Repo, RepoType};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use serde::Serialize;
 use tokenizers::Tokenizer;
 use symphonia::core::audio::SampleBuffer;
 use symphonia::core::codecs::{DecoderOptions, CODEC_TYPE_NULL};
-use symphonia::core::errors::Error as SymphoniaError;
-use symphonia::core::formats::FormatOptions;
-use symphonia::core::io::MediaSourceStream;
-use symphonia::core::meta::MetadataOptions;
-use symphonia::core::probe::Hint;
-use std::path::Path;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
-use crate::audio::pcm_to_mel;
+use crate::alignment::{self, AlignmentInput};
+use crate::audio_encoder::AudioEncoder;
+use crate::audio::{mel_filters, LogMel, MelExtractor, HOP_LENGTH, N_FFT, N_FRAMES, SAMPLE_RATE};
+use crate::decoding::{self, compression_ratio, DecodeResult, DecodingOptions, Task};
+use crate::engine_config::{device_name, EngineConfig, Precision};
+use crate::ffmpeg;
+use crate::guard::{check_segment, FlaggedSegment, GuardAction, GuardOptions};
+use crate::job::{Job, Progress};
+use crate::language::LANGUAGES;
+use crate::logit_filters::{ApplyTimestampRules, FilteredDecoder, LogitFilter, SuppressBlank, SuppressTokens};
+use crate::models::ModelStore;
+use crate::preprocess::preprocess;
+use crate::quantize::{parse_model_id, published_gguf, quantize_safetensors, PublishedGguf, PUBLISHED_REPO};
+use crate::resample::{downmix_to_mono, resample};
+use crate::streaming::{StreamEvent, StreamingTranscriber};
+use crate::text_decoder::{TextDecoder, WindowDecoder};
+use crate::tracks;
+use crate::vad::{collect_speech, detect_speech, SpeechMap};
 
 // ... imports remain ...
 // We need to keep other imports, just change where we call functionality.
 
+/// A loaded Whisper model. Clones share the weights (candle tensors are
+/// reference counted) and the thread pool, but decode independently.
+#[derive(Clone)]
 pub struct WhisperEngine {
-    model: m::model::Whisper,
+    encoder: AudioEncoder,
+    decoder: TextDecoder,
     tokenizer: Tokenizer,
     device: Device,
-    mel_filters: Vec<f32>,
+    /// Precision the model actually runs at.
+    precision: Precision,
+    /// GGUF weights (q4_0/q5_0/q8_0) rather than safetensors.
+    quantized: bool,
+    /// Runs inference when the thread count is limited.
+    pool: Option<Arc<rayon::ThreadPool>>,
+    mel: MelExtractor,
+    /// Windows encoded at once, see `EngineConfig::encoder_batch_size`.
+    encoder_batch_size: usize,
     config: Config,
+    /// `(layer, head)` pairs whose cross-attention tracks the audio position.
+    alignment_heads: Vec<(usize, usize)>,
+}
+
+/// A word with global start/end times and the mean probability of its tokens.
+#[derive(Debug, Clone, Serialize)]
+pub struct Word {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    pub probability: f32,
+}
+
+/// A text token and the probability the model gave it.
+#[derive(Debug, Clone, Serialize)]
+pub struct TokenProbability {
+    pub id: u32,
+    pub text: String,
+    pub probability: f32,
+}
+
+/// A transcribed segment; `words` is filled when word timestamps were requested.
+#[derive(Debug, Clone, Serialize)]
+pub struct Segment {
+    pub start: f64,
+    pub end: f64,
+    pub text: String,
+    pub words: Vec<Word>,
+    /// Mean log-probability of the segment's text tokens.
+    pub avg_logprob: f64,
+    /// Probability that the window the segment came from holds no speech.
+    pub no_speech_prob: f32,
+    pub compression_ratio: f64,
+    pub tokens: Vec<TokenProbability>,
+}
+
+impl Segment {
+    /// Probability of the least likely text token; 1.0 without tokens.
+    pub fn min_probability(&self) -> f32 {
+        self.tokens.iter().map(|t| t.probability).fold(1.0, f32::min)
+    }
+}
+
+/// Result of a transcription job.
+#[derive(Debug, Clone)]
+pub struct Transcription {
+    pub segments: Vec<(f64, f64, String)>,
+    /// The same segments with word-level detail.
+    pub detailed: Vec<Segment>,
+    /// Language code that was decoded, detected or given in the options.
+    pub language: String,
+    /// Detection confidence; 1.0 when the language was given explicitly.
+    pub language_probability: f32,
+    /// Segments the hallucination guard dropped or replaced.
+    pub flagged: Vec<FlaggedSegment>,
+    /// What decoded the audio file; `None` when PCM was passed in.
+    pub audio_decoder: Option<AudioDecoder>,
+}
+
+/// Which decoder `load_audio` used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioDecoder {
+    Symphonia,
+    /// The fallback for files symphonia cannot read.
+    Ffmpeg,
+}
+
+impl std::fmt::Display for AudioDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            AudioDecoder::Symphonia => "symphonia",
+            AudioDecoder::Ffmpeg => "ffmpeg",
+        })
+    }
+}
+
+/// Tokens of one segment cut from a decoded window, with their log-probabilities.
+struct WindowSegment {
+    start: f64,
+    end: f64,
+    tokens: Vec<u32>,
+    logprobs: Vec<f32>,
+}
+
+/// Files that make up a Whisper checkpoint on disk.
+struct ModelFiles {
+    config: PathBuf,
+    tokenizer: PathBuf,
+    weights: PathBuf,
+    /// Holds the alignment heads; optional.
+    generation_config: Option<PathBuf>,
+    /// Raw little-endian f32 filterbank; computed when absent.
+    mel_filters: Option<PathBuf>,
 }
 
 impl WhisperEngine {
+    /// Download (or reuse from the Hugging Face cache) `openai/whisper-{model_id}`.
+    ///
+    /// Ids with a quantization suffix such as `small-q5_0` are kept as a local
+    /// model folder (see `quantized_model_dir`): the GGUF candle publishes when
+    /// there is one (see `published_gguf`), else the checkpoint converted once.
     pub fn new(model_id: &str) -> Result<Self> {
-        let device = Device::new_metal(0).unwrap_or(Device::Cpu);
-        println!("Using device: {:?}", device);
-        
+        Self::new_with_config(model_id, &EngineConfig::default())
+    }
+
+    /// `new` on the device, precision and threads of `engine_config`.
+    pub fn new_with_config(model_id: &str, engine_config: &EngineConfig) -> Result<Self> {
+        let (base_id, quantization) = parse_model_id(model_id);
         let api = Api::new()?;
         let repo = api.repo(Repo::new(
-            format!("openai/whisper-{}", model_id),
+            format!("openai/whisper-{}", base_id),
             RepoType::Model,
         ));
 
-        let config_filename = repo.get("config.json")?;
-        let tokenizer_filename = repo.get("tokenizer.json")?;
-        let weights_filename = repo.get("model.safetensors")?;
+        let Some(quantization) = quantization else {
+            let files = ModelFiles {
+                config: repo.get("config.json")?,
+                tokenizer: repo.get("tokenizer.json")?,
+                weights: repo.get("model.safetensors")?,
+                generation_config: repo.get("generation_config.json").ok(),
+                mel_filters: None,
+            };
+            return Self::load(files, engine_config);
+        };
 
-        let config: Config = serde_json::from_str(&std::fs::read_to_string(config_filename)?)?;
-        let tokenizer = Tokenizer::from_file(tokenizer_filename).map_err(Error::msg)?;
-        
-        let vb = unsafe {
-            candle_nn::VarBuilder::from_mmaped_safetensors(&[weights_filename], m::DTYPE, &device)?
+        let dir = quantized_model_dir(model_id);
+        if !dir.join("model.gguf").is_file() {
+            std::fs::create_dir_all(&dir)?;
+            let fetched = published_gguf(base_id, quantization).is_some_and(|published| {
+                match fetch_published(&api, &published, &dir) {
+                    Ok(()) => true,
+                    Err(e) => {
+                        log::warn!("cannot fetch {} from {} ({}), converting locally", published.weights, PUBLISHED_REPO, e);
+                        false
+                    }
+                }
+            });
+            if !fetched {
+                std::fs::copy(repo.get("config.json")?, dir.join("config.json"))?;
+                std::fs::copy(repo.get("tokenizer.json")?, dir.join("tokenizer.json"))?;
+                quantize_safetensors(&repo.get("model.safetensors")?, &dir.join("model.gguf"), quantization)?;
+            }
+            // Only the alignment heads; the published models come without them.
+            if let Ok(path) = repo.get("generation_config.json") {
+                std::fs::copy(path, dir.join("generation_config.json"))?;
+            }
+        }
+        Self::from_dir_with_config(dir, engine_config)
+    }
+
+    /// Load a model from a local folder without touching the network.
+    ///
+    /// The folder needs `config.json`, `tokenizer.json` and either `model.safetensors`
+    /// or a GGUF file (`model.gguf`, or the only `*.gguf` in the folder);
+    /// `generation_config.json` and `mel_filters.bytes` are used when present.
+    pub fn from_dir(dir: impl AsRef<Path>) -> Result<Self> {
+        Self::from_dir_with_config(dir, &EngineConfig::default())
+    }
+
+    /// `from_dir` on the device, precision and threads of `engine_config`.
+    pub fn from_dir_with_config(dir: impl AsRef<Path>, engine_config: &EngineConfig) -> Result<Self> {
+        let dir = dir.as_ref();
+        if !dir.is_dir() {
+            anyhow::bail!("model folder not found: {}", dir.display());
+        }
+        let weights = find_weights(dir)?;
+        let mut missing: Vec<&str> = ["config.json", "tokenizer.json"]
+            .into_iter()
+            .filter(|name| !dir.join(name).is_file())
+            .collect();
+        if weights.is_none() {
+            missing.push("model.safetensors (or a .gguf file)");
+        }
+        if !missing.is_empty() {
+            anyhow::bail!("model folder {} is missing: {}", dir.display(), missing.join(", "));
+        }
+
+        let optional = |name: &str| Some(dir.join(name)).filter(|path| path.is_file());
+        let files = ModelFiles {
+            config: dir.join("config.json"),
+            tokenizer: dir.join("tokenizer.json"),
+            weights: weights.unwrap_or_default(),
+            generation_config: optional("generation_config.json"),
+            mel_filters: optional("mel_filters.bytes"),
         };
-        let model = m::model::Whisper::load(&vb, config.clone())?;
+        Self::load(files, engine_config)
+    }
+
+    fn load(files: ModelFiles, engine_config: &EngineConfig) -> Result<Self> {
+        let device = engine_config.device()?;
+        let pool = match engine_config.threads {
+            Some(threads) => Some(Arc::new(rayon::ThreadPoolBuilder::new().num_threads(threads.max(1)).build()?)),
+            None => None,
+        };
+
+        let config: Config = serde_json::from_str(&std::fs::read_to_string(&files.config)?)?;
+        let tokenizer = Tokenizer::from_file(&files.tokenizer).map_err(Error::msg)?;
+
+        let quantized = files.weights.extension().is_some_and(|ext| ext == "gguf");
+        let (encoder, decoder, precision) = if quantized {
+            let vb = candle_transformers::quantized_var_builder::VarBuilder::from_gguf(&files.weights, &device)?;
+            let encoder = AudioEncoder::load_quantized(vb.pp("model.encoder"), &config)?;
+            (encoder, TextDecoder::load_quantized(vb.pp("model.decoder"), &config)?, Precision::F32)
+        } else {
+            let precision = engine_config.precision_on(&device);
+            let vb = unsafe {
+                candle_nn::VarBuilder::from_mmaped_safetensors(&[&files.weights], precision.dtype(), &device)?
+            };
+            let encoder = AudioEncoder::load(vb.pp("model.encoder"), &config)?;
+            (encoder, TextDecoder::load(vb.pp("model.decoder"), &config)?, precision)
+        };
+
+        // Hugging Face checkpoints list the alignment heads in generation_config.json;
+        // without it, use every head of the second half of the decoder like reference Whisper.
+        let alignment_heads = files
+            .generation_config
+            .and_then(|path| std::fs::read_to_string(path).ok())
+            .and_then(|json| parse_alignment_heads(&json))
+            .unwrap_or_else(|| {
+                let layers = config.decoder_layers;
+                (layers / 2..layers)
+                    .flat_map(|l| (0..config.decoder_attention_heads).map(move |h| (l, h)))
+                    .collect()
+            });
         
-        let mel_path = match config.num_mel_bins {
-            80 => api.repo(Repo::new("openai/whisper-tiny".to_string(), RepoType::Model)).get("mel_filters.bytes")?,
-            _ => api.repo(Repo::new("openai/whisper-large-v3".to_string(), RepoType::Model)).get("mel_filters.bytes")?,
+        let mel_filters = match files.mel_filters {
+            Some(path) => {
+                let mel_bytes = std::fs::read(path)?;
+                let mut mel_filters = vec![0f32; mel_bytes.len() / 4];
+                <byteorder::LittleEndian as byteorder::ByteOrder>::read_f32_into(&mel_bytes, &mut mel_filters);
+                if mel_filters.len() != config.num_mel_bins * (N_FFT / 2 + 1) {
+                    anyhow::bail!("mel_filters.bytes does not match the model's {} mel bins", config.num_mel_bins);
+                }
+                mel_filters
+            }
+            None => mel_filters(config.num_mel_bins),
         };
-        let mel_bytes = std::fs::read(mel_path)?;
-        let mut mel_filters = vec![0f32; mel_bytes.len() / 4];
-        <byteorder::LittleEndian as byteorder::ByteOrder>::read_f32_into(&mel_bytes, &mut mel_filters);
+        let mel = MelExtractor::new(config.num_mel_bins, mel_filters)?;
 
-        Ok(Self {
-            model,
+        let engine = Self {
+            encoder,
+            decoder,
             tokenizer,
             device,
-            mel_filters,
+            precision,
+            quantized,
+            pool,
+            mel,
+            encoder_batch_size: engine_config.encoder_batch_size.max(1),
             config,
-        })
+            alignment_heads,
+        };
+        log::info!("whisper model running on {}", engine.device_description());
+        Ok(engine)
     }
 
-    pub fn transcribe(&mut self, audio_path: &str) -> Result<Vec<(f64, f64, String)>> {
-        let pcm_data = load_audio(audio_path)?;
-        let mel = pcm_to_mel(&self.config, &pcm_data, &self.mel_filters, &self.device)?;
-        
-        // Run Encoder
-        let audio_features = self.model.encoder.forward(&mel, true)?;
-        
-        // Simple Greedy Decoder with Timestamps
-        let sot_token = *self.tokenizer.get_vocab(true).get("<|startoftranscript|>").unwrap_or(&50258);
-        let eot_token = *self.tokenizer.get_vocab(true).get("<|endoftext|>").unwrap_or(&50257);
-        let transcribe_token = *self.tokenizer.get_vocab(true).get("<|transcribe|>").unwrap_or(&50359);
-        // We do NOT add <|notimestamps|> because we WANT timestamps.
-        
-        // Find timestamp begin index. Usually it's right after <|notimestamps|> or at a fixed index.
-        // For OpenAI models: <|notimestamps|> is 50363. Timestamps start at 50364.
-        // We will detect it dynamically or fallback.
-        let no_timestamps_id = *self.tokenizer.get_vocab(true).get("<|notimestamps|>").unwrap_or(&50363);
+    /// Where and how the model runs, e.g. `CPU, f32, 4 threads` or `Metal 0, f16`.
+    pub fn device_description(&self) -> String {
+        let mut description = format!("{}, {}", device_name(&self.device), self.precision);
+        if self.quantized {
+            description.push_str(" (GGUF)");
+        }
+        if self.device.is_cpu() {
+            let threads = self.pool.as_ref().map_or_else(rayon::current_num_threads, |pool| pool.current_num_threads());
+            description.push_str(&format!(", {} threads", threads));
+        }
+        if self.encoder_batch_size > 1 {
+            description.push_str(&format!(", {} windows per encoder batch", self.encoder_batch_size));
+        }
+        description
+    }
+
+    pub fn transcribe(&mut self, audio_path: &str, options: &DecodingOptions) -> Result<Transcription> {
+        self.transcribe_with(audio_path, options, &mut Job::default())
+    }
+
+    /// `transcribe` reporting progress to `job` after every window and stopping
+    /// with a `Cancelled` error when it is cancelled.
+    pub fn transcribe_with(&mut self, audio_path: &str, options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        let (pcm_data, decoder) = load_pcm(audio_path, options)?;
+        job.check()?;
+        let mut transcription = self.transcribe_pcm_with(&pcm_data, options, job)?;
+        transcription.audio_decoder = Some(decoder);
+        Ok(transcription)
+    }
+
+    /// Feed a chunk of 16 kHz mono PCM into a live stream, see `StreamingTranscriber`.
+    /// Once the stream has detected a language it is kept for the remaining chunks.
+    pub fn push_stream(&mut self, stream: &mut StreamingTranscriber, chunk: &[f32], options: &DecodingOptions) -> Result<Vec<StreamEvent>> {
+        let options = Self::stream_options(stream, options);
+        stream.push(chunk, |pcm| self.transcribe_pcm(pcm, &options))
+    }
+
+    /// End a live stream, finalizing the audio still pending.
+    pub fn finish_stream(&mut self, stream: &mut StreamingTranscriber, options: &DecodingOptions) -> Result<Vec<StreamEvent>> {
+        let options = Self::stream_options(stream, options);
+        stream.finish(|pcm| self.transcribe_pcm(pcm, &options))
+    }
+
+    fn stream_options(stream: &StreamingTranscriber, options: &DecodingOptions) -> DecodingOptions {
+        let mut options = options.clone();
+        if options.language.is_none() {
+            options.language = stream.language().map(str::to_string);
+        }
+        options
+    }
+
+    /// Transcribe 16 kHz mono PCM of any length by sliding a 30 second window over it.
+    ///
+    /// Like reference Whisper, the window is advanced to the last complete timestamp
+    /// token the model produced, so a sentence cut off at the window edge is decoded
+    /// again at the start of the next window. Returned times are global (seconds from
+    /// the start of `pcm`).
+    ///
+    /// Without an explicit language in `options`, the language is detected once on
+    /// the first window and used for the rest of the file.
+    ///
+    /// With `options.vad` set, only the detected speech regions are decoded (joined
+    /// back to back) and their times are mapped back onto the original timeline.
+    pub fn transcribe_pcm(&mut self, pcm: &[f32], options: &DecodingOptions) -> Result<Transcription> {
+        self.transcribe_pcm_with(pcm, options, &mut Job::default())
+    }
+
+    /// `transcribe_pcm` with progress reports and cancellation, see `transcribe_with`.
+    pub fn transcribe_pcm_with(&mut self, pcm: &[f32], options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        match self.pool.clone() {
+            Some(pool) => pool.install(|| self.transcribe_speech(pcm, options, job)),
+            None => self.transcribe_speech(pcm, options, job),
+        }
+    }
+
+    /// The language `transcribe_pcm` would detect in `pcm`, from the first window
+    /// of speech, and its probability.
+    pub fn detect_language_pcm(&mut self, pcm: &[f32], options: &DecodingOptions) -> Result<(String, f32)> {
+        if !self.is_multilingual() {
+            return Ok(("en".to_string(), 1.0));
+        }
+        let speech = match &options.vad {
+            Some(vad_options) => collect_speech(pcm, &detect_speech(pcm, vad_options)),
+            None => pcm.to_vec(),
+        };
+        if speech.len() < HOP_LENGTH {
+            // Nothing to decode either; `transcribe_pcm` reports the same.
+            return Ok(("en".to_string(), 0.0));
+        }
+        // The whole input, so the first window is clamped exactly as in `transcribe_pcm`.
+        let mel = self.mel.log_mel(&speech).window(0, &self.device)?;
+        let pool = self.pool.clone();
+        let mut detect = || {
+            let audio_features = self.encoder.forward(&mel)?;
+            self.detect_language(&audio_features)
+        };
+        match pool {
+            Some(pool) => pool.install(detect),
+            None => detect(),
+        }
+    }
+
+    /// Runs VAD when enabled and transcribes the speech found.
+    fn transcribe_speech(&mut self, pcm: &[f32], options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        let Some(vad_options) = &options.vad else {
+            return self.transcribe_windows(pcm, options, job);
+        };
+        let regions = detect_speech(pcm, vad_options);
+        let speech = collect_speech(pcm, &regions);
+        let mut transcription = self.transcribe_windows(&speech, options, job)?;
+
+        let map = SpeechMap::new(&regions);
+        for segment in &mut transcription.detailed {
+            segment.start = map.to_original(segment.start, false);
+            segment.end = map.to_original(segment.end, true);
+            for word in &mut segment.words {
+                word.start = map.to_original(word.start, false);
+                word.end = map.to_original(word.end, true);
+            }
+        }
+        for flagged in &mut transcription.flagged {
+            flagged.start = map.to_original(flagged.start, false);
+            flagged.end = map.to_original(flagged.end, true);
+        }
+        transcription.segments = transcription
+            .detailed
+            .iter()
+            .map(|s| (s.start, s.end, s.text.clone()))
+            .collect();
+        Ok(transcription)
+    }
+
+    fn transcribe_windows(&mut self, pcm: &[f32], options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        if options.task == Task::Translate && !self.is_multilingual() {
+            anyhow::bail!("translation requires a multilingual model");
+        }
+
+        let vocab = self.tokenizer.get_vocab(true);
+        let no_timestamps_id = *vocab.get("<|notimestamps|>").unwrap_or(&50363);
+        // Timestamp tokens start right after <|notimestamps|> (50364 for OpenAI models).
         let timestamp_begin = no_timestamps_id + 1;
 
-        let mut tokens = vec![sot_token, transcribe_token];
-        // Language detection is skipped for now (assuming English or letting model default).
-        
-        let mut segments = Vec::new();
-        let mut current_start = 0.0;
-        let mut current_text_tokens = Vec::new();
-        
-        // Safety limit
-        for _ in 0..1000 { 
-            let input = Tensor::new(tokens.as_slice(), &self.device)?.unsqueeze(0)?;
-            let logits = self.model.decoder.forward(&input, &audio_features, true)?;
-            let logits = logits.squeeze(0)?;
-            let (_seq_len, _vocab_size) = logits.dims2()?;
-            
-            let last_logits = logits.get(_seq_len - 1)?;
-            let next_token = last_logits.argmax(0)?.to_scalar::<u32>()?;
-            
-            if next_token == eot_token {
-                // If we have pending text, save it ending at 30.0 or current max
-                if !current_text_tokens.is_empty() {
-                    let text = self.tokenizer.decode(&current_text_tokens, true).unwrap_or_default();
-                    segments.push((current_start, 30.0, text)); // Default end to window max
+        // Each audio context position covers this many mel frames (3000 / 1500 = 2).
+        let input_stride = N_FRAMES / self.config.max_source_positions;
+        let time_precision = (input_stride * HOP_LENGTH) as f64 / SAMPLE_RATE as f64; // 0.02s
+        let frame_secs = HOP_LENGTH as f64 / SAMPLE_RATE as f64;
+        // One spectrogram for the whole input; each window is a slice of it.
+        let mel = self.mel.log_mel(pcm);
+        let content_frames = mel.n_frames();
+        let total_secs = content_frames as f64 * frame_secs;
+        let mut window = 0;
+        // Windows encoded ahead, dropped when decoding resumes somewhere else.
+        let mut encoded: VecDeque<(usize, Tensor)> = VecDeque::new();
+
+        let mut detailed = Vec::new();
+        let mut seek = 0usize; // position in mel frames
+        let mut language: Option<(String, f32)> = match (&options.language, self.is_multilingual()) {
+            (Some(lang), _) => Some((lang.clone(), 1.0)),
+            (None, false) => Some(("en".to_string(), 1.0)),
+            (None, true) => None,
+        };
+        let mut sot_sequence = None;
+        let mut rng = StdRng::seed_from_u64(options.seed);
+        let hotwords = self.hotword_tokens(options)?;
+        let filters = self.logit_filters(options, timestamp_begin, time_precision)?;
+        // Text tokens of earlier windows, starting with the initial prompt.
+        let mut previous = match options.initial_prompt.as_deref().map(str::trim) {
+            Some(prompt) if !prompt.is_empty() => self.encode_text(prompt)?,
+            _ => Vec::new(),
+        };
+
+        while seek < content_frames {
+            job.check()?;
+            let time_offset = seek as f64 * frame_secs;
+            let segment_size = N_FRAMES.min(content_frames - seek);
+
+            if encoded.front().is_none_or(|(start, _)| *start != seek) {
+                encoded = self.encode_windows(&mel, seek)?;
+            }
+            let (_, audio_features) = encoded.pop_front().unwrap();
+
+            if sot_sequence.is_none() {
+                if language.is_none() {
+                    language = Some(self.detect_language(&audio_features)?);
+                }
+                let (lang, _) = language.as_ref().unwrap();
+                sot_sequence = Some(build_sot_sequence(&self.tokenizer, self.is_multilingual(), lang, options.task)?);
+            }
+            let prompt = self.prompt_tokens(&hotwords, &previous, sot_sequence.as_ref().unwrap());
+            let decoded = self.decode_window(&audio_features, &prompt, &filters, options, &mut rng)?;
+
+            // Like reference Whisper, a window that is probably silent is skipped
+            // unless the decoded text is confident anyway.
+            let no_speech = options
+                .no_speech_threshold
+                .is_some_and(|threshold| decoded.no_speech_prob as f64 > threshold)
+                && !options
+                    .logprob_threshold
+                    .is_some_and(|threshold| decoded.avg_logprob > threshold);
+            if no_speech {
+                seek += segment_size;
+                window += 1;
+                job.report(Progress { window, processed_secs: seek.min(content_frames) as f64 * frame_secs, total_secs });
+                continue;
+            }
+            let (window_segments, advance) = split_window(
+                decoded.tokens,
+                decoded.token_logprobs,
+                timestamp_begin,
+                time_offset,
+                segment_size,
+                input_stride,
+            );
+
+            let mut words = if options.word_timestamps {
+                let (lang, _) = language.as_ref().unwrap();
+                let sot = sot_sequence.as_ref().unwrap();
+                self.align_words(&audio_features, sot, lang, &window_segments, segment_size, time_offset)?
+            } else {
+                Vec::new()
+            }
+            .into_iter();
+            // Like reference Whisper, drop the context after a high-temperature
+            // fallback so a bad window does not steer the next one.
+            if options.condition_on_previous_text && decoded.temperature <= 0.5 {
+                previous.extend(window_segments.iter().flat_map(|s| s.tokens.iter().copied()));
+            } else {
+                previous.clear();
+            }
+            for segment in &window_segments {
+                let words = words.next().unwrap_or_default();
+                self.push_segment(&mut detailed, segment, timestamp_begin, decoded.no_speech_prob, words);
+            }
+
+            seek += advance;
+            window += 1;
+            job.report(Progress { window, processed_secs: seek.min(content_frames) as f64 * frame_secs, total_secs });
+        }
+
+        let (language, language_probability) = language
+            .unwrap_or_else(|| (options.language.clone().unwrap_or_else(|| "en".to_string()), 0.0));
+        let (detailed, flagged) = match &options.guard {
+            Some(guard) => self.apply_guard(pcm, detailed, &language, options, guard, job)?,
+            None => (detailed, Vec::new()),
+        };
+        Ok(Transcription {
+            segments: detailed.iter().map(|s| (s.start, s.end, s.text.clone())).collect(),
+            detailed,
+            language,
+            language_probability,
+            flagged,
+            audio_decoder: None,
+        })
+    }
+
+    /// Encoder outputs of the window at `seek` and, with batching, of the
+    /// windows following it every 30 seconds, keyed by their first mel frame.
+    fn encode_windows(&self, mel: &LogMel, seek: usize) -> Result<VecDeque<(usize, Tensor)>> {
+        let starts: Vec<usize> = (seek..mel.n_frames()).step_by(N_FRAMES).take(self.encoder_batch_size).collect();
+        let mels = starts.iter().map(|&start| mel.window(start, &self.device)).collect::<Result<Vec<_>>>()?;
+        let features = self.encoder.forward(&Tensor::cat(&mels, 0)?)?;
+        starts
+            .into_iter()
+            .enumerate()
+            .map(|(i, start)| Ok((start, features.narrow(0, i, 1)?)))
+            .collect()
+    }
+
+    /// Check every segment with the hallucination guard, dropping or re-decoding
+    /// the flagged ones. `pcm` is the audio the segment times refer to.
+    fn apply_guard(
+        &mut self,
+        pcm: &[f32],
+        segments: Vec<Segment>,
+        language: &str,
+        options: &DecodingOptions,
+        guard: &GuardOptions,
+        job: &Job,
+    ) -> Result<(Vec<Segment>, Vec<FlaggedSegment>)> {
+        let mut kept = Vec::with_capacity(segments.len());
+        let mut flagged = Vec::new();
+        for segment in segments {
+            let issues = check_segment(&segment.text, segment.end - segment.start, guard);
+            if issues.is_empty() {
+                kept.push(segment);
+                continue;
+            }
+
+            let mut replacement = Vec::new();
+            let start = ((segment.start * SAMPLE_RATE as f64) as usize).min(pcm.len());
+            let end = ((segment.end * SAMPLE_RATE as f64) as usize).min(pcm.len());
+            if guard.action == GuardAction::Redecode && end > start {
+                // Sample at the fallback temperatures, without context that may
+                // hold the same loop, and keep whatever passes the guard.
+                let mut temperatures: Vec<f64> = options.temperatures.iter().copied().filter(|&t| t > 0.0).collect();
+                if temperatures.is_empty() {
+                    temperatures = vec![0.2, 0.4, 0.6, 0.8, 1.0];
+                }
+                let retry = DecodingOptions {
+                    language: Some(language.to_string()),
+                    temperatures,
+                    initial_prompt: None,
+                    condition_on_previous_text: false,
+                    vad: None,
+                    guard: None,
+                    ..options.clone()
+                };
+                let redecoded = self.transcribe_windows(&pcm[start..end], &retry, &mut job.quiet())?;
+                let offset = start as f64 / SAMPLE_RATE as f64;
+                for mut s in redecoded.detailed {
+                    if check_segment(&s.text, s.end - s.start, guard).is_empty() {
+                        s.start += offset;
+                        s.end += offset;
+                        for word in &mut s.words {
+                            word.start += offset;
+                            word.end += offset;
+                        }
+                        replacement.push(s);
+                    }
                 }
+            }
+
+            let report = FlaggedSegment {
+                start: segment.start,
+                end: segment.end,
+                text: segment.text,
+                issues,
+                replacement: if replacement.is_empty() {
+                    None
+                } else {
+                    Some(replacement.iter().map(|s| s.text.as_str()).collect())
+                },
+            };
+            log::warn!(
+                "flagged segment [{:.2} -> {:.2}] {:?}: {}",
+                report.start,
+                report.end,
+                report.text,
+                report.issues.iter().map(|i| i.to_string()).collect::<Vec<_>>().join(", ")
+            );
+            flagged.push(report);
+            kept.extend(replacement);
+        }
+        Ok((kept, flagged))
+    }
+
+    /// English-only checkpoints (`*.en`) have a smaller vocabulary without language tokens.
+    fn is_multilingual(&self) -> bool {
+        self.config.vocab_size >= 51865
+    }
+
+    /// `<|nospeech|>`, called `<|nocaptions|>` in older vocabularies.
+    fn no_speech_token(&self) -> Option<u32> {
+        self.tokenizer
+            .token_to_id("<|nospeech|>")
+            .or_else(|| self.tokenizer.token_to_id("<|nocaptions|>"))
+    }
+
+    /// Reference Whisper's logit filters for timestamped decoding.
+    fn logit_filters(&self, options: &DecodingOptions, timestamp_begin: u32, time_precision: f64) -> Result<Vec<Box<dyn LogitFilter>>> {
+        let eot_token = self.tokenizer.token_to_id("<|endoftext|>").unwrap_or(50257);
+        let mut filters: Vec<Box<dyn LogitFilter>> = Vec::new();
+        if options.suppress_blank {
+            let mut blank_tokens = self.tokenizer.encode(" ", false).map_err(Error::msg)?.get_ids().to_vec();
+            blank_tokens.push(eot_token);
+            filters.push(Box::new(SuppressBlank { blank_tokens }));
+        }
+
+        let mut suppressed = options.suppress_tokens.clone().unwrap_or_else(|| self.config.suppress_tokens.clone());
+        let specials = ["<|transcribe|>", "<|translate|>", "<|startoftranscript|>", "<|startofprev|>", "<|startoflm|>"];
+        suppressed.extend(specials.iter().filter_map(|t| self.tokenizer.token_to_id(t)));
+        suppressed.extend(self.no_speech_token());
+        filters.push(Box::new(SuppressTokens { tokens: suppressed }));
+
+        filters.push(Box::new(ApplyTimestampRules {
+            eot_token,
+            no_timestamps_token: timestamp_begin - 1,
+            timestamp_begin,
+            max_initial_timestamp_index: options
+                .max_initial_timestamp
+                .map(|secs| (secs / time_precision).round() as u32),
+        }));
+        Ok(filters)
+    }
+
+    /// Tokens of `text` with a leading space, as Whisper expects for running text.
+    fn encode_text(&self, text: &str) -> Result<Vec<u32>> {
+        let encoding = self.tokenizer.encode(format!(" {}", text), false).map_err(Error::msg)?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    fn hotword_tokens(&self, options: &DecodingOptions) -> Result<Vec<u32>> {
+        let hotwords: Vec<&str> = options.hotwords.iter().map(|w| w.trim()).filter(|w| !w.is_empty()).collect();
+        if hotwords.is_empty() {
+            return Ok(Vec::new());
+        }
+        self.encode_text(&hotwords.join(", "))
+    }
+
+    /// `<|startofprev|> hotwords previous-text` followed by the sot sequence. The
+    /// context takes at most half the decoder's positions; the hotwords come first
+    /// and the most recent previous text fills the rest.
+    fn prompt_tokens(&self, hotwords: &[u32], previous: &[u32], sot_sequence: &[u32]) -> Vec<u32> {
+        if hotwords.is_empty() && previous.is_empty() {
+            return sot_sequence.to_vec();
+        }
+        let max_context = self.config.max_target_positions / 2 - 1;
+        let sot_prev = self.tokenizer.token_to_id("<|startofprev|>").unwrap_or(50361);
+        let hotwords = &hotwords[..hotwords.len().min(max_context)];
+        let room = max_context - hotwords.len();
+        let previous = &previous[previous.len().saturating_sub(room)..];
+
+        let mut prompt = Vec::with_capacity(1 + hotwords.len() + previous.len() + sot_sequence.len());
+        prompt.push(sot_prev);
+        prompt.extend_from_slice(hotwords);
+        prompt.extend_from_slice(previous);
+        prompt.extend_from_slice(sot_sequence);
+        prompt
+    }
+
+    /// Pick the most likely language from the logits following `<|startoftranscript|>`,
+    /// see `pick_language`.
+    fn detect_language(&mut self, audio_features: &Tensor) -> Result<(String, f32)> {
+        let sot_token = self.tokenizer.token_to_id("<|startoftranscript|>").unwrap_or(50258);
+        let candidates = language_candidates(&self.tokenizer);
+        if candidates.is_empty() {
+            anyhow::bail!("model has no language tokens");
+        }
+
+        let input = Tensor::new(&[sot_token], &self.device)?.unsqueeze(0)?;
+        let hidden = self.decoder.forward(&input, audio_features, true)?;
+        let logits = self.decoder.final_linear(&hidden)?.squeeze(0)?.squeeze(0)?;
+        let logits = logits.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+        Ok(pick_language(&logits, &candidates))
+    }
+
+    /// Decode one window of encoder output, retrying at the next temperature of the
+    /// schedule while the result is too repetitive or too unlikely. The returned
+    /// tokens include timestamps but not the prompt or the end-of-text token.
+    fn decode_window(
+        &mut self,
+        audio_features: &Tensor,
+        prompt: &[u32],
+        filters: &[Box<dyn LogitFilter>],
+        options: &DecodingOptions,
+        rng: &mut StdRng,
+    ) -> Result<DecodeResult> {
+        let eot_token = self.tokenizer.token_to_id("<|endoftext|>").unwrap_or(50257);
+        let sot_token = self.tokenizer.token_to_id("<|startoftranscript|>").unwrap_or(50258);
+        let sot_index = prompt.iter().position(|&t| t == sot_token).unwrap_or(0);
+        let no_speech_token = self.no_speech_token();
+        // The decoder has room for max_target_positions tokens; reference Whisper
+        // samples at most half of that per window, less if a long context is fed.
+        let sample_len = (self.config.max_target_positions / 2)
+            .min(self.config.max_target_positions.saturating_sub(prompt.len()));
+        let temperatures = if options.temperatures.is_empty() { &[0.0][..] } else { &options.temperatures[..] };
+
+        let mut result = None;
+        for &temperature in temperatures {
+            let mut window = WindowDecoder::new(&mut self.decoder, audio_features);
+            if let Some(token) = no_speech_token {
+                window = window.with_no_speech(sot_index, token);
+            }
+            let mut decoder = FilteredDecoder::new(window, filters);
+            let mut decoded = decoding::decode(&mut decoder, prompt, eot_token, sample_len, temperature, options, rng)?;
+            decoded.no_speech_prob = decoder.inner.no_speech_prob().unwrap_or(0.0);
+
+            let text_tokens: Vec<u32> = decoded.tokens.iter().copied().filter(|&t| t < eot_token).collect();
+            let text = self.tokenizer.decode(&text_tokens, true).unwrap_or_default();
+            let too_repetitive = options
+                .compression_ratio_threshold
+                .is_some_and(|threshold| compression_ratio(&text) > threshold);
+            let too_unlikely = options
+                .logprob_threshold
+                .is_some_and(|threshold| decoded.avg_logprob < threshold);
+
+            // Silence is not retried: more temperature would only invent text.
+            let is_silence = options
+                .no_speech_threshold
+                .is_some_and(|threshold| decoded.no_speech_prob as f64 > threshold);
+
+            result = Some(decoded);
+            if (!too_repetitive && !too_unlikely) || is_silence {
                 break;
             }
-            
-            tokens.push(next_token);
-            
-            if next_token >= timestamp_begin {
-                let time = (next_token - timestamp_begin) as f64 * 0.02;
-                
-                if !current_text_tokens.is_empty() {
-                    // This timestamp likely ends the previous segment
-                    let text = self.tokenizer.decode(&current_text_tokens, true).unwrap_or_default();
-                    segments.push((current_start, time, text));
-                    current_text_tokens.clear();
+        }
+        Ok(result.unwrap())
+    }
+
+    /// Word timings for each of `window_segments`, from one alignment pass over
+    /// all of the window's text tokens.
+    fn align_words(
+        &mut self,
+        audio_features: &Tensor,
+        sot_sequence: &[u32],
+        language: &str,
+        window_segments: &[WindowSegment],
+        num_frames: usize,
+        time_offset: f64,
+    ) -> Result<Vec<Vec<Word>>> {
+        let eot_token = self.tokenizer.token_to_id("<|endoftext|>").unwrap_or(50257);
+        let no_timestamps_token = self.tokenizer.token_to_id("<|notimestamps|>").unwrap_or(50363);
+        let per_segment: Vec<Vec<u32>> = window_segments
+            .iter()
+            .map(|s| s.tokens.iter().copied().filter(|&t| t < eot_token).collect())
+            .collect();
+        let text_tokens = per_segment.concat();
+
+        let input = AlignmentInput {
+            sot_sequence,
+            no_timestamps_token,
+            eot_token,
+            text_tokens: &text_tokens,
+            num_frames,
+            language,
+        };
+        let timings = alignment::find_alignment(&mut self.decoder, &self.tokenizer, audio_features, &self.alignment_heads, &input)?;
+
+        // Hand words to segments in order, by how many text tokens each segment holds.
+        let mut timings = timings.into_iter();
+        Ok(per_segment
+            .iter()
+            .map(|tokens| {
+                let mut words = Vec::new();
+                let mut saved_tokens = 0;
+                while saved_tokens < tokens.len() {
+                    let Some(timing) = timings.next() else { break };
+                    saved_tokens += timing.tokens.len();
+                    words.push(Word {
+                        start: time_offset + timing.start,
+                        end: time_offset + timing.end,
+                        text: timing.word,
+                        probability: timing.probability,
+                    });
                 }
-                
-                // This timestamp also starts the next segment
-                current_start = time;
-            } else {
-                current_text_tokens.push(next_token);
+                words
+            })
+            .collect())
+    }
+
+    fn push_segment(
+        &self,
+        segments: &mut Vec<Segment>,
+        segment: &WindowSegment,
+        timestamp_begin: u32,
+        no_speech_prob: f32,
+        words: Vec<Word>,
+    ) {
+        let (text_tokens, logprobs): (Vec<u32>, Vec<f32>) = segment
+            .tokens
+            .iter()
+            .zip(&segment.logprobs)
+            .filter(|(&t, _)| t < timestamp_begin)
+            .unzip();
+        let text = self.tokenizer.decode(&text_tokens, true).unwrap_or_default();
+        if text.trim().is_empty() {
+            return;
+        }
+        let avg_logprob = logprobs.iter().map(|&l| l as f64).sum::<f64>() / logprobs.len().max(1) as f64;
+        let tokens = text_tokens
+            .iter()
+            .zip(&logprobs)
+            .map(|(&id, &logprob)| TokenProbability {
+                id,
+                text: self.tokenizer.decode(&[id], false).unwrap_or_default(),
+                probability: logprob.exp(),
+            })
+            .collect();
+        segments.push(Segment {
+            start: segment.start,
+            end: segment.end,
+            compression_ratio: compression_ratio(text.trim()),
+            text,
+            words,
+            avg_logprob,
+            no_speech_prob,
+            tokens,
+        });
+    }
+}
+
+/// Where `new` keeps GGUF conversions of hub models, next to the Hugging Face cache.
+pub fn quantized_model_dir(model_id: &str) -> PathBuf {
+    ModelStore::from_env().local_dir().join(format!("whisper-{}", model_id))
+}
+
+/// Weights file of a local model folder, preferring full precision.
+pub(crate) fn find_weights(dir: &Path) -> Result<Option<PathBuf>> {
+    for name in ["model.safetensors", "model.gguf"] {
+        if dir.join(name).is_file() {
+            return Ok(Some(dir.join(name)));
+        }
+    }
+    let mut ggufs = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "gguf") {
+            ggufs.push(path);
+        }
+    }
+    match ggufs.len() {
+        0 => Ok(None),
+        1 => Ok(ggufs.pop()),
+        _ => anyhow::bail!("model folder {} has several .gguf files; keep only one", dir.display()),
+    }
+}
+
+/// Read `alignment_heads` (a list of `[layer, head]`) from a generation_config.json.
+fn parse_alignment_heads(json: &str) -> Option<Vec<(usize, usize)>> {
+    let value: serde_json::Value = serde_json::from_str(json).ok()?;
+    let heads: Vec<(usize, usize)> = value
+        .get("alignment_heads")?
+        .as_array()?
+        .iter()
+        .filter_map(|pair| Some((pair.get(0)?.as_u64()? as usize, pair.get(1)?.as_u64()? as usize)))
+        .collect();
+    (!heads.is_empty()).then_some(heads)
+}
+
+/// `<|startoftranscript|><|lang|><|task|>` for multilingual models, just
+/// `<|startoftranscript|>` for English-only ones.
+fn build_sot_sequence(tokenizer: &Tokenizer, multilingual: bool, language: &str, task: Task) -> Result<Vec<u32>> {
+    let sot_token = tokenizer.token_to_id("<|startoftranscript|>").unwrap_or(50258);
+    if !multilingual {
+        return Ok(vec![sot_token]);
+    }
+    let language_token = tokenizer
+        .token_to_id(&format!("<|{}|>", language))
+        .ok_or_else(|| anyhow::anyhow!("unsupported language: {}", language))?;
+    let task_token = tokenizer
+        .token_to_id(task.token())
+        .ok_or_else(|| anyhow::anyhow!("missing task token {}", task.token()))?;
+    // We do NOT add <|notimestamps|> because we WANT timestamps.
+    Ok(vec![sot_token, language_token, task_token])
+}
+
+/// The languages the tokenizer has a `<|code|>` token for, with their ids.
+fn language_candidates(tokenizer: &Tokenizer) -> Vec<(&'static str, u32)> {
+    LANGUAGES
+        .iter()
+        .filter_map(|(code, _)| tokenizer.token_to_id(&format!("<|{}|>", code)).map(|id| (*code, id)))
+        .collect()
+}
+
+/// The candidate language with the highest logit and its softmax probability,
+/// taken over the language tokens only.
+fn pick_language(logits: &[f32], candidates: &[(&str, u32)]) -> (String, f32) {
+    let language_logits: Vec<f32> = candidates.iter().map(|(_, id)| logits[*id as usize]).collect();
+    let max = language_logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = language_logits.iter().map(|l| (l - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    let (best, p) = exp
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|(i, e)| (i, e / sum))
+        .unwrap();
+    (candidates[best].0.to_string(), p)
+}
+
+/// Copy the files of a model from `PUBLISHED_REPO` into the model folder `dir`,
+/// the weights last so a complete `model.gguf` marks a complete folder.
+fn fetch_published(api: &Api, published: &PublishedGguf, dir: &Path) -> Result<()> {
+    let repo = api.repo(Repo::new(PUBLISHED_REPO.to_string(), RepoType::Model));
+    std::fs::copy(repo.get(&published.config)?, dir.join("config.json"))?;
+    std::fs::copy(repo.get(&published.tokenizer)?, dir.join("tokenizer.json"))?;
+    let part = dir.join("model.gguf.part");
+    std::fs::copy(repo.get(&published.weights)?, &part)?;
+    std::fs::rename(part, dir.join("model.gguf"))?;
+    Ok(())
+}
+
+/// Cut the tokens decoded from one window into segments like reference Whisper,
+/// and return them with the number of mel frames to advance. The window starts
+/// `time_offset` seconds in and holds `segment_size` mel frames; each timestamp
+/// step covers `input_stride` frames. Without a single timestamp at the end, the
+/// tokens after the last pair of timestamps are dropped and decoding resumes
+/// from that pair.
+fn split_window(
+    tokens: Vec<u32>,
+    logprobs: Vec<f32>,
+    timestamp_begin: u32,
+    time_offset: f64,
+    segment_size: usize,
+    input_stride: usize,
+) -> (Vec<WindowSegment>, usize) {
+    let time_precision = (input_stride * HOP_LENGTH) as f64 / SAMPLE_RATE as f64;
+    let segment_duration = segment_size as f64 * HOP_LENGTH as f64 / SAMPLE_RATE as f64;
+    let is_timestamp = |t: u32| t >= timestamp_begin;
+    let single_timestamp_ending = tokens.len() >= 2
+        && !is_timestamp(tokens[tokens.len() - 2])
+        && is_timestamp(tokens[tokens.len() - 1]);
+
+    // Positions where two timestamp tokens follow each other close a segment.
+    let mut slices: Vec<usize> = (1..tokens.len())
+        .filter(|&i| is_timestamp(tokens[i - 1]) && is_timestamp(tokens[i]))
+        .collect();
+
+    let mut window_segments: Vec<WindowSegment> = Vec::new();
+    let mut advance = segment_size;
+    if !slices.is_empty() {
+        if single_timestamp_ending {
+            slices.push(tokens.len());
+        }
+        let mut last_slice = 0;
+        for &current_slice in &slices {
+            let sliced = &tokens[last_slice..current_slice];
+            let start_pos = sliced[0].saturating_sub(timestamp_begin);
+            let end_pos = sliced[sliced.len() - 1].saturating_sub(timestamp_begin);
+            window_segments.push(WindowSegment {
+                start: time_offset + start_pos as f64 * time_precision,
+                end: time_offset + end_pos as f64 * time_precision,
+                tokens: sliced.to_vec(),
+                logprobs: logprobs[last_slice..current_slice].to_vec(),
+            });
+            last_slice = current_slice;
+        }
+        if !single_timestamp_ending {
+            // Resume decoding from the last complete timestamp.
+            let last_timestamp_pos = tokens[last_slice - 1].saturating_sub(timestamp_begin) as usize;
+            if last_timestamp_pos > 0 {
+                advance = (last_timestamp_pos * input_stride).min(segment_size);
             }
         }
-        
-        Ok(segments)
+    } else {
+        let mut duration = segment_duration;
+        if let Some(&last) = tokens.iter().rev().find(|&&t| is_timestamp(t)) {
+            if last != timestamp_begin {
+                duration = (last - timestamp_begin) as f64 * time_precision;
+            }
+        }
+        window_segments.push(WindowSegment {
+            start: time_offset,
+            end: time_offset + duration,
+            tokens,
+            logprobs,
+        });
+    }
+    (window_segments, advance)
+}
+
+/// Decode audio track `track` (counted among the file's audio tracks; the
+/// default one when `None`) of an audio or video file to 16 kHz mono PCM with
+/// symphonia, falling back to ffmpeg for containers and codecs symphonia cannot read.
+///
+/// A chosen track is numbered like `list_audio_tracks` numbers it, so it is
+/// decoded by ffmpeg when ffprobe can read the file: symphonia skips tracks
+/// with codecs it does not know and would count the rest differently.
+pub(crate) fn load_audio(path: impl AsRef<Path>, track: Option<usize>) -> Result<(Vec<f32>, AudioDecoder)> {
+    let path = path.as_ref();
+    if !path.is_file() {
+        anyhow::bail!("file not found: {}", path.display());
+    }
+    if track.is_some() && ffmpeg::is_available() && ffmpeg::probe_audio_tracks(path).is_ok() {
+        let pcm = ffmpeg::decode(path, track)?;
+        log::info!("decoded {} with ffmpeg", path.display());
+        return Ok((pcm, AudioDecoder::Ffmpeg));
+    }
+    let symphonia_error = match load_audio_symphonia(path, track) {
+        Ok(pcm) => {
+            log::info!("decoded {} with symphonia", path.display());
+            return Ok((pcm, AudioDecoder::Symphonia));
+        }
+        Err(e) => e,
+    };
+    log::warn!("symphonia cannot decode {} ({}), trying ffmpeg", path.display(), symphonia_error);
+    match ffmpeg::decode(path, track) {
+        Ok(pcm) => {
+            log::info!("decoded {} with ffmpeg", path.display());
+            Ok((pcm, AudioDecoder::Ffmpeg))
+        }
+        Err(e) => anyhow::bail!("cannot decode {}: symphonia: {}; {}", path.display(), symphonia_error, e),
     }
 }
 
-fn load_audio(path: impl AsRef<Path>) -> Result<Vec<f32>> {
-    let src = std::fs::File::open(path)?;
-    let mss = MediaSourceStream::new(Box::new(src), Default::default());
-    let hint = Hint::new();
-    // hint.with_extension("mp3"); 
+/// `load_audio` of the track selected in `options`, then its pre-processing.
+pub(crate) fn load_pcm(path: impl AsRef<Path>, options: &DecodingOptions) -> Result<(Vec<f32>, AudioDecoder)> {
+    let (pcm, decoder) = load_audio(path, options.audio_track)?;
+    if options.preprocess.is_enabled() {
+        return Ok((preprocess(&pcm, &options.preprocess), decoder));
+    }
+    Ok((pcm, decoder))
+}
 
-    let meta_opts: MetadataOptions = Default::default();
-    let fmt_opts: FormatOptions = Default::default();
-    let probed = symphonia::default::get_probe().format(&hint, mss, &fmt_opts, &meta_opts)?;
-    let mut format = probed.format;
-    let track = format.default_track().ok_or_else(|| anyhow::anyhow!("no track found"))?;
+fn load_audio_symphonia(path: &Path, track: Option<usize>) -> Result<Vec<f32>> {
+    let mut format = tracks::open(path)?;
+    let track = match track {
+        None => format
+            .default_track()
+            .filter(|track| track.codec_params.codec != CODEC_TYPE_NULL)
+            .or_else(|| tracks::audio_tracks(format.as_ref()).next())
+            .ok_or_else(|| anyhow::anyhow!("no track found"))?,
+        Some(index) => {
+            let count = tracks::audio_tracks(format.as_ref()).count();
+            tracks::audio_tracks(format.as_ref())
+                .nth(index)
+                .ok_or_else(|| anyhow::anyhow!("no audio track {} (the file has {})", index + 1, count))?
+        }
+    };
     let mut decoder = symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
     let track_id = track.id;
     let sample_rate = track.codec_params.sample_rate.ok_or_else(|| anyhow::anyhow!("no sample rate"))?;
@@ -152,26 +1115,243 @@ fn load_audio(path: impl AsRef<Path>) -> Result<Vec<f32>> {
     while let Ok(packet) = format.next_packet() {
         if packet.track_id() != track_id { continue; }
         let decoded = decoder.decode(&packet)?;
+        let channels = decoded.spec().channels.count();
         let mut sample_buf = SampleBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
         sample_buf.copy_interleaved_ref(decoded);
-        
-        pcm_data.extend_from_slice(sample_buf.samples());
-    }
-    
-    // Resample if needed (very naive check and decimation if 48k -> 16k)
-    // If 44.1k, this simple logic fails. 
-    // Assuming 16k input or implementing naive downsample.
-    // For this fast fix:
-    if sample_rate == 48000 {
-         let mut new_pcm = Vec::new();
-         for (i, sample) in pcm_data.iter().enumerate() {
-             if i % 3 == 0 { new_pcm.push(*sample); }
-         }
-         Ok(new_pcm)
-    } else if sample_rate == 16000 {
-        Ok(pcm_data)
-    } else {
-        // Fallback: warn and return as is (will sound slow/fast)
-        Ok(pcm_data)
+
+        pcm_data.extend(downmix_to_mono(sample_buf.samples(), channels));
+    }
+    if pcm_data.is_empty() {
+        anyhow::bail!("no audio decoded");
+    }
+
+    Ok(resample(&pcm_data, sample_rate, SAMPLE_RATE as u32))
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use candle_nn::VarMap;
+    use candle_transformers::models::whisper::model::Whisper;
+    use std::collections::HashMap;
+    use tokenizers::models::wordlevel::WordLevel;
+
+    /// Words `w0`..`w19`, the special tokens, then the 1501 timestamps.
+    pub(crate) fn tiny_tokenizer() -> Tokenizer {
+        let specials = ["<|endoftext|>", "<|startoftranscript|>", "<|startofprev|>", "<|nocaptions|>", "<|notimestamps|>"];
+        let vocab: HashMap<String, u32> = (0..20)
+            .map(|i| format!("w{}", i))
+            .chain(specials.iter().map(|s| s.to_string()))
+            .chain((0..=1500).map(|i| format!("<|{:.2}|>", i as f64 * 0.02)))
+            .enumerate()
+            .map(|(id, token)| (token, id as u32))
+            .collect();
+        Tokenizer::new(WordLevel::builder().vocab(vocab.into_iter().collect()).unk_token("w0".into()).build().unwrap())
+    }
+
+    /// A folder with an English-only model of random weights, small enough to
+    /// transcribe a minute of audio in a test.
+    pub(crate) fn tiny_model_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("whisper-tiny-model-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let tokenizer = tiny_tokenizer();
+        let config = Config {
+            num_mel_bins: 80,
+            max_source_positions: 1500,
+            d_model: 16,
+            encoder_attention_heads: 2,
+            encoder_layers: 1,
+            vocab_size: tokenizer.get_vocab_size(true),
+            max_target_positions: 48,
+            decoder_attention_heads: 2,
+            decoder_layers: 1,
+            suppress_tokens: vec![],
+        };
+        let config_json = serde_json::json!({
+            "num_mel_bins": config.num_mel_bins,
+            "max_source_positions": config.max_source_positions,
+            "d_model": config.d_model,
+            "encoder_attention_heads": config.encoder_attention_heads,
+            "encoder_layers": config.encoder_layers,
+            "vocab_size": config.vocab_size,
+            "max_target_positions": config.max_target_positions,
+            "decoder_attention_heads": config.decoder_attention_heads,
+            "decoder_layers": config.decoder_layers,
+            "suppress_tokens": [],
+        });
+        std::fs::write(dir.join("config.json"), config_json.to_string()).unwrap();
+        tokenizer.save(dir.join("tokenizer.json"), false).unwrap();
+
+        let device = Device::Cpu;
+        let varmap = VarMap::new();
+        Whisper::load(&candle_nn::VarBuilder::from_varmap(&varmap, DType::F32, &device), config).unwrap();
+        // Seeded, so every run decodes the same tokens.
+        let mut state = 0x2545_f491u32;
+        let mut vars: Vec<_> = varmap.data().lock().unwrap().clone().into_iter().collect();
+        vars.sort_by(|a, b| a.0.cmp(&b.0));
+        for (_, var) in vars {
+            let values: Vec<f32> = (0..var.elem_count())
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state as f32 / u32::MAX as f32 - 0.5
+                })
+                .collect();
+            var.set(&Tensor::from_vec(values, var.shape(), &device).unwrap()).unwrap();
+        }
+        varmap.save(dir.join("model.safetensors")).unwrap();
+        dir
+    }
+
+    /// Words, then `<|startoftranscript|>`, three languages and the two tasks.
+    fn multilingual_tokenizer() -> Tokenizer {
+        let specials = ["<|startoftranscript|>", "<|en|>", "<|zh|>", "<|de|>", "<|transcribe|>", "<|translate|>"];
+        let vocab: HashMap<String, u32> = (0..10)
+            .map(|i| format!("w{}", i))
+            .chain(specials.iter().map(|s| s.to_string()))
+            .enumerate()
+            .map(|(id, token)| (token, id as u32))
+            .collect();
+        Tokenizer::new(WordLevel::builder().vocab(vocab.into_iter().collect()).unk_token("w0".into()).build().unwrap())
+    }
+
+    #[test]
+    fn test_language_detection_considers_language_tokens_only() {
+        let tokenizer = multilingual_tokenizer();
+        let id = |token: &str| tokenizer.token_to_id(token).unwrap();
+        let candidates = language_candidates(&tokenizer);
+        assert_eq!(candidates, [("en", id("<|en|>")), ("zh", id("<|zh|>")), ("de", id("<|de|>"))]);
+
+        let mut logits = vec![0.0; tokenizer.get_vocab_size(true)];
+        // A text token and a task token beat every language but are not languages.
+        logits[id("w3") as usize] = 20.0;
+        logits[id("<|translate|>") as usize] = 10.0;
+        logits[id("<|en|>") as usize] = 1.0;
+        logits[id("<|zh|>") as usize] = 3.0;
+        logits[id("<|de|>") as usize] = 1.0;
+        let (language, probability) = pick_language(&logits, &candidates);
+        assert_eq!(language, "zh");
+        let e = std::f32::consts::E;
+        let expected = e.powi(3) / (e.powi(3) + 2.0 * e);
+        assert!((probability - expected).abs() < 1e-6, "{} vs {}", probability, expected);
+    }
+
+    #[test]
+    fn test_sot_sequence_for_language_and_task() {
+        let tokenizer = multilingual_tokenizer();
+        let id = |token: &str| tokenizer.token_to_id(token).unwrap();
+        let sot = id("<|startoftranscript|>");
+        assert_eq!(
+            build_sot_sequence(&tokenizer, true, "de", Task::Translate).unwrap(),
+            [sot, id("<|de|>"), id("<|translate|>")]
+        );
+        assert_eq!(
+            build_sot_sequence(&tokenizer, true, "zh", Task::Transcribe).unwrap(),
+            [sot, id("<|zh|>"), id("<|transcribe|>")]
+        );
+        let error = build_sot_sequence(&tokenizer, true, "fr", Task::Transcribe).unwrap_err();
+        assert_eq!(error.to_string(), "unsupported language: fr");
+        // English-only models take no language or task token.
+        assert_eq!(build_sot_sequence(&tokenizer, false, "de", Task::Translate).unwrap(), [sot]);
+    }
+
+    /// Start, end, tokens and logprobs of a `WindowSegment`.
+    type Cut = (f64, f64, Vec<u32>, Vec<f32>);
+
+    /// `split_window` of a full window starting at 30 s, with timestamps from
+    /// token 100 and 20 ms per step; logprobs are `-token / 1000`.
+    fn split(tokens: &[u32]) -> (Vec<Cut>, usize) {
+        let logprobs = tokens.iter().map(|&t| -(t as f32) / 1000.0).collect();
+        let (segments, advance) = split_window(tokens.to_vec(), logprobs, 100, 30.0, N_FRAMES, 2);
+        let segments = segments
+            .into_iter()
+            .map(|s| ((s.start * 100.0).round() / 100.0, (s.end * 100.0).round() / 100.0, s.tokens, s.logprobs))
+            .collect();
+        (segments, advance)
+    }
+
+    #[test]
+    fn test_split_window_resumes_after_the_last_pair() {
+        // <|0.00|> a <|1.00|><|1.00|> b <|2.00|><|2.20|> c: "c" never finished.
+        let (segments, advance) = split(&[100, 5, 150, 150, 6, 200, 210, 7]);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0], (30.0, 31.0, vec![100, 5, 150], vec![-0.1, -0.005, -0.15]));
+        assert_eq!((segments[1].0, segments[1].1, &segments[1].2[..]), (31.0, 32.0, &[150, 6, 200][..]));
+        // 2.00 s is 100 steps of two mel frames.
+        assert_eq!(advance, 200);
+    }
+
+    #[test]
+    fn test_split_window_with_single_timestamp_ending() {
+        let (segments, advance) = split(&[100, 5, 150, 150, 6, 200]);
+        let spans: Vec<(f64, f64)> = segments.iter().map(|s| (s.0, s.1)).collect();
+        assert_eq!(spans, [(30.0, 31.0), (31.0, 32.0)]);
+        assert_eq!(segments[1].2, [150, 6, 200]);
+        assert_eq!(advance, N_FRAMES);
+    }
+
+    #[test]
+    fn test_split_window_without_timestamps() {
+        let (segments, advance) = split(&[5, 6, 7]);
+        assert_eq!(segments, [(30.0, 60.0, vec![5, 6, 7], vec![-0.005, -0.006, -0.007])]);
+        assert_eq!(advance, N_FRAMES);
+
+        // A single timestamp sets the end.
+        let (segments, _) = split(&[5, 6, 350]);
+        assert_eq!((segments[0].0, segments[0].1), (30.0, 35.0));
+    }
+
+    #[test]
+    fn test_split_window_at_timestamp_zero_moves_on() {
+        // The only pair is at 0.00, which would seek nowhere.
+        let (segments, advance) = split(&[100, 100, 5, 6]);
+        assert_eq!(segments, [(30.0, 30.0, vec![100], vec![-0.1])]);
+        assert_eq!(advance, N_FRAMES);
+    }
+
+    /// A gliding tone, so every window sounds different.
+    fn chirp(secs: usize) -> Vec<f32> {
+        (0..secs * SAMPLE_RATE)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                0.3 * (2.0 * std::f32::consts::PI * (200.0 + 10.0 * t) * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_encoder_batching_keeps_the_transcript() {
+        let dir = tiny_model_dir("batch");
+        let pcm = chirp(45);
+        // One pass per window, as a random model would fall back through every temperature.
+        let options = DecodingOptions {
+            temperatures: vec![0.0],
+            condition_on_previous_text: false,
+            guard: None,
+            ..Default::default()
+        };
+        let transcribe = |encoder_batch_size| {
+            let config = EngineConfig { encoder_batch_size, ..Default::default() };
+            let mut engine = WhisperEngine::from_dir_with_config(&dir, &config).unwrap();
+            let mut seeks = Vec::new();
+            let transcription = {
+                let mut job = Job::default().on_progress(|progress| seeks.push(progress.processed_secs));
+                engine.transcribe_pcm_with(&pcm, &options, &mut job).unwrap()
+            };
+            let tokens: Vec<_> = transcription
+                .detailed
+                .iter()
+                .map(|s| (s.start, s.end, s.tokens.iter().map(|t| t.id).collect::<Vec<_>>()))
+                .collect();
+            (tokens, seeks)
+        };
+        let (single, seeks) = transcribe(1);
+        let (batched, _) = transcribe(3);
+        std::fs::remove_dir_all(&dir).unwrap();
+        // The second window starts at the last timestamp, not where the batch encoded it.
+        assert!(seeks[0] < 30.0, "{:?}", seeks);
+        assert!(!single.is_empty());
+        assert_eq!(single, batched);
     }
 }
@@ -0,0 +1,351 @@
+//! The batch pipeline (file queue, SRT writing, error paths) against `MockTranscriber`.
+
+use anyhow::Result;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use whisper_app::batch::{run_batch, run_batch_parallel, BatchEvent, BatchOptions, FileStatus, TrackSelection};
+use whisper_app::decoding::DecodingOptions;
+use whisper_app::job::{CancellationToken, Job};
+use whisper_app::transcriber::{MockTranscriber, Transcriber};
+use whisper_app::whisper_engine::Transcription;
+
+/// A fresh, empty output directory for one test.
+fn output_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("whisper-batch-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn files(names: &[&str]) -> Vec<String> {
+    names.iter().map(|n| n.to_string()).collect()
+}
+
+fn list_dir(dir: &PathBuf) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+        .collect();
+    names.sort();
+    names
+}
+
+#[test]
+fn test_queue_writes_one_srt_per_file_in_order() {
+    let dir = output_dir("queue");
+    let mut mock = MockTranscriber::new()
+        .with_segments("talk.mp4", &[(0.0, 2.5, " Hello there."), (2.5, 61.0, " General Kenobi!")])
+        .with_windows(3);
+    let queue = files(&["talk.mp4", "/videos/intro.wav"]);
+
+    let mut started = Vec::new();
+    let mut progress = Vec::new();
+    let summary = run_batch(
+        &mut mock,
+        &queue,
+        &DecodingOptions::default(),
+        &BatchOptions::new(&dir),
+        &CancellationToken::default(),
+        |event| match event {
+            BatchEvent::Started { index, .. } => started.push(index),
+            BatchEvent::Progress { index, progress: p } => progress.push((index, p.window, p.percent().round())),
+            _ => {}
+        },
+    );
+
+    assert_eq!(mock.calls, queue);
+    assert_eq!(started, [0, 1]);
+    assert_eq!(progress[..3], [(0, 1, 33.0), (0, 2, 67.0), (0, 3, 100.0)]);
+    assert_eq!(summary.completed, queue);
+    assert!(summary.failed.is_empty() && !summary.cancelled);
+    assert_eq!(list_dir(&dir), ["intro.srt", "talk.srt"]);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("talk.srt")).unwrap(),
+        "1\n00:00:00,000 --> 00:00:02,500\nHello there.\n\n2\n00:00:02,500 --> 00:01:01,000\nGeneral Kenobi!\n\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(dir.join("intro.srt")).unwrap(),
+        "1\n00:00:00,000 --> 00:00:01,000\n/videos/intro.wav\n\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_extra_outputs() {
+    let dir = output_dir("extra");
+    let mut mock = MockTranscriber::new();
+    let options = DecodingOptions { word_timestamps: true, ..Default::default() };
+    let batch = BatchOptions { export_confidence: true, ..BatchOptions::new(&dir) };
+    let summary = run_batch(&mut mock, &files(&["a.wav"]), &options, &batch, &CancellationToken::default(), |_| {});
+
+    assert_eq!(summary.completed.len(), 1);
+    assert_eq!(list_dir(&dir), ["a.ass", "a.confidence.json", "a.srt", "a.words.json"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_failed_file_is_reported_and_queue_continues() {
+    let dir = output_dir("failed");
+    let mut mock = MockTranscriber::new().with_error("broken.mp3", "unsupported codec");
+    let mut errors = Vec::new();
+    let summary = run_batch(
+        &mut mock,
+        &files(&["broken.mp3", "fine.wav"]),
+        &DecodingOptions::default(),
+        &BatchOptions::new(&dir),
+        &CancellationToken::default(),
+        |event| {
+            if let BatchEvent::Failed { index, error } = event {
+                errors.push((index, error.to_string()));
+            }
+        },
+    );
+
+    assert_eq!(errors, [(0, "unsupported codec".to_string())]);
+    assert_eq!(summary.failed, ["broken.mp3"]);
+    assert_eq!(summary.completed, ["fine.wav"]);
+    assert_eq!(list_dir(&dir), ["fine.srt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_unwritable_output_dir() {
+    let dir = output_dir("unwritable").join("missing");
+    let mut mock = MockTranscriber::new();
+    let mut write_failures = 0;
+    let summary = run_batch(
+        &mut mock,
+        &files(&["a.wav"]),
+        &DecodingOptions::default(),
+        &BatchOptions::new(&dir),
+        &CancellationToken::default(),
+        |event| {
+            if let BatchEvent::WriteFailed { path, .. } = event {
+                assert!(path.ends_with("a.srt"));
+                write_failures += 1;
+            }
+        },
+    );
+
+    assert_eq!(write_failures, 1);
+    assert_eq!(summary.failed, ["a.wav"]);
+    assert!(summary.completed.is_empty());
+    std::fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+}
+
+#[test]
+fn test_failed_output_removes_the_others() {
+    let dir = output_dir("rollback");
+    // The second output, after `a.srt`, cannot replace a directory.
+    std::fs::create_dir(dir.join("a.confidence.json")).unwrap();
+    let mut mock = MockTranscriber::new();
+    let batch = BatchOptions { export_confidence: true, ..BatchOptions::new(&dir) };
+    let mut events = Vec::new();
+    let summary = run_batch(
+        &mut mock,
+        &files(&["a.wav", "b.wav"]),
+        &DecodingOptions::default(),
+        &batch,
+        &CancellationToken::default(),
+        |event| match event {
+            BatchEvent::Written { index, path } | BatchEvent::WriteFailed { index, path, .. } => {
+                events.push((index, path.file_name().unwrap().to_string_lossy().to_string()));
+            }
+            _ => {}
+        },
+    );
+
+    assert_eq!(summary.failed, ["a.wav"]);
+    assert_eq!(summary.completed, ["b.wav"]);
+    assert_eq!(events[0], (0, "a.confidence.json".to_string()));
+    assert!(events[1..].iter().all(|(index, _)| *index == 1));
+    assert_eq!(list_dir(&dir), ["a.confidence.json", "b.ass", "b.confidence.json", "b.srt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cancel_mid_file_leaves_no_partial_output() {
+    let dir = output_dir("cancel");
+    let mut mock = MockTranscriber::new().with_windows(4);
+    let cancel = CancellationToken::default();
+    let summary = run_batch(
+        &mut mock,
+        &files(&["first.wav", "second.wav", "third.wav"]),
+        &DecodingOptions::default(),
+        &BatchOptions::new(&dir),
+        &cancel,
+        |event| {
+            if let BatchEvent::Progress { index: 1, progress } = event {
+                if progress.window == 2 {
+                    cancel.cancel();
+                }
+            }
+        },
+    );
+
+    assert!(summary.cancelled);
+    assert_eq!(summary.completed, ["first.wav"]);
+    assert_eq!(mock.calls, ["first.wav", "second.wav"]);
+    assert_eq!(list_dir(&dir), ["first.srt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_each_track_gets_its_own_srt() {
+    let dir = output_dir("tracks");
+    let mut mock = MockTranscriber::new().with_tracks("interview.mkv", &["eng", "deu", "fra"]);
+    let batch = BatchOptions { tracks: TrackSelection::Only(vec![0, 2]), ..BatchOptions::new(&dir) };
+    let mut transcribed = Vec::new();
+    let summary = run_batch(
+        &mut mock,
+        &files(&["interview.mkv"]),
+        &DecodingOptions::default(),
+        &batch,
+        &CancellationToken::default(),
+        |event| {
+            if let BatchEvent::Transcribed { track, .. } = event {
+                transcribed.push(track.unwrap().to_string());
+            }
+        },
+    );
+
+    assert_eq!(summary.completed, ["interview.mkv"]);
+    assert_eq!(transcribed, ["#1 pcm_s16le, 1 ch, 16000 Hz, eng", "#3 pcm_s16le, 1 ch, 16000 Hz, fra"]);
+    assert_eq!(list_dir(&dir), ["interview.track1.srt", "interview.track3.srt"]);
+    assert!(std::fs::read_to_string(dir.join("interview.track3.srt")).unwrap().contains("interview.mkv #3"));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_merged_tracks_are_labelled() {
+    let dir = output_dir("merged");
+    let mut mock = MockTranscriber::new().with_tracks("panel.mov", &["eng", "deu"]);
+    let batch = BatchOptions { tracks: TrackSelection::All, merge_tracks: true, ..BatchOptions::new(&dir) };
+    let summary = run_batch(&mut mock, &files(&["panel.mov"]), &DecodingOptions::default(), &batch, &CancellationToken::default(), |_| {});
+
+    assert_eq!(summary.completed, ["panel.mov"]);
+    assert_eq!(mock.calls, ["panel.mov", "panel.mov"]);
+    assert_eq!(list_dir(&dir), ["panel.srt"]);
+    assert_eq!(
+        std::fs::read_to_string(dir.join("panel.srt")).unwrap(),
+        "1\n00:00:00,000 --> 00:00:01,000\n[eng] panel.mov #1\n\n2\n00:00:00,000 --> 00:00:01,000\n[deu] panel.mov #2\n\n"
+    );
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_missing_track_fails_the_file() {
+    let dir = output_dir("missing-track");
+    let mut mock = MockTranscriber::new();
+    let batch = BatchOptions { tracks: TrackSelection::Only(vec![1]), ..BatchOptions::new(&dir) };
+    let mut errors = Vec::new();
+    let summary = run_batch(&mut mock, &files(&["mono.wav"]), &DecodingOptions::default(), &batch, &CancellationToken::default(), |event| {
+        if let BatchEvent::Failed { error, .. } = event {
+            errors.push(error.to_string());
+        }
+    });
+
+    assert_eq!(errors, ["no audio track 2 (the file has 1)"]);
+    assert_eq!(summary.failed, ["mono.wav"]);
+    assert!(mock.calls.is_empty());
+    assert!(list_dir(&dir).is_empty());
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// A `MockTranscriber` that takes a while per file and records how many of
+/// its shared copies run at once.
+#[derive(Clone)]
+struct SlowTranscriber {
+    mock: MockTranscriber,
+    running: Arc<AtomicUsize>,
+    peak: Arc<AtomicUsize>,
+}
+
+impl Transcriber for SlowTranscriber {
+    fn transcribe_with(&mut self, audio_path: &str, options: &DecodingOptions, job: &mut Job) -> Result<Transcription> {
+        let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+        self.peak.fetch_max(running, Ordering::SeqCst);
+        std::thread::sleep(Duration::from_millis(50));
+        self.running.fetch_sub(1, Ordering::SeqCst);
+        self.mock.transcribe_with(audio_path, options, job)
+    }
+
+    fn share(&self) -> Option<Box<dyn Transcriber>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+#[test]
+fn test_pool_runs_files_concurrently() {
+    let dir = output_dir("pool");
+    let slow = SlowTranscriber {
+        mock: MockTranscriber::new().with_error("c.wav", "unsupported codec"),
+        running: Arc::default(),
+        peak: Arc::default(),
+    };
+    let mut shared: Vec<Box<dyn Transcriber>> = (0..3).map(|_| slow.share().unwrap()).collect();
+    let mut workers: Vec<&mut dyn Transcriber> = shared.iter_mut().map(|t| t.as_mut() as &mut dyn Transcriber).collect();
+    let queue = files(&["a.wav", "b.wav", "c.wav", "d.wav", "e.wav", "f.wav"]);
+    let mut statuses = vec![FileStatus::Queued; queue.len()];
+    let summary = run_batch_parallel(
+        &mut workers,
+        &queue,
+        &DecodingOptions::default(),
+        &BatchOptions::new(&dir),
+        &CancellationToken::default(),
+        |event| {
+            if let Some(status) = event.status() {
+                statuses[event.index()] = status;
+            }
+        },
+    );
+
+    assert!(slow.peak.load(Ordering::SeqCst) >= 2, "files ran one at a time");
+    assert_eq!(summary.completed, ["a.wav", "b.wav", "d.wav", "e.wav", "f.wav"]);
+    assert_eq!(summary.failed, ["c.wav"]);
+    assert!(!summary.cancelled);
+    assert_eq!(statuses[2], FileStatus::Failed("unsupported codec".to_string()));
+    assert!(statuses.iter().enumerate().all(|(i, s)| i == 2 || *s == FileStatus::Done));
+    assert_eq!(list_dir(&dir), ["a.srt", "b.srt", "d.srt", "e.srt", "f.srt"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_cancelled_pool_requeues_running_files() {
+    let dir = output_dir("pool-cancel");
+    let mock = MockTranscriber::new().with_windows(4);
+    let mut shared: Vec<Box<dyn Transcriber>> = (0..2).map(|_| mock.share().unwrap()).collect();
+    let mut workers: Vec<&mut dyn Transcriber> = shared.iter_mut().map(|t| t.as_mut() as &mut dyn Transcriber).collect();
+    let queue = files(&["first.wav", "second.wav", "third.wav", "fourth.wav"]);
+    let cancel = CancellationToken::default();
+    let mut statuses = vec![FileStatus::Queued; queue.len()];
+    let summary = run_batch_parallel(
+        &mut workers,
+        &queue,
+        &DecodingOptions::default(),
+        &BatchOptions::new(&dir),
+        &cancel,
+        |event| {
+            if let BatchEvent::Progress { index: 2, progress } = &event {
+                if progress.window == 2 {
+                    cancel.cancel();
+                }
+            }
+            if let Some(status) = event.status() {
+                statuses[event.index()] = status;
+            }
+        },
+    );
+
+    assert!(summary.cancelled);
+    assert!(summary.failed.is_empty());
+    assert!(!summary.completed.contains(&"third.wav".to_string()));
+    assert_eq!(statuses[2], FileStatus::Queued);
+    assert_eq!(statuses[3], FileStatus::Queued);
+    assert!(!statuses.iter().any(|s| matches!(s, FileStatus::Running(_))));
+    let written: Vec<String> = summary.completed.iter().map(|f| f.replace(".wav", ".srt")).collect();
+    assert_eq!(list_dir(&dir), written);
+    std::fs::remove_dir_all(&dir).unwrap();
+}